@@ -22,13 +22,19 @@ fn main() {
 
     let url = &args[1];
     let url = url::Url::parse(url).expect("error parsing url");
-    let opts = ConnectionOptions {
-        username: url.username.map(|s| s.to_string()),
-        password: url.password.map(|s| s.to_string()),
-        sasl_mechanism: url.username.map_or(Some(SaslMechanism::Anonymous), |_| {
-            Some(SaslMechanism::Plain)
-        }),
-    };
+    let mut opts = ConnectionOptions::new().sasl_mechanism(
+        url.username
+            .map_or(SaslMechanism::Anonymous, |_| SaslMechanism::Plain),
+    );
+    if let Some(username) = url.username {
+        opts = opts.username(username);
+    }
+    if let Some(password) = url.password {
+        opts = opts.password(password);
+    }
+    if url.scheme == url::UrlScheme::AMQPS {
+        opts = opts.tls(TlsOptions::new());
+    }
 
     let container = Container::new()
         .expect("unable to create container")
@@ -34,7 +34,7 @@ fn main() {
 
     connection.open(Open::new("example-send-minimal")).unwrap();
     connection
-        .begin(0, Begin::new(0, std::u32::MAX, std::u32::MAX))
+        .begin(0, Begin::new(0, u32::MAX, u32::MAX))
         .unwrap();
     connection
         .attach(
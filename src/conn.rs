@@ -5,7 +5,13 @@
 
 //! The conn module contains basic primitives for establishing and accepting AMQP connections and performing the initial handshake. Once handshake is complete, the connection can be used to send and receive frames.
 
+use ::mio::event::Source;
+use ::mio::net::TcpListener;
+use ::mio::{Interest, Registry, Token};
 use log::{debug, trace};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
 use std::time::Duration;
 use std::time::Instant;
 use std::vec::Vec;
@@ -13,6 +19,7 @@ use std::vec::Vec;
 use crate::error::*;
 use crate::framing::*;
 use crate::sasl::*;
+use crate::transport::mio::{MioNetwork, TlsAcceptor};
 use crate::transport::*;
 
 #[derive(Debug, Clone)]
@@ -20,6 +27,15 @@ pub struct ConnectionOptions {
     pub username: Option<String>,
     pub password: Option<String>,
     pub sasl_mechanism: Option<SaslMechanism>,
+    pub sasl_mechanisms: Vec<SaslMechanism>,
+    pub token: Option<String>,
+    pub sasl_provider: Option<Box<dyn SaslMechanismProvider>>,
+    pub max_frame_size: Option<u32>,
+    pub tls: Option<TlsOptions>,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub max_redirects: u32,
+    pub connect_timeout: Option<Duration>,
+    pub operation_timeout: Option<Duration>,
 }
 
 impl ConnectionOptions {
@@ -29,6 +45,15 @@ impl ConnectionOptions {
             username: None,
             password: None,
             sasl_mechanism: None,
+            sasl_mechanisms: Vec::new(),
+            token: None,
+            sasl_provider: None,
+            max_frame_size: None,
+            tls: None,
+            reconnect: None,
+            max_redirects: 5,
+            connect_timeout: None,
+            operation_timeout: None,
         }
     }
 
@@ -37,6 +62,14 @@ impl ConnectionOptions {
         self
     }
 
+    /// Mechanisms to use in order of preference. The first one offered by the server is
+    /// selected. If neither this nor sasl_mechanism is set, the strongest mechanism usable
+    /// with the given credentials is selected.
+    pub fn sasl_mechanisms(mut self, mechanisms: &[SaslMechanism]) -> Self {
+        self.sasl_mechanisms = mechanisms.to_vec();
+        self
+    }
+
     pub fn username(mut self, username: &str) -> Self {
         self.username = Some(username.to_string());
         self
@@ -46,13 +79,162 @@ impl ConnectionOptions {
         self.password = Some(password.to_string());
         self
     }
+
+    /// Bearer token for the XOAUTH2 and OAUTHBEARER mechanisms.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Use a custom provider for the client side of the SASL exchange. The provider takes
+    /// precedence over the mechanism and credentials set in these options.
+    pub fn sasl_provider<P: SaslMechanismProvider + 'static>(mut self, provider: P) -> Self {
+        self.sasl_provider = Some(Box::new(provider));
+        self
+    }
+
+    /// The largest frame accepted on this connection. Defaults to 64KB.
+    pub fn max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Connect using TLS with the given settings.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Time allowed for the connection to be opened, including redirects. No timeout by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Default time allowed for operations on the connection, such as creating sessions and
+    /// links, sending and receiving. No timeout by default.
+    pub fn operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// The number of connection redirects to follow when connecting. Defaults to 5.
+    pub fn max_redirects(mut self, max_redirects: u32) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Reconnect according to the given policy if the connection is lost, re-establishing
+    /// sessions and links. Without a policy, a lost connection is closed.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
 }
 
-/*
-// TODO: Listener
-#[derive(Debug)]
-pub struct ListenOptions {}
-*/
+/// Policy for reconnecting a lost connection. Attempts cycle through the original endpoint and
+/// the failover endpoints, waiting with exponential backoff between attempts.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub failover: Vec<(String, u16)>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy {
+            failover: Vec::new(),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+
+    /// Add an endpoint to try when the connection is lost.
+    pub fn failover(mut self, host: &str, port: u16) -> Self {
+        self.failover.push((host.to_string(), port));
+        self
+    }
+
+    /// Delay before the first attempt. The delay is doubled for every failed attempt.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Upper bound on the delay between attempts. Defaults to 30 seconds.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Give up after the given number of consecutive failed attempts. Defaults to no limit.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// The delay before the given attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        std::cmp::min(
+            self.initial_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    pub sasl_mechanisms: Vec<SaslMechanism>,
+    pub sasl_authenticator: Option<Box<dyn SaslAuthenticator>>,
+    pub max_frame_size: Option<u32>,
+    pub tls: Option<TlsOptions>,
+}
+
+impl ListenOptions {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> ListenOptions {
+        ListenOptions {
+            sasl_mechanisms: Vec::new(),
+            sasl_authenticator: None,
+            max_frame_size: None,
+            tls: None,
+        }
+    }
+
+    /// Add a SASL mechanism to advertise to clients. Defaults to PLAIN and ANONYMOUS if
+    /// an authenticator is set and no mechanisms are given.
+    pub fn sasl_mechanism(mut self, mechanism: SaslMechanism) -> Self {
+        self.sasl_mechanisms.push(mechanism);
+        self
+    }
+
+    /// Require clients to authenticate using SASL, checking credentials with the given authenticator.
+    pub fn sasl_authenticator<A: SaslAuthenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.sasl_authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// The largest frame accepted on incoming connections. Defaults to 64KB.
+    pub fn max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    /// Only accept TLS connections, using the given settings.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
 
 #[derive(Debug)]
 pub struct Connection<N: Network> {
@@ -60,6 +242,8 @@ pub struct Connection<N: Network> {
     state: ConnectionState,
     transport: Transport<N>,
     tx_frames: Vec<Frame>,
+    // Deliveries of which some but not all frames have been written, by channel and handle
+    partial_deliveries: HashMap<(ChannelId, HandleId), u32>,
     header_sent: bool,
 }
 
@@ -69,8 +253,7 @@ pub type HandleId = u32;
 #[derive(Debug)]
 enum ConnectionState {
     Start,
-    // TODO: Listener
-    // StartWait,
+    StartWait,
     Sasl,
     Opened,
     Closed,
@@ -84,13 +267,26 @@ pub fn connect<N: Network>(
     opts: ConnectionOptions,
 ) -> Result<Connection<N>> {
     let mut connection = Connection::new(transport);
-    if opts.username.is_some() || opts.password.is_some() || opts.sasl_mechanism.is_some() {
+    if let Some(provider) = opts.sasl_provider {
         connection.sasl = Some(Sasl {
-            role: SaslRole::Client(SaslClient {
-                mechanism: opts.sasl_mechanism.unwrap_or(SaslMechanism::Plain),
-                username: opts.username,
-                password: opts.password,
-            }),
+            role: SaslRole::Client(provider),
+            state: SaslState::InProgress,
+        });
+    } else if opts.username.is_some()
+        || opts.password.is_some()
+        || opts.token.is_some()
+        || opts.sasl_mechanism.is_some()
+        || !opts.sasl_mechanisms.is_empty()
+    {
+        let mechanisms = opts
+            .sasl_mechanism
+            .into_iter()
+            .chain(opts.sasl_mechanisms)
+            .collect();
+        let mut client = SaslClient::new(mechanisms, opts.username, opts.password);
+        client.token = opts.token;
+        connection.sasl = Some(Sasl {
+            role: SaslRole::Client(Box::new(client)),
             state: SaslState::InProgress,
         });
     }
@@ -99,32 +295,89 @@ pub fn connect<N: Network>(
     Ok(connection)
 }
 
-/*
+#[derive(Debug)]
 pub struct Listener {
     pub listener: TcpListener,
-    pub sasl_mechanisms: Option<Vec<SaslMechanism>>,
+    opts: ListenOptions,
+    tls: Option<TlsAcceptor>,
 }
 
-pub fn listen(host: &str, port: u16, _opts: ListenOptions) -> Result<Listener> {
-    let addr = format!("{}:{}", host, port).parse().unwrap();
+pub fn listen(host: &str, port: u16, opts: ListenOptions) -> Result<Listener> {
+    let addr = format!("{}:{}", host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| AmqpError::generic("unable to resolve listen address"))?;
+    let tls = match &opts.tls {
+        Some(tls) => Some(TlsAcceptor::new(tls)?),
+        None => None,
+    };
     let listener = TcpListener::bind(addr)?;
     Ok(Listener {
         listener,
-        sasl_mechanisms: None,
+        opts,
+        tls,
     })
 }
 
 impl Listener {
-    pub fn accept(&mut self) -> Result<Connection> {
+    /// Accept an incoming connection. The returned connection performs the server half of the
+    /// protocol header exchange when processed.
+    pub fn accept(&mut self) -> Result<Connection<MioNetwork>> {
         let (stream, _addr) = self.listener.accept()?;
-        let transport: Transport = Transport::new(stream, 1024)?;
+        let network = match &self.tls {
+            Some(acceptor) => MioNetwork::from_stream_tls(stream, acceptor),
+            None => MioNetwork::from_stream(stream),
+        };
+        let max_frame_size = self
+            .opts
+            .max_frame_size
+            .map_or(DEFAULT_MAX_FRAME_SIZE, |s| s as usize);
+        let transport = Transport::new(network, max_frame_size);
 
         let mut connection = Connection::new(transport);
+        if let Some(authenticator) = &self.opts.sasl_authenticator {
+            let mechanisms = if self.opts.sasl_mechanisms.is_empty() {
+                vec![SaslMechanism::Plain, SaslMechanism::Anonymous]
+            } else {
+                self.opts.sasl_mechanisms.clone()
+            };
+            connection.sasl = Some(Sasl {
+                role: SaslRole::Server(SaslServer::new(mechanisms, authenticator.clone())),
+                state: SaslState::InProgress,
+            });
+        }
         connection.state = ConnectionState::StartWait;
         Ok(connection)
     }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.listener.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.listener.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.listener.deregister(registry)
+    }
 }
-*/
 
 impl<N: Network> Connection<N> {
     pub fn new(transport: Transport<N>) -> Connection<N> {
@@ -133,11 +386,17 @@ impl<N: Network> Connection<N> {
             state: ConnectionState::Start,
             sasl: None,
             tx_frames: Vec::new(),
+            partial_deliveries: HashMap::new(),
             header_sent: false,
         }
     }
 
-    pub fn open(&mut self, open: Open) -> Result<()> {
+    /// Send the open performative. The max frame size is always set to the one used by the
+    /// transport.
+    pub fn open(&mut self, mut open: Open) -> Result<()> {
+        let max_frame_size =
+            std::cmp::min(self.transport.local_max_frame_size(), u32::MAX as usize);
+        open.max_frame_size = Some(max_frame_size as u32);
         self.tx_frames.push(Frame::AMQP(AmqpFrame {
             channel: 0,
             performative: Some(Performative::Open(open)),
@@ -173,6 +432,11 @@ impl<N: Network> Connection<N> {
         Ok(())
     }
 
+    /// The largest frame that may be sent on this connection.
+    pub fn max_frame_size(&self) -> usize {
+        self.transport.max_frame_size()
+    }
+
     pub fn transfer(
         &mut self,
         channel: ChannelId,
@@ -187,6 +451,75 @@ impl<N: Network> Connection<N> {
         Ok(())
     }
 
+    /// Abort a delivery that has not been completely written. Queued frames of the delivery are
+    /// discarded, and if some of its frames have already been written, a transfer with the
+    /// aborted flag is sent in their place. Returns false if the delivery was already written.
+    pub fn abort_transfer(
+        &mut self,
+        channel: ChannelId,
+        handle: u32,
+        delivery_id: u32,
+    ) -> Result<bool> {
+        let link_transfer = |frame: &Frame| -> Option<Transfer> {
+            match frame {
+                Frame::AMQP(AmqpFrame {
+                    channel: c,
+                    performative: Some(Performative::Transfer(transfer)),
+                    ..
+                }) if *c as ChannelId == channel && transfer.handle == handle => {
+                    Some(transfer.clone())
+                }
+                _ => None,
+            }
+        };
+
+        let first = self
+            .tx_frames
+            .iter()
+            .position(|f| link_transfer(f).and_then(|t| t.delivery_id) == Some(delivery_id));
+        let (start, partially_written) = match first {
+            Some(start) => (start, false),
+            // Frames are written in order, so the first queued frame on the link continues the
+            // delivery that is partially written.
+            None if self.partial_deliveries.get(&(channel, handle)) == Some(&delivery_id) => {
+                match self
+                    .tx_frames
+                    .iter()
+                    .position(|f| link_transfer(f).is_some())
+                {
+                    Some(start) => (start, true),
+                    None => return Ok(false),
+                }
+            }
+            None => return Ok(false),
+        };
+
+        while start < self.tx_frames.len() {
+            match link_transfer(&self.tx_frames[start]) {
+                Some(transfer) => {
+                    self.tx_frames.remove(start);
+                    if transfer.more != Some(true) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if partially_written {
+            let transfer = Transfer::new(handle).settled(true).aborted(true);
+            self.tx_frames.insert(
+                start,
+                Frame::AMQP(AmqpFrame {
+                    channel,
+                    performative: Some(Performative::Transfer(transfer)),
+                    payload: None,
+                }),
+            );
+        }
+        Ok(true)
+    }
+
     pub fn disposition(&mut self, channel: ChannelId, disposition: Disposition) -> Result<()> {
         self.tx_frames.push(Frame::AMQP(AmqpFrame {
             channel: channel as u16,
@@ -257,6 +590,19 @@ impl<N: Network> Connection<N> {
                 for frame in self.tx_frames.drain(..) {
                     debug!("TX {:?}", frame);
                     self.transport.write_frame(&frame)?;
+                    if let Frame::AMQP(AmqpFrame {
+                        channel,
+                        performative: Some(Performative::Transfer(ref transfer)),
+                        ..
+                    }) = frame
+                    {
+                        let link = (channel, transfer.handle);
+                        if transfer.more != Some(true) {
+                            self.partial_deliveries.remove(&link);
+                        } else if let Some(delivery_id) = transfer.delivery_id {
+                            self.partial_deliveries.insert(link, delivery_id);
+                        }
+                    }
                 }
             }
             _ => {}
@@ -296,8 +642,6 @@ impl<N: Network> Connection<N> {
                     }
                 }
             }
-            /*
-            TODO: Listener
             ConnectionState::StartWait => {
                 let header = self.transport.read_protocol_header()?;
                 if let Some(header) = header {
@@ -313,7 +657,14 @@ impl<N: Network> Connection<N> {
                             self.transport.flush()?;
                         }
                         _ => {
-                            self.transport.write_protocol_header(&AMQP_10_HEADER)?;
+                            // Reply with the header we support before closing, as required by the spec,
+                            // so that the client can retry with SASL if it is required.
+                            let supported = if self.skip_sasl() {
+                                AMQP_10_HEADER
+                            } else {
+                                SASL_10_HEADER
+                            };
+                            self.transport.write_protocol_header(&supported)?;
                             self.state = ConnectionState::Closed;
                             self.transport.flush()?;
                             self.transport.close()?;
@@ -321,13 +672,15 @@ impl<N: Network> Connection<N> {
                     }
                 }
             }
-            */
             ConnectionState::Sasl => {
                 let sasl = self.sasl.as_mut().unwrap();
                 match sasl.state {
                     SaslState::Success => {
                         self.header_sent = false;
-                        self.state = ConnectionState::Start;
+                        self.state = match sasl.role {
+                            SaslRole::Server(_) => ConnectionState::StartWait,
+                            SaslRole::Client(_) => ConnectionState::Start,
+                        };
                     }
                     SaslState::Failed => {
                         self.transport.close()?;
@@ -340,6 +693,15 @@ impl<N: Network> Connection<N> {
             }
             ConnectionState::Opened => {
                 let frame = self.transport.read_frame()?;
+                if let Frame::AMQP(AmqpFrame {
+                    performative: Some(Performative::Open(ref open)),
+                    ..
+                }) = frame
+                {
+                    let max_frame_size = open.max_frame_size.unwrap_or(u32::MAX);
+                    self.transport
+                        .set_remote_max_frame_size(max_frame_size as usize);
+                }
                 frames.push(frame);
             }
             ConnectionState::Closed => {
@@ -352,3 +714,736 @@ impl<N: Network> Connection<N> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn is_would_block(result: &Result<()>) -> bool {
+        match result {
+            Err(AmqpError::Io(e)) => e.kind() == std::io::ErrorKind::WouldBlock,
+            _ => false,
+        }
+    }
+
+    fn accept(listener: &mut Listener) -> Connection<MioNetwork> {
+        loop {
+            match listener.accept() {
+                Ok(connection) => return connection,
+                Err(AmqpError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => panic!("error accepting connection: {:?}", e),
+            }
+        }
+    }
+
+    // Drive both ends until they have exchanged open frames or one of them fails.
+    fn exchange_open(
+        client: &mut Connection<MioNetwork>,
+        server: &mut Connection<MioNetwork>,
+    ) -> (Option<Open>, Option<Open>) {
+        client.open(Open::new("client")).unwrap();
+
+        let mut client_open = None;
+        let mut server_open = None;
+        for _ in 0..500 {
+            let mut frames = Vec::new();
+            let result = server.process(&mut frames);
+            if !(result.is_ok() || is_would_block(&result)) {
+                break;
+            }
+            for frame in frames.drain(..) {
+                if let Frame::AMQP(AmqpFrame {
+                    performative: Some(Performative::Open(open)),
+                    ..
+                }) = frame
+                {
+                    server.open(Open::new("server")).unwrap();
+                    server_open = Some(open);
+                }
+            }
+            if server.flush().is_err() {
+                break;
+            }
+
+            let result = client.process(&mut frames);
+            if !(result.is_ok() || is_would_block(&result)) {
+                break;
+            }
+            for frame in frames.drain(..) {
+                if let Frame::AMQP(AmqpFrame {
+                    performative: Some(Performative::Open(open)),
+                    ..
+                }) = frame
+                {
+                    client_open = Some(open);
+                }
+            }
+            if client.flush().is_err() {
+                break;
+            }
+
+            if client_open.is_some() && server_open.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        (client_open, server_open)
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestAuthenticator;
+
+    impl SaslAuthenticator for TestAuthenticator {
+        fn plain(&mut self, username: &str, password: &str) -> bool {
+            username == "test" && password == "secret"
+        }
+    }
+
+    #[test]
+    fn listen_and_accept() {
+        let mut listener = listen("127.0.0.1", 0, ListenOptions::new()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let mut client = connect(Transport::new(network, 1024), ConnectionOptions::new()).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert_eq!(
+            "client",
+            server_open.expect("server never got open").container_id
+        );
+        assert_eq!(
+            "server",
+            client_open.expect("client never got open").container_id
+        );
+    }
+
+    #[test]
+    fn negotiate_max_frame_size() {
+        let opts = ListenOptions::new().max_frame_size(4096);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let mut client = connect(Transport::new(network, 1024), ConnectionOptions::new()).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert_eq!(Some(1024), server_open.unwrap().max_frame_size);
+        assert_eq!(Some(4096), client_open.unwrap().max_frame_size);
+        assert_eq!(1024, client.max_frame_size());
+        assert_eq!(1024, server.max_frame_size());
+    }
+
+    #[test]
+    fn abort_transfer() {
+        let mut listener = listen("127.0.0.1", 0, ListenOptions::new()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let mut client = connect(
+            Transport::new(network, DEFAULT_MAX_FRAME_SIZE),
+            ConnectionOptions::new(),
+        )
+        .unwrap();
+        let _server = accept(&mut listener);
+
+        let transfers = |client: &Connection<MioNetwork>| -> Vec<Transfer> {
+            client
+                .tx_frames
+                .iter()
+                .filter_map(|f| match f {
+                    Frame::AMQP(AmqpFrame {
+                        performative: Some(Performative::Transfer(t)),
+                        ..
+                    }) => Some(t.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Delivery that has not been written is removed
+        client
+            .transfer(0, Transfer::new(1).delivery_id(0).more(true), None)
+            .unwrap();
+        client
+            .transfer(0, Transfer::new(1).more(true), None)
+            .unwrap();
+        client.transfer(0, Transfer::new(1), None).unwrap();
+        client
+            .transfer(0, Transfer::new(1).delivery_id(1), None)
+            .unwrap();
+        assert!(client.abort_transfer(0, 1, 0).unwrap());
+        let queued = transfers(&client);
+        assert_eq!(1, queued.len());
+        assert_eq!(Some(1), queued[0].delivery_id);
+
+        // Delivery that has been partially written is replaced by an aborted transfer
+        client.tx_frames.clear();
+        client.state = ConnectionState::Opened;
+        client
+            .transfer(0, Transfer::new(1).delivery_id(2).more(true), None)
+            .unwrap();
+        client.flush().unwrap();
+        client
+            .transfer(0, Transfer::new(1).more(true), None)
+            .unwrap();
+        client.transfer(0, Transfer::new(1), None).unwrap();
+        assert!(client.abort_transfer(0, 1, 2).unwrap());
+        let queued = transfers(&client);
+        assert_eq!(1, queued.len());
+        assert_eq!(Some(true), queued[0].aborted);
+
+        // Delivery that has been written cannot be aborted
+        client.flush().unwrap();
+        assert!(!client.abort_transfer(0, 1, 2).unwrap());
+
+        // Frames of the next delivery on the link are kept when aborting a written delivery
+        client
+            .transfer(0, Transfer::new(1).delivery_id(3), None)
+            .unwrap();
+        client
+            .transfer(0, Transfer::new(1).delivery_id(4).more(true), None)
+            .unwrap();
+        client.flush().unwrap();
+        client.transfer(0, Transfer::new(1), None).unwrap();
+        assert!(!client.abort_transfer(0, 1, 3).unwrap());
+        let queued = transfers(&client);
+        assert_eq!(1, queued.len());
+        assert_ne!(Some(true), queued[0].aborted);
+        assert!(client.abort_transfer(0, 1, 4).unwrap());
+        let queued = transfers(&client);
+        assert_eq!(1, queued.len());
+        assert_eq!(Some(true), queued[0].aborted);
+    }
+
+    #[test]
+    fn reject_oversized_frame() {
+        let opts = ListenOptions::new().max_frame_size(1024);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut server = accept(&mut listener);
+        stream
+            .write_all(&[b'A', b'M', b'Q', b'P', 0, 1, 0, 0])
+            .unwrap();
+        stream.write_all(&[0, 0, 8, 0, 2, 0, 0, 0]).unwrap();
+
+        let mut frames = Vec::new();
+        for _ in 0..500 {
+            let result = server.process(&mut frames);
+            match result {
+                Err(AmqpError::Amqp(e)) => {
+                    assert_eq!(Condition::FramingError, e.kind());
+                    return;
+                }
+                Err(ref e) if !is_would_block(&result) => panic!("unexpected error {:?}", e),
+                _ => {}
+            }
+            let _ = server.flush();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("oversized frame not rejected");
+    }
+
+    #[test]
+    fn sasl_plain_accepted() {
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .username("test")
+            .password("secret");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    #[test]
+    fn sasl_plain_rejected() {
+        let opts = ListenOptions::new().sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .username("test")
+            .password("wrong");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_none());
+        assert!(client_open.is_none());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn sasl_external_with_tls() {
+        #[derive(Debug, Clone)]
+        struct ExternalAuthenticator;
+
+        impl SaslAuthenticator for ExternalAuthenticator {
+            fn init(
+                &mut self,
+                mechanism: SaslMechanism,
+                initial_response: Option<&[u8]>,
+                _hostname: Option<&str>,
+            ) -> SaslStep {
+                if mechanism == SaslMechanism::External && initial_response == Some(b"") {
+                    SaslStep::Outcome(SASL_CODE_OK)
+                } else {
+                    SaslStep::Outcome(SASL_CODE_AUTH)
+                }
+            }
+        }
+
+        let ca = include_bytes!("../tests/certs/ca.pem");
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::External)
+            .sasl_authenticator(ExternalAuthenticator)
+            .tls(TlsOptions::new().ca_certs(ca).certificate(
+                include_bytes!("../tests/certs/server.pem"),
+                include_bytes!("../tests/certs/server.key"),
+            ));
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let tls = TlsOptions::new()
+            .ca_certs(ca)
+            .certificate(
+                include_bytes!("../tests/certs/client.pem"),
+                include_bytes!("../tests/certs/client.key"),
+            )
+            .server_name("localhost");
+        let network = MioNetwork::connect_tls("127.0.0.1", port, &tls).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::External)
+            .tls(tls);
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    /// Minimal SCRAM server peer for user "test" with password "secret".
+    #[derive(Debug, Clone)]
+    struct ScramAuthenticator {
+        hash: ScramHash,
+        auth_message: String,
+        server_signature: Vec<u8>,
+        stored_key: Vec<u8>,
+        done: bool,
+    }
+
+    impl ScramAuthenticator {
+        fn new(hash: ScramHash) -> ScramAuthenticator {
+            ScramAuthenticator {
+                hash,
+                auth_message: String::new(),
+                server_signature: Vec::new(),
+                stored_key: Vec::new(),
+                done: false,
+            }
+        }
+    }
+
+    impl SaslAuthenticator for ScramAuthenticator {
+        fn init(
+            &mut self,
+            _mechanism: SaslMechanism,
+            initial_response: Option<&[u8]>,
+            _hostname: Option<&str>,
+        ) -> SaslStep {
+            let client_first = std::str::from_utf8(initial_response.unwrap()).unwrap();
+            let client_first_bare = client_first.strip_prefix("n,,").unwrap();
+            let client_nonce = client_first_bare.split(",r=").nth(1).unwrap();
+            let salt = b"salty";
+            let server_first = format!(
+                "r={}servernonce,s={},i=4096",
+                client_nonce,
+                base64::encode(salt)
+            );
+
+            let salted_password = self.hash.salted_password("secret", salt, 4096);
+            let client_key = self.hash.hmac(&salted_password, b"Client Key");
+            self.stored_key = self.hash.hash(&client_key);
+            let server_key = self.hash.hmac(&salted_password, b"Server Key");
+            self.auth_message = format!(
+                "{},{},c=biws,r={}servernonce",
+                client_first_bare, server_first, client_nonce
+            );
+            self.server_signature = self.hash.hmac(&server_key, self.auth_message.as_bytes());
+            SaslStep::Challenge(server_first.into_bytes())
+        }
+
+        fn response(&mut self, response: &[u8]) -> SaslStep {
+            if self.done {
+                return SaslStep::Outcome(SASL_CODE_OK);
+            }
+            let client_final = std::str::from_utf8(response).unwrap();
+            let proof = base64::decode(client_final.split(",p=").nth(1).unwrap()).unwrap();
+            let client_signature = self
+                .hash
+                .hmac(&self.stored_key, self.auth_message.as_bytes());
+            let client_key: Vec<u8> = proof
+                .iter()
+                .zip(client_signature.iter())
+                .map(|(p, s)| p ^ s)
+                .collect();
+            if self.hash.hash(&client_key) != self.stored_key {
+                return SaslStep::Outcome(SASL_CODE_AUTH);
+            }
+            self.done = true;
+            SaslStep::Challenge(
+                format!("v={}", base64::encode(&self.server_signature)).into_bytes(),
+            )
+        }
+    }
+
+    fn scram_exchange(
+        mechanism: SaslMechanism,
+        authenticator: ScramAuthenticator,
+        password: &str,
+    ) -> (Option<Open>, Option<Open>) {
+        let opts = ListenOptions::new()
+            .sasl_mechanism(mechanism.clone())
+            .sasl_authenticator(authenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(mechanism)
+            .username("test")
+            .password(password);
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        exchange_open(&mut client, &mut server)
+    }
+
+    #[test]
+    fn sasl_scram_accepted() {
+        for (mechanism, hash) in [
+            (SaslMechanism::ScramSha1, ScramHash::Sha1),
+            (SaslMechanism::ScramSha256, ScramHash::Sha256),
+        ]
+        .iter()
+        {
+            let (client_open, server_open) =
+                scram_exchange(mechanism.clone(), ScramAuthenticator::new(*hash), "secret");
+            assert!(server_open.is_some());
+            assert!(client_open.is_some());
+        }
+    }
+
+    #[test]
+    fn sasl_scram_rejected() {
+        let (client_open, server_open) = scram_exchange(
+            SaslMechanism::ScramSha256,
+            ScramAuthenticator::new(ScramHash::Sha256),
+            "wrong",
+        );
+        assert!(server_open.is_none());
+        assert!(client_open.is_none());
+    }
+
+    #[test]
+    fn sasl_scram_bad_server_signature() {
+        // The server reports success without ever proving it knows the password
+        let mut authenticator = ScramAuthenticator::new(ScramHash::Sha256);
+        authenticator.done = true;
+        let (client_open, _) = scram_exchange(SaslMechanism::ScramSha256, authenticator, "secret");
+        assert!(client_open.is_none());
+    }
+
+    #[derive(Debug, Clone)]
+    struct ChallengeAuthenticator;
+
+    impl SaslAuthenticator for ChallengeAuthenticator {
+        fn init(
+            &mut self,
+            mechanism: SaslMechanism,
+            initial_response: Option<&[u8]>,
+            _hostname: Option<&str>,
+        ) -> SaslStep {
+            match mechanism {
+                SaslMechanism::CramMd5 => SaslStep::Challenge(b"<1234@localhost>".to_vec()),
+                SaslMechanism::OAuthBearer
+                    if initial_response == Some(b"n,,\x01auth=Bearer token\x01\x01") =>
+                {
+                    SaslStep::Outcome(SASL_CODE_OK)
+                }
+                _ => SaslStep::Outcome(SASL_CODE_AUTH),
+            }
+        }
+
+        fn response(&mut self, response: &[u8]) -> SaslStep {
+            let expected = cram_md5_response("test", "secret", b"<1234@localhost>");
+            if response == expected.as_bytes() {
+                SaslStep::Outcome(SASL_CODE_OK)
+            } else {
+                SaslStep::Outcome(SASL_CODE_AUTH)
+            }
+        }
+    }
+
+    fn challenge_exchange(
+        mechanism: SaslMechanism,
+        opts: ConnectionOptions,
+    ) -> (Option<Open>, Option<Open>) {
+        let listen_opts = ListenOptions::new()
+            .sasl_mechanism(mechanism)
+            .sasl_authenticator(ChallengeAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, listen_opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+        exchange_open(&mut client, &mut server)
+    }
+
+    #[test]
+    fn sasl_cram_md5() {
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::CramMd5)
+            .username("test")
+            .password("secret");
+        let (client_open, server_open) = challenge_exchange(SaslMechanism::CramMd5, opts);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::CramMd5)
+            .username("test")
+            .password("wrong");
+        let (client_open, _) = challenge_exchange(SaslMechanism::CramMd5, opts);
+        assert!(client_open.is_none());
+    }
+
+    #[test]
+    fn sasl_oauthbearer() {
+        let opts = ConnectionOptions::new().token("token");
+        let (client_open, server_open) = challenge_exchange(SaslMechanism::OAuthBearer, opts);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    #[test]
+    fn sasl_custom_provider() {
+        #[derive(Debug, Clone)]
+        struct SiteProvider;
+
+        impl SaslMechanismProvider for SiteProvider {
+            fn select(&mut self, offered: &[SaslMechanism]) -> Option<SaslMechanism> {
+                offered.iter().find(|m| m.to_str() == "X-SITE").cloned()
+            }
+
+            fn initial_response(&mut self, _mechanism: &SaslMechanism) -> Result<Option<Vec<u8>>> {
+                Ok(Some(b"ticket".to_vec()))
+            }
+
+            fn challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+                Ok(challenge.iter().rev().cloned().collect())
+            }
+        }
+
+        #[derive(Debug, Clone)]
+        struct SiteAuthenticator;
+
+        impl SaslAuthenticator for SiteAuthenticator {
+            fn init(
+                &mut self,
+                mechanism: SaslMechanism,
+                initial_response: Option<&[u8]>,
+                _hostname: Option<&str>,
+            ) -> SaslStep {
+                if mechanism == SaslMechanism::Other("X-SITE".to_string())
+                    && initial_response == Some(b"ticket")
+                {
+                    SaslStep::Challenge(b"abc".to_vec())
+                } else {
+                    SaslStep::Outcome(SASL_CODE_AUTH)
+                }
+            }
+
+            fn response(&mut self, response: &[u8]) -> SaslStep {
+                SaslStep::Outcome(if response == b"cba" {
+                    SASL_CODE_OK
+                } else {
+                    SASL_CODE_AUTH
+                })
+            }
+        }
+
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::Other("X-SITE".to_string()))
+            .sasl_authenticator(SiteAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new().sasl_provider(SiteProvider);
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    // Drive both ends until the client fails, returning the error.
+    fn client_error(
+        client: &mut Connection<MioNetwork>,
+        server: &mut Connection<MioNetwork>,
+    ) -> AmqpError {
+        client.open(Open::new("client")).unwrap();
+        let mut frames = Vec::new();
+        for _ in 0..500 {
+            let _ = server.process(&mut frames);
+            let _ = server.flush();
+            let result = client.process(&mut frames);
+            if !is_would_block(&result) {
+                if let Err(e) = result {
+                    return e;
+                }
+            }
+            let _ = client.flush();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("client did not fail");
+    }
+
+    #[test]
+    fn sasl_auto_select() {
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .sasl_mechanism(SaslMechanism::ScramSha256)
+            .sasl_authenticator(ScramAuthenticator::new(ScramHash::Sha256));
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new().username("test").password("secret");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    #[test]
+    fn sasl_preference_list() {
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanisms(&[SaslMechanism::ScramSha256, SaslMechanism::Plain])
+            .username("test")
+            .password("secret");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_some());
+        assert!(client_open.is_some());
+    }
+
+    #[test]
+    fn sasl_failure_reported() {
+        let opts = ListenOptions::new()
+            .sasl_mechanism(SaslMechanism::Plain)
+            .sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new()
+            .sasl_mechanism(SaslMechanism::ScramSha256)
+            .username("test")
+            .password("secret");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+        let error = client_error(&mut client, &mut server);
+        assert!(matches!(error, AmqpError::Sasl { code: None, .. }));
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let opts = ConnectionOptions::new().username("test").password("wrong");
+        let mut client = connect(Transport::new(network, 1024), opts).unwrap();
+        let mut server = accept(&mut listener);
+        let error = client_error(&mut client, &mut server);
+        assert!(matches!(
+            error,
+            AmqpError::Sasl {
+                code: Some(SASL_CODE_AUTH),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn sasl_required() {
+        let opts = ListenOptions::new().sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+        let mut client = connect(Transport::new(network, 1024), ConnectionOptions::new()).unwrap();
+        let mut server = accept(&mut listener);
+
+        let (client_open, server_open) = exchange_open(&mut client, &mut server);
+        assert!(server_open.is_none());
+        assert!(client_open.is_none());
+    }
+
+    #[test]
+    fn sasl_required_header() {
+        let opts = ListenOptions::new().sasl_authenticator(TestAuthenticator);
+        let mut listener = listen("127.0.0.1", 0, opts).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut server = accept(&mut listener);
+        stream
+            .write_all(&[b'A', b'M', b'Q', b'P', 0, 1, 0, 0])
+            .unwrap();
+
+        let mut frames = Vec::new();
+        for _ in 0..500 {
+            let result = server.process(&mut frames);
+            if !(result.is_ok() || is_would_block(&result)) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut header = [0; 8];
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!([b'A', b'M', b'Q', b'P', 3, 1, 0, 0], header);
+    }
+}
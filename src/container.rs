@@ -3,30 +3,40 @@
  * License: Apache License 2.0 (see the file LICENSE or http://apache.org/licenses/LICENSE-2.0.html).
  */
 
-//! The container module contains a simple API for creating client connections and sending and receiving messages
+//! The container module contains a simple API for creating client connections, accepting incoming connections and sending and receiving messages
 
 use crate::conn;
 use crate::driver::{
-    Channel, ConnectionDriver, DeliveryDriver, LinkDriver, SessionDriver, SessionOpts,
+    Channel, ConnectionDriver, DeliveryDriver, LinkDriver, SessionDriver, SessionOpts, Signal,
+    Timers,
 };
 use crate::error::*;
-use crate::framing::{AmqpFrame, Close, LinkRole, Open, Performative};
+use crate::framing::{
+    AmqpFrame, Attach, Close, Declare, Discharge, LinkRole, LinkTarget, Open, Performative,
+};
 use crate::transport;
+use crate::transport::mio::MioNetwork;
 
 use log::{error, trace};
-use mio::{Events, Poll, Token, Waker};
-use std::collections::HashMap;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 // Re-exports
-pub use crate::conn::ConnectionOptions;
-pub use crate::framing::DeliveryState;
+pub use crate::conn::{ConnectionOptions, ListenOptions, ReconnectPolicy};
+pub use crate::driver::{ConnectionEvent, LinkOptions};
+pub use crate::framing::{
+    Declared, DeliveryState, DistributionMode, Outcome, ReceiverSettleMode, SenderSettleMode,
+    TerminusDurability, TerminusExpiryPolicy, TransactionalState,
+};
 pub use crate::message::{Message, MessageProperties};
-pub use crate::sasl::SaslMechanism;
+pub use crate::sasl::{SaslAuthenticator, SaslMechanism, SaslMechanismProvider, SaslStep};
+pub use crate::transport::TlsOptions;
 pub use crate::types::{Value, ValueRef};
 
 /// Represents an AMQP 1.0 container that can manage multiple connections.
@@ -41,9 +51,50 @@ struct ContainerInner {
     poll: Mutex<Poll>,
     incoming: Channel<Token>,
     connections: Mutex<HashMap<Token, Arc<ConnectionDriver>>>,
+    incoming_listeners: Channel<Token>,
+    listeners: Mutex<HashMap<Token, Arc<ListenerInner>>>,
     token_generator: AtomicU32,
     waker: Arc<Waker>,
+    timers: Arc<Timers>,
     closed: AtomicBool,
+    reconnects: Mutex<HashMap<Token, Reconnect>>,
+}
+
+// Reconnect state of a connection with a reconnect policy
+struct Reconnect {
+    host: String,
+    port: u16,
+    opts: ConnectionOptions,
+    policy: ReconnectPolicy,
+    attempt: u32,
+    next_attempt: Option<Instant>,
+    // Endpoint to use for the next attempt, as requested by the remote
+    redirect: Option<(String, u16)>,
+}
+
+impl Reconnect {
+    // The endpoint to use for the given attempt, cycling through the original and failover endpoints.
+    fn endpoint(&self, attempt: u32) -> (String, u16) {
+        let index = (attempt.saturating_sub(1) as usize) % (self.policy.failover.len() + 1);
+        if index == 0 {
+            (self.host.clone(), self.port)
+        } else {
+            self.policy.failover[index - 1].clone()
+        }
+    }
+}
+
+/// Represents a listener accepting AMQP connections from remote endpoints.
+pub struct Listener {
+    listener: Arc<ListenerInner>,
+    waker: Arc<Waker>,
+    container_id: String,
+}
+
+struct ListenerInner {
+    listener: Mutex<conn::Listener>,
+    incoming: Channel<Arc<ConnectionDriver>>,
+    local_addr: SocketAddr,
 }
 
 /// Represents a single AMQP connection to a remote endpoint.
@@ -59,6 +110,7 @@ pub struct Connection {
     pub remote_idle_timeout: Duration,
     pub remote_container_id: String,
     pub remote_channel_max: u16,
+    pub remote_max_frame_size: u32,
 }
 
 /// Represents an AMQP session.
@@ -71,6 +123,7 @@ pub struct Session {
 /// Represents a sender link.
 #[allow(dead_code)]
 pub struct Sender {
+    address: Option<String>,
     handle: u32,
     waker: Arc<Waker>,
     connection: Arc<ConnectionDriver>,
@@ -81,6 +134,7 @@ pub struct Sender {
 /// Represents a receiver link.
 #[allow(dead_code)]
 pub struct Receiver {
+    address: Option<String>,
     waker: Arc<Waker>,
     handle: u32,
     connection: Arc<ConnectionDriver>,
@@ -92,14 +146,62 @@ pub struct Receiver {
 #[allow(dead_code)]
 pub struct Disposition {
     delivery: Arc<DeliveryDriver>,
+    state: Option<DeliveryState>,
+}
+
+/// Represents a message that has been queued for sending, but may not have been completely sent.
+pub struct PendingDelivery {
+    waker: Arc<Waker>,
+    connection: Arc<ConnectionDriver>,
+    link: Arc<LinkDriver>,
+    delivery: Arc<DeliveryDriver>,
+    aborted: AtomicBool,
 }
 
 /// Represent a delivery
 pub struct Delivery {
     settled: bool,
     waker: Arc<Waker>,
+    connection: Arc<ConnectionDriver>,
     link: Arc<LinkDriver>,
     delivery: Arc<DeliveryDriver>,
+    txn_id: Option<Vec<u8>>,
+}
+
+/// Represents a transaction declared at a remote transaction coordinator. Messages sent and
+/// deliveries settled within the transaction take effect when it is committed. Dropping the
+/// transaction without committing it detaches the link to the coordinator, which rolls back the
+/// transaction.
+pub struct Transaction {
+    id: Vec<u8>,
+    connection: Arc<ConnectionDriver>,
+    controller: Sender,
+}
+
+/// A client for request/response messaging with a service. Requests are sent to the address of
+/// the service with a reply-to address of a temporary node created by the other side, and replies
+/// are routed back to the request with the same correlation id.
+pub struct RequestResponseClient {
+    sender: Sender,
+    receiver: Receiver,
+    reply_to: String,
+    next_correlation_id: AtomicU64,
+    // Requests waiting for a reply by correlation id, with the reply once received
+    waiting: Mutex<BTreeMap<Value, Option<Message>>>,
+    // Set while a request is receiving replies on behalf of all requests
+    receiving: AtomicBool,
+    // Notified when a reply is passed on, or no request is receiving replies
+    replied: Signal,
+}
+
+// Lets other requests receive replies once the request receiving them is done.
+struct ReceivingReplies<'a>(&'a RequestResponseClient);
+
+impl Drop for ReceivingReplies<'_> {
+    fn drop(&mut self) {
+        self.0.receiving.store(false, Ordering::SeqCst);
+        self.0.replied.notify();
+    }
 }
 
 impl Container {
@@ -112,15 +214,20 @@ impl Container {
 
     pub fn with_id(container_id: &str) -> Result<Container> {
         let p = Poll::new()?;
-        let waker = Arc::new(Waker::new(p.registry(), Token(std::u32::MAX as usize))?);
+        let waker = Arc::new(Waker::new(p.registry(), Token(u32::MAX as usize))?);
+        let timers = Arc::new(Timers::new(waker.clone()));
         let inner = ContainerInner {
             container_id: container_id.to_string(),
-            incoming: Channel::new(),
+            incoming: Channel::new(timers.clone()),
             poll: Mutex::new(p),
             connections: Mutex::new(HashMap::new()),
+            incoming_listeners: Channel::new(timers.clone()),
+            listeners: Mutex::new(HashMap::new()),
             token_generator: AtomicU32::new(0),
             waker,
+            timers,
             closed: AtomicBool::new(false),
+            reconnects: Mutex::new(HashMap::new()),
         };
         Ok(Container {
             container: Arc::new(inner),
@@ -166,7 +273,27 @@ impl Container {
         port: u16,
         opts: ConnectionOptions,
     ) -> Result<Connection> {
-        self.container.connect(host, port, opts).await
+        let deadline = opts.connect_timeout.map(|t| Instant::now() + t);
+        self.container.connect(host, port, opts, deadline).await
+    }
+
+    /// Connect to an AMQP endpoint, failing with a timeout error if the connection is not
+    /// opened within the given time.
+    pub async fn connect_timeout(
+        &self,
+        host: &str,
+        port: u16,
+        opts: ConnectionOptions,
+        timeout: Duration,
+    ) -> Result<Connection> {
+        let deadline = Some(Instant::now() + timeout);
+        self.container.connect(host, port, opts, deadline).await
+    }
+
+    /// Listen for incoming AMQP connections on the given host and port. Use the accept() method
+    /// on the returned listener to retrieve connections.
+    pub fn listen(&self, host: &str, port: u16, opts: ListenOptions) -> Result<Listener> {
+        self.container.listen(host, port, opts)
     }
 
     /// Close the connection. Flushes outgoing buffer before sending the final close performative,
@@ -201,27 +328,106 @@ impl ContainerInner {
 
         for (_id, connection) in self.connections.lock().unwrap().drain() {
             connection.close(None)?;
+            connection.close_channels(None);
         }
+        self.listeners.lock().unwrap().clear();
         self.waker.wake()?;
         trace!("{}: container is shut down", self.container_id);
         Ok(())
     }
 
-    async fn connect(&self, host: &str, port: u16, opts: ConnectionOptions) -> Result<Connection> {
-        let network = transport::mio::MioNetwork::connect(host, port)?;
-        let transport = transport::Transport::new(network, 1024);
+    // Connect to an endpoint and queue the open performative.
+    fn open_connection(
+        &self,
+        host: &str,
+        port: u16,
+        hostname: &str,
+        opts: ConnectionOptions,
+    ) -> Result<conn::Connection<MioNetwork>> {
+        let network = match &opts.tls {
+            Some(tls) => MioNetwork::connect_tls(host, port, tls)?,
+            None => MioNetwork::connect(host, port)?,
+        };
+        let max_frame_size = opts
+            .max_frame_size
+            .map_or(transport::DEFAULT_MAX_FRAME_SIZE, |s| s as usize);
+        let transport = transport::Transport::new(network, max_frame_size);
         let mut driver = conn::connect(transport, opts)?;
         trace!("{}: connected to {}:{}", self.container_id, host, port);
 
-        let mut open = Open::new(self.container_id.as_str());
-        open.hostname = Some(host.to_string());
-        open.channel_max = Some(std::u16::MAX);
-        open.idle_timeout = Some(5000);
-        driver.open(open)?;
+        driver.open(local_open(&self.container_id, Some(hostname)))?;
+        Ok(driver)
+    }
+
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        opts: ConnectionOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Connection> {
+        let mut endpoint = (host.to_string(), port, host.to_string());
+        let mut redirects = 0;
+        loop {
+            let (host, port, hostname) = &endpoint;
+            let result = self
+                .connect_endpoint(host, *port, hostname, opts.clone(), deadline)
+                .await;
+            match result {
+                Err(AmqpError::ConnectionClosed(Some(ref e))) if redirects < opts.max_redirects => {
+                    if let Some(redirect) = e.redirect() {
+                        trace!(
+                            "{}: redirected to {}:{}",
+                            self.container_id,
+                            redirect.network_host,
+                            redirect.port
+                        );
+                        redirects += 1;
+                        let hostname = redirect
+                            .hostname
+                            .clone()
+                            .unwrap_or_else(|| redirect.network_host.clone());
+                        endpoint = (redirect.network_host, redirect.port, hostname);
+                    } else {
+                        return result;
+                    }
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    async fn connect_endpoint(
+        &self,
+        host: &str,
+        port: u16,
+        hostname: &str,
+        opts: ConnectionOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Connection> {
+        let driver = self.open_connection(host, port, hostname, opts.clone())?;
+        let operation_timeout = opts.operation_timeout;
 
         let id = Token(self.token_generator.fetch_add(1, Ordering::SeqCst) as usize);
+        if let Some(policy) = opts.reconnect.clone() {
+            self.reconnects.lock().unwrap().insert(
+                id,
+                Reconnect {
+                    host: host.to_string(),
+                    port,
+                    opts,
+                    policy,
+                    attempt: 0,
+                    next_attempt: None,
+                    redirect: None,
+                },
+            );
+        }
         let conn = {
-            let conn = Arc::new(ConnectionDriver::new(driver));
+            let conn = Arc::new(
+                ConnectionDriver::new(driver, self.timers.clone())
+                    .with_operation_timeout(operation_timeout),
+            );
             let mut m = self.connections.lock().unwrap();
             m.insert(id, conn.clone());
             conn
@@ -229,58 +435,91 @@ impl ContainerInner {
         self.incoming.send(id)?;
         self.waker.wake()?;
 
-        loop {
-            let frame = conn.recv()?;
-            match frame.performative {
-                Some(Performative::Open(o)) => {
-                    trace!(
-                        "{}: received OPEN frame from {}:{}",
-                        self.container_id,
-                        host,
-                        port
-                    );
-                    // Populate remote properties
-                    return Ok(Connection {
-                        waker: self.waker.clone(),
-                        connection: conn,
-                        container_id: self.container_id.clone(),
-                        hostname: host.to_string(),
-                        channel_max: std::u16::MAX,
-                        idle_timeout: Duration::from_secs(5),
-
-                        remote_container_id: o.container_id.clone(),
-                        remote_channel_max: o.channel_max.unwrap_or(std::u16::MAX),
-                        remote_idle_timeout: Duration::from_millis(
-                            o.idle_timeout.unwrap_or(0) as u64
-                        ),
-                    });
-                }
-                Some(Performative::Close(c)) => {
-                    trace!(
-                        "{}: received CLOSE frame from {}:{}",
-                        self.container_id,
-                        host,
-                        port
-                    );
-                    match c.error {
-                        Some(e) => {
-                            return Err(AmqpError::Amqp(e));
-                        }
-                        None => {
-                            return Err(AmqpError::Generic("connection closed".to_string()));
-                        }
-                    }
-                }
-                _ => {
-                    // Push it back into the queue
-                    // TODO: Prevent reordering
-                    conn.unrecv(frame)?;
+        let frame = match conn.recv_matching(is_open_or_close, deadline).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                let _ = self.close_connection(id, &conn, None);
+                return Err(e);
+            }
+        };
+        match frame.performative {
+            Some(Performative::Open(o)) => {
+                trace!(
+                    "{}: received OPEN frame from {}:{}",
+                    self.container_id,
+                    host,
+                    port
+                );
+                if let Some(error) = conn.take_redirect() {
+                    let _ = self.close_connection(id, &conn, None);
+                    return Err(AmqpError::ConnectionClosed(Some(error)));
                 }
+
+                Ok(Connection::opened(
+                    self.waker.clone(),
+                    conn,
+                    &self.container_id,
+                    host.to_string(),
+                    o,
+                ))
+            }
+            Some(Performative::Close(c)) => {
+                trace!(
+                    "{}: received CLOSE frame from {}:{}",
+                    self.container_id,
+                    host,
+                    port
+                );
+                Err(AmqpError::ConnectionClosed(c.error))
             }
+            _ => Err(AmqpError::internal_error()),
         }
     }
 
+    fn listen(&self, host: &str, port: u16, opts: ListenOptions) -> Result<Listener> {
+        let listener = conn::listen(host, port, opts)?;
+        let local_addr = listener.local_addr()?;
+        trace!("{}: listening on {}", self.container_id, local_addr);
+
+        let id = Token(self.token_generator.fetch_add(1, Ordering::SeqCst) as usize);
+        let inner = Arc::new(ListenerInner {
+            listener: Mutex::new(listener),
+            incoming: Channel::new(self.timers.clone()),
+            local_addr,
+        });
+        self.listeners.lock().unwrap().insert(id, inner.clone());
+        self.incoming_listeners.send(id)?;
+        self.waker.wake()?;
+
+        Ok(Listener {
+            listener: inner,
+            waker: self.waker.clone(),
+            container_id: self.container_id.clone(),
+        })
+    }
+
     fn process(&self) -> Result<()> {
+        self.reconnect_connections()?;
+
+        // Register new listeners
+        loop {
+            let result = self.incoming_listeners.try_recv();
+            match result {
+                Err(_) => break,
+                Ok(id) => {
+                    let m = self.listeners.lock().unwrap();
+                    if let Some(listener) = m.get(&id) {
+                        let mut l = listener.listener.lock().unwrap();
+                        self.poll.lock().unwrap().registry().register(
+                            &mut *l,
+                            id,
+                            Interest::READABLE,
+                        )?;
+                    }
+                }
+            }
+        }
+
         // Register new connections
         loop {
             let result = self.incoming.try_recv();
@@ -298,7 +537,11 @@ impl ContainerInner {
         }
 
         // Push connection frames on the wire
+        let waiting = self.waiting_reconnects();
         for (id, connection) in self.connections.lock().unwrap().iter_mut() {
+            if waiting.contains(id) {
+                continue;
+            }
             let mut driver = connection.driver();
 
             // Handle keepalive
@@ -316,13 +559,21 @@ impl ContainerInner {
         // Poll for new events
         let mut events = Events::with_capacity(1024);
         {
-            self.poll
-                .lock()
-                .unwrap()
-                .poll(&mut events, Some(Duration::from_millis(2000)))?;
+            let mut timeout = Duration::from_millis(2000);
+            let now = Instant::now();
+            for reconnect in self.reconnects.lock().unwrap().values() {
+                if let Some(next_attempt) = reconnect.next_attempt {
+                    timeout = std::cmp::min(timeout, next_attempt.saturating_duration_since(now));
+                }
+            }
+            if let Some(deadline) = self.timers.next_deadline() {
+                timeout = std::cmp::min(timeout, deadline.saturating_duration_since(now));
+            }
+            self.poll.lock().unwrap().poll(&mut events, Some(timeout))?;
         }
+        self.timers.expire();
 
-        let waker_token = Token(std::u32::MAX as usize);
+        let waker_token = Token(u32::MAX as usize);
         for event in &events {
             let id = event.token();
             if id == waker_token {
@@ -330,6 +581,8 @@ impl ContainerInner {
                 for id in ids.iter() {
                     self.process_connection(*id)?;
                 }
+            } else if self.listeners.lock().unwrap().contains_key(&id) {
+                self.accept_connections(id)?;
             } else {
                 self.process_connection(id)?;
             }
@@ -337,19 +590,45 @@ impl ContainerInner {
         Ok(())
     }
 
+    fn accept_connections(&self, id: Token) -> Result<()> {
+        let listener = self.listeners.lock().unwrap().get(&id).cloned();
+        if let Some(listener) = listener {
+            loop {
+                let result = listener.listener.lock().unwrap().accept();
+                match result {
+                    Ok(driver) => {
+                        let id =
+                            Token(self.token_generator.fetch_add(1, Ordering::SeqCst) as usize);
+                        trace!("{}: accepted connection {:?}", self.container_id, id);
+                        let conn = Arc::new(ConnectionDriver::new(driver, self.timers.clone()));
+                        conn.register(id, &mut self.poll.lock().unwrap())?;
+                        self.connections.lock().unwrap().insert(id, conn.clone());
+                        listener.incoming.send(conn)?;
+                    }
+                    Err(AmqpError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn process_connection(&self, id: Token) -> Result<()> {
         let connection = {
             let mut m = self.connections.lock().unwrap();
             m.get_mut(&id).cloned()
         };
         if let Some(c) = connection {
+            if self.waiting_reconnects().contains(&id) {
+                return Ok(());
+            }
             let result = c.process();
             match result {
-                Err(AmqpError::Amqp(condition)) => {
-                    self.close_connection(id, &c, Some(condition))?;
-                }
-                Err(_) => {
-                    self.close_connection(id, &c, None)?;
+                Err(_) if self.schedule_reconnect(id, &c, true)? => {}
+                Err(e) => {
+                    self.close_connection(id, &c, e.error_condition())?;
                 }
                 _ => {}
             }
@@ -357,6 +636,122 @@ impl ContainerInner {
         Ok(())
     }
 
+    // Connections that are waiting for their next reconnect attempt.
+    fn waiting_reconnects(&self) -> Vec<Token> {
+        self.reconnects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, r)| r.next_attempt.is_some())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // Schedule the next reconnect attempt for a connection that failed. Returns false if the
+    // connection has no reconnect policy or the policy gives up.
+    fn schedule_reconnect(
+        &self,
+        id: Token,
+        connection: &ConnectionDriver,
+        lost: bool,
+    ) -> Result<bool> {
+        // Connections that were never established or were closed locally are not reconnected
+        if connection.is_closed() || !connection.is_opened() {
+            return Ok(false);
+        }
+        let mut reconnects = self.reconnects.lock().unwrap();
+        let reconnect = match reconnects.get_mut(&id) {
+            Some(reconnect) => reconnect,
+            None => return Ok(false),
+        };
+        if lost && !connection.is_resuming() {
+            // The connection was established since the previous attempt
+            reconnect.attempt = 0;
+            connection.event(ConnectionEvent::Disconnected)?;
+        }
+        if let Some(max_attempts) = reconnect.policy.max_attempts {
+            if reconnect.attempt >= max_attempts {
+                reconnects.remove(&id);
+                connection.event(ConnectionEvent::ReconnectFailed)?;
+                return Ok(false);
+            }
+        }
+        let redirect = connection.take_redirect().and_then(|e| e.redirect());
+        let delay = match redirect {
+            Some(redirect) => {
+                reconnect.redirect = Some((redirect.network_host, redirect.port));
+                Duration::from_millis(0)
+            }
+            None => reconnect.policy.delay(reconnect.attempt + 1),
+        };
+        trace!(
+            "{}: reconnecting {:?} in {:?}",
+            self.container_id,
+            id,
+            delay
+        );
+        reconnect.next_attempt = Some(Instant::now() + delay);
+        Ok(true)
+    }
+
+    // Make reconnect attempts that are due, replacing the connection of the driver.
+    fn reconnect_connections(&self) -> Result<()> {
+        let now = Instant::now();
+        let due: Vec<Token> = self
+            .reconnects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, r)| matches!(r.next_attempt, Some(t) if t <= now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in due {
+            let connection = self.connections.lock().unwrap().get(&id).cloned();
+            let connection = match connection {
+                Some(connection) => connection,
+                None => {
+                    self.reconnects.lock().unwrap().remove(&id);
+                    continue;
+                }
+            };
+            let (host, port, opts, attempt) = {
+                let mut reconnects = self.reconnects.lock().unwrap();
+                let reconnect = reconnects.get_mut(&id).unwrap();
+                reconnect.attempt += 1;
+                reconnect.next_attempt = None;
+                let (host, port) = match reconnect.redirect.take() {
+                    Some(redirect) => redirect,
+                    None => reconnect.endpoint(reconnect.attempt),
+                };
+                (host, port, reconnect.opts.clone(), reconnect.attempt)
+            };
+            connection.event(ConnectionEvent::Reconnecting {
+                host: host.clone(),
+                port,
+                attempt,
+            })?;
+            match self.open_connection(&host, port, &host, opts) {
+                Ok(driver) => {
+                    connection.reset(driver, &host, port);
+                    connection.register(id, &mut self.poll.lock().unwrap())?;
+                }
+                Err(e) => {
+                    trace!(
+                        "{}: error reconnecting to {}:{}: {:?}",
+                        self.container_id,
+                        host,
+                        port,
+                        e
+                    );
+                    if !self.schedule_reconnect(id, &connection, false)? {
+                        self.close_connection(id, &connection, None)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn close_connection(
         &self,
         id: Token,
@@ -372,16 +767,20 @@ impl ContainerInner {
         let result = connection.close(condition.clone());
         match result {
             // Socket error is ignored
-            Err(AmqpError::IoError(_)) => {}
+            Err(AmqpError::Io(_)) => {}
             Err(e) => return Err(e),
             _ => {}
         }
         connection.unrecv(AmqpFrame {
             channel: 0,
-            performative: Some(Performative::Close(Close { error: condition })),
+            performative: Some(Performative::Close(Close {
+                error: condition.clone(),
+            })),
             payload: None,
         })?;
+        connection.close_channels(condition);
         self.connections.lock().unwrap().remove(&id);
+        self.reconnects.lock().unwrap().remove(&id);
         Ok(())
     }
 }
@@ -392,33 +791,154 @@ impl Drop for ContainerInner {
     }
 }
 
+impl Listener {
+    /// Accept an incoming connection. The connection is returned once the remote endpoint
+    /// have sent the open performative, which is responded to with an open performative.
+    pub async fn accept(&self) -> Result<Connection> {
+        let conn = self.listener.incoming.recv().await?;
+        let frame = conn.recv_matching(is_open_or_close, None).await?;
+        match frame.performative {
+            Some(Performative::Open(o)) => {
+                trace!(
+                    "{}: received OPEN frame from {}",
+                    self.container_id,
+                    o.container_id
+                );
+                conn.driver().open(local_open(&self.container_id, None))?;
+                self.waker.wake()?;
+
+                let hostname = o.hostname.clone().unwrap_or_default();
+                Ok(Connection::opened(
+                    self.waker.clone(),
+                    conn,
+                    &self.container_id,
+                    hostname,
+                    o,
+                ))
+            }
+            Some(Performative::Close(c)) => Err(AmqpError::ConnectionClosed(c.error)),
+            _ => Err(AmqpError::internal_error()),
+        }
+    }
+
+    /// The local address this listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr
+    }
+}
+
 impl Connection {
+    // Connection with the properties of the remote endpoint, once its open is received.
+    fn opened(
+        waker: Arc<Waker>,
+        connection: Arc<ConnectionDriver>,
+        container_id: &str,
+        hostname: String,
+        remote: Open,
+    ) -> Connection {
+        Connection {
+            waker,
+            connection,
+            container_id: container_id.to_string(),
+            hostname,
+            channel_max: u16::MAX,
+            idle_timeout: Duration::from_secs(5),
+
+            remote_container_id: remote.container_id,
+            remote_channel_max: remote.channel_max.unwrap_or(u16::MAX),
+            remote_max_frame_size: remote.max_frame_size.unwrap_or(u32::MAX),
+            remote_idle_timeout: Duration::from_millis(remote.idle_timeout.unwrap_or(0) as u64),
+        }
+    }
+
+    /// Accept a session initiated by the remote endpoint. Returns the session once
+    /// the begin performative have been sent in response.
+    pub async fn accept_session(&self) -> Result<Session> {
+        let frame = self.connection.recv_incoming().await?;
+        match frame.performative {
+            Some(Performative::Begin(ref begin)) => {
+                let s = self.connection.accept_session(frame.channel, begin)?;
+                self.waker.wake()?;
+                Ok(Session {
+                    waker: self.waker.clone(),
+                    connection: self.connection.clone(),
+                    session: s,
+                })
+            }
+            _ => Err(AmqpError::internal_error()),
+        }
+    }
+
     /// Create a new session over this connection. Returns a session once the other
     /// endpoint have confirmed the creation.
     pub async fn new_session(&self, opts: Option<SessionOpts>) -> Result<Session> {
+        self.new_session_until(opts, self.connection.deadline())
+            .await
+    }
+
+    /// Create a new session, failing with a timeout error if the other endpoint does not
+    /// confirm the creation within the given time.
+    pub async fn new_session_timeout(
+        &self,
+        opts: Option<SessionOpts>,
+        timeout: Duration,
+    ) -> Result<Session> {
+        self.new_session_until(opts, Some(Instant::now() + timeout))
+            .await
+    }
+
+    async fn new_session_until(
+        &self,
+        opts: Option<SessionOpts>,
+        deadline: Option<Instant>,
+    ) -> Result<Session> {
         let s = self.connection.new_session(opts).await?;
 
         self.waker.wake()?;
-        loop {
-            let frame = s.recv()?;
-            match frame.performative {
-                Some(Performative::Begin(_b)) => {
-                    // Populate remote properties
-                    return Ok(Session {
-                        waker: self.waker.clone(),
-                        connection: self.connection.clone(),
-                        session: s,
-                    });
-                }
-                _ => {
-                    // Push it back into the queue
-                    // TODO: Prevent reordering
-                    s.unrecv(frame)?;
-                }
+        let result = s
+            .recv_matching(
+                |f| {
+                    matches!(
+                        f.performative,
+                        Some(Performative::Begin(_)) | Some(Performative::End(_))
+                    )
+                },
+                deadline,
+            )
+            .await;
+        // A session that is not begun in time is ended, so that a late begin is ignored
+        let frame = match result {
+            Err(AmqpError::Timeout) => {
+                self.connection.abandon_session(&s)?;
+                self.waker.wake()?;
+                return Err(AmqpError::Timeout);
+            }
+            result => result?,
+        };
+        match frame.performative {
+            Some(Performative::Begin(_b)) => {
+                // Populate remote properties
+                Ok(Session {
+                    waker: self.waker.clone(),
+                    connection: self.connection.clone(),
+                    session: s,
+                })
             }
+            Some(Performative::End(e)) => Err(AmqpError::SessionEnded(e.error)),
+            _ => Err(AmqpError::internal_error()),
         }
     }
 
+    /// Wait for the next event on this connection, such as a reconnect.
+    pub async fn next_event(&self) -> Result<ConnectionEvent> {
+        self.connection.recv_event().await
+    }
+
+    /// Retrieve the next event on this connection, if any.
+    pub fn try_next_event(&self) -> Option<ConnectionEvent> {
+        self.connection.try_recv_event().ok()
+    }
+
     /// Close a connection, ending the close performative.
     pub fn close(&self, error: Option<ErrorCondition>) -> Result<()> {
         self.connection.close(error)?;
@@ -443,56 +963,307 @@ impl Session {
     /// Create a new sender link for a given address cross this session. The sender
     /// is returned when the other side have confirmed its existence.
     pub async fn new_sender(&self, addr: &str) -> Result<Sender> {
-        let link = self.session.new_link(addr, LinkRole::Sender)?;
+        self.new_sender_until(addr, &LinkOptions::new(), self.connection.deadline())
+            .await
+    }
+
+    /// Create a new sender link with the given link options, such as the settle modes.
+    pub async fn new_sender_with(&self, addr: &str, opts: LinkOptions) -> Result<Sender> {
+        self.new_sender_until(addr, &opts, self.connection.deadline())
+            .await
+    }
+
+    /// Create a new sender link, failing with a timeout error if the other side does not
+    /// confirm its existence within the given time.
+    pub async fn new_sender_timeout(&self, addr: &str, timeout: Duration) -> Result<Sender> {
+        self.new_sender_until(addr, &LinkOptions::new(), Some(Instant::now() + timeout))
+            .await
+    }
+
+    async fn new_sender_until(
+        &self,
+        addr: &str,
+        opts: &LinkOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Sender> {
+        let link = self.session.new_link(addr, LinkRole::Sender, opts)?;
         trace!("Created link, waiting for attach frame");
-        self.waker.wake()?;
-        loop {
-            let frame = self.session.recv()?;
-            match frame.performative {
-                Some(Performative::Attach(_a)) => {
-                    // Populate remote properties
-                    return Ok(Sender {
-                        waker: self.waker.clone(),
-                        handle: link.handle,
-                        connection: self.connection.clone(),
-                        link,
-                        next_message_id: AtomicU64::new(0),
-                    });
-                }
-                _ => {
-                    // Push it back into the queue
-                    // TODO: Prevent reordering
-                    self.session.unrecv(frame)?;
-                }
+        let address = if opts.dynamic == Some(true) {
+            None
+        } else {
+            Some(addr.to_string())
+        };
+        self.attach_sender(link, address, deadline).await
+    }
+
+    /// Create a new sender link to a temporary node created by the other side. The address of
+    /// the node is assigned by the other side, and is available from the returned sender.
+    pub async fn new_dynamic_sender(&self) -> Result<Sender> {
+        let opts = LinkOptions::new()
+            .name(&format!("dynamic-{}", Uuid::new_v4()))
+            .dynamic(true);
+        self.new_sender_until("", &opts, self.connection.deadline())
+            .await
+    }
+
+    /// Declare a new transaction at the transaction coordinator of the remote endpoint. A
+    /// controller link is attached to the coordinator for the lifetime of the transaction.
+    pub async fn transaction(&self) -> Result<Transaction> {
+        let deadline = self.connection.deadline();
+        let name = format!("txn-controller-{}", Uuid::new_v4());
+        let link = self.session.new_coordinator(&name)?;
+        let controller = self.attach_sender(link, None, deadline).await?;
+
+        let declare = Declare { global_id: None };
+        let disposition = controller
+            .send(Message::amqp_value(declare.to_value()?))
+            .await?;
+        match disposition.state {
+            Some(DeliveryState::Declared(declared)) => Ok(Transaction {
+                id: declared.txn_id,
+                connection: self.connection.clone(),
+                controller,
+            }),
+            Some(DeliveryState::Rejected(rejected)) => {
+                Err(AmqpError::Amqp(rejected.error.unwrap_or_else(|| {
+                    ErrorCondition::new(Condition::NotImplemented, "transaction not declared")
+                })))
             }
+            _ => Err(AmqpError::generic("unexpected declare outcome")),
         }
     }
 
+    // Wait for the remote attach of a sender link initiated by this session. Without an address,
+    // the sender uses the address assigned by the remote endpoint.
+    async fn attach_sender(
+        &self,
+        link: Arc<LinkDriver>,
+        address: Option<String>,
+        deadline: Option<Instant>,
+    ) -> Result<Sender> {
+        self.waker.wake()?;
+        let a = self.remote_attach(&link, deadline).await?;
+        // The remote refuses the link by attaching without a target
+        let target = match a.target {
+            Some(target) => target,
+            None => return Err(link_detached(&link, deadline).await?),
+        };
+        // Populate remote properties
+        Ok(Sender {
+            address: address.or_else(|| target.address().map(|a| a.to_string())),
+            waker: self.waker.clone(),
+            handle: link.handle,
+            connection: self.connection.clone(),
+            link,
+            next_message_id: AtomicU64::new(0),
+        })
+    }
+
     /// Create a new receiving link for a given address cross this session. The
     /// is returned when the other side have confirmed its existence.
     pub async fn new_receiver(&self, addr: &str) -> Result<Receiver> {
-        let link = self.session.new_link(addr, LinkRole::Receiver)?;
+        self.new_receiver_until(addr, &LinkOptions::new(), self.connection.deadline())
+            .await
+    }
+
+    /// Create a new receiving link with the given link options, such as the settle modes.
+    pub async fn new_receiver_with(&self, addr: &str, opts: LinkOptions) -> Result<Receiver> {
+        self.new_receiver_until(addr, &opts, self.connection.deadline())
+            .await
+    }
+
+    /// Create a new receiving link, failing with a timeout error if the other side does not
+    /// confirm its existence within the given time.
+    pub async fn new_receiver_timeout(&self, addr: &str, timeout: Duration) -> Result<Receiver> {
+        self.new_receiver_until(addr, &LinkOptions::new(), Some(Instant::now() + timeout))
+            .await
+    }
+
+    /// Create a client for requests to the service at the given address. Replies are received
+    /// from a temporary node created by the other side.
+    pub async fn new_request_response_client(&self, addr: &str) -> Result<RequestResponseClient> {
+        let deadline = self.connection.deadline();
+        let sender = self
+            .new_sender_until(addr, &LinkOptions::new(), deadline)
+            .await?;
+        let opts = LinkOptions::new()
+            .name(&format!("dynamic-{}", Uuid::new_v4()))
+            .dynamic(true);
+        let receiver = self.new_receiver_until("", &opts, deadline).await?;
+        let reply_to = receiver
+            .address()
+            .map(|a| a.to_string())
+            .ok_or_else(|| AmqpError::generic("no reply-to address assigned"))?;
+        Ok(RequestResponseClient {
+            sender,
+            receiver,
+            reply_to,
+            next_correlation_id: AtomicU64::new(0),
+            waiting: Mutex::new(BTreeMap::new()),
+            receiving: AtomicBool::new(false),
+            replied: Signal::new(self.connection.timers()),
+        })
+    }
+
+    /// Create a new receiving link from a temporary node created by the other side, such as a
+    /// queue for replies. The address of the node is assigned by the other side, and is
+    /// available from the returned receiver.
+    pub async fn new_dynamic_receiver(&self) -> Result<Receiver> {
+        let opts = LinkOptions::new()
+            .name(&format!("dynamic-{}", Uuid::new_v4()))
+            .dynamic(true);
+        self.new_receiver_until("", &opts, self.connection.deadline())
+            .await
+    }
+
+    async fn new_receiver_until(
+        &self,
+        addr: &str,
+        opts: &LinkOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Receiver> {
+        let link = self.session.new_link(addr, LinkRole::Receiver, opts)?;
         trace!("Created link, waiting for attach frame");
+        let dynamic = opts.dynamic == Some(true);
         self.waker.wake()?;
-        loop {
-            let frame = self.session.recv()?;
-            match frame.performative {
-                Some(Performative::Attach(_a)) => {
-                    // Populate remote properties
-                    return Ok(Receiver {
-                        waker: self.waker.clone(),
-                        handle: link.handle,
-                        connection: self.connection.clone(),
-                        link,
-                        next_message_id: AtomicU64::new(0),
-                    });
+        let a = self.remote_attach(&link, deadline).await?;
+        // The remote refuses the link by attaching without a source
+        let source = match a.source {
+            Some(source) => source,
+            None => return Err(link_detached(&link, deadline).await?),
+        };
+        // Populate remote properties
+        Ok(Receiver {
+            address: if dynamic {
+                source.address
+            } else {
+                Some(addr.to_string())
+            },
+            waker: self.waker.clone(),
+            handle: link.handle,
+            connection: self.connection.clone(),
+            link,
+            next_message_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Resume a sender link from a previous session, typically after reconnecting. The link is
+    /// attached with the same name, and deliveries that were not settled on the previous link
+    /// are settled, resumed or sent again depending on the state known by the remote receiver.
+    pub async fn resume_sender(&self, previous: &Sender) -> Result<Sender> {
+        let link = self.session.resume_link(&previous.link)?;
+        self.waker.wake()?;
+        let remote = self
+            .remote_attach(&link, self.connection.deadline())
+            .await?;
+        link.recover(&previous.link, &remote)?;
+        self.waker.wake()?;
+        link.recovered(self.connection.deadline()).await?;
+        self.waker.wake()?;
+        Ok(Sender {
+            address: previous.address.clone(),
+            waker: self.waker.clone(),
+            handle: link.handle,
+            connection: self.connection.clone(),
+            link,
+            next_message_id: AtomicU64::new(previous.next_message_id.load(Ordering::SeqCst)),
+        })
+    }
+
+    /// Resume a receiver link from a previous session, typically after reconnecting. The link is
+    /// attached with the same name, and resumed deliveries that were already processed are
+    /// settled rather than received again.
+    pub async fn resume_receiver(&self, previous: &Receiver) -> Result<Receiver> {
+        let link = self.session.resume_link(&previous.link)?;
+        self.waker.wake()?;
+        self.remote_attach(&link, self.connection.deadline())
+            .await?;
+        Ok(Receiver {
+            address: previous.address.clone(),
+            waker: self.waker.clone(),
+            handle: link.handle,
+            connection: self.connection.clone(),
+            link,
+            next_message_id: AtomicU64::new(previous.next_message_id.load(Ordering::SeqCst)),
+        })
+    }
+
+    // Wait for the remote attach of a link initiated by this session. A link that is not attached
+    // in time is detached, so that a late attach is ignored.
+    async fn remote_attach(&self, link: &LinkDriver, deadline: Option<Instant>) -> Result<Attach> {
+        let result = self
+            .session
+            .recv_matching(|f| is_attach_or_end(f, &link.name), deadline)
+            .await;
+        let frame = match result {
+            Err(AmqpError::Timeout) => {
+                self.session.abandon_link(link)?;
+                self.waker.wake()?;
+                return Err(AmqpError::Timeout);
+            }
+            result => result?,
+        };
+        match frame.performative {
+            Some(Performative::Attach(attach)) => Ok(attach),
+            Some(Performative::End(e)) => Err(AmqpError::SessionEnded(e.error)),
+            _ => Err(AmqpError::internal_error()),
+        }
+    }
+
+    /// Accept a sender link initiated by a remote receiver on this session. The attach
+    /// performative is sent in response, mirroring the remote source and target.
+    pub async fn accept_sender(&self) -> Result<Sender> {
+        let frame = self.session.recv_incoming(LinkRole::Receiver).await?;
+        match frame.performative {
+            Some(Performative::Attach(mut attach)) => {
+                // Assign the address of the temporary node requested by a dynamic source
+                if let Some(source) = attach.source.as_mut() {
+                    if source.dynamic == Some(true) && source.address.is_none() {
+                        source.address = Some(Uuid::new_v4().to_string());
+                    }
                 }
-                _ => {
-                    // Push it back into the queue
-                    // TODO: Prevent reordering
-                    self.session.unrecv(frame)?;
+                let link = self.session.accept_link(&attach)?;
+                self.waker.wake()?;
+                Ok(Sender {
+                    address: attach.source.as_ref().and_then(|s| s.address.clone()),
+                    waker: self.waker.clone(),
+                    handle: link.handle,
+                    connection: self.connection.clone(),
+                    link,
+                    next_message_id: AtomicU64::new(0),
+                })
+            }
+            _ => Err(AmqpError::internal_error()),
+        }
+    }
+
+    /// Accept a receiver link initiated by a remote sender on this session. The attach
+    /// performative is sent in response, mirroring the remote source and target.
+    pub async fn accept_receiver(&self) -> Result<Receiver> {
+        let frame = self.session.recv_incoming(LinkRole::Sender).await?;
+        match frame.performative {
+            Some(Performative::Attach(mut attach)) => {
+                // Assign the address of the temporary node requested by a dynamic target
+                if let Some(LinkTarget::Target(target)) = attach.target.as_mut() {
+                    if target.dynamic == Some(true) && target.address.is_none() {
+                        target.address = Some(Uuid::new_v4().to_string());
+                    }
                 }
+                let link = self.session.accept_link(&attach)?;
+                self.waker.wake()?;
+                Ok(Receiver {
+                    address: attach
+                        .target
+                        .as_ref()
+                        .and_then(|t| t.address().map(|a| a.to_string())),
+                    waker: self.waker.clone(),
+                    handle: link.handle,
+                    connection: self.connection.clone(),
+                    link,
+                    next_message_id: AtomicU64::new(0),
+                })
             }
+            _ => Err(AmqpError::internal_error()),
         }
     }
 
@@ -505,8 +1276,62 @@ impl Session {
 }
 
 impl Sender {
+    /// The address of the node this sender is attached to.
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+
     /// Send a message across this link. The returned disposition signals the acceptance or rejection of the message on the receiving end.
-    pub async fn send(&self, mut message: Message) -> Result<Disposition> {
+    pub async fn send(&self, message: Message) -> Result<Disposition> {
+        let deadline = self.connection.deadline();
+        self.send_pending_until(message, self.presettled(), None, deadline)
+            .await?
+            .disposition_until(deadline)
+            .await
+    }
+
+    /// Send a message, failing with a timeout error if there is no credit to send it or it
+    /// is not settled within the given time.
+    pub async fn send_timeout(&self, message: Message, timeout: Duration) -> Result<Disposition> {
+        let deadline = Some(Instant::now() + timeout);
+        self.send_pending_until(message, self.presettled(), None, deadline)
+            .await?
+            .disposition_until(deadline)
+            .await
+    }
+
+    /// Queue a message for sending across this link. The returned pending delivery can be used to
+    /// abort the delivery before it has been completely sent, or to wait for its disposition.
+    pub async fn send_pending(&self, message: Message) -> Result<PendingDelivery> {
+        self.send_pending_until(message, self.presettled(), None, self.connection.deadline())
+            .await
+    }
+
+    /// Send a message settled, without waiting for the receiver to process it. Fails if the
+    /// negotiated sender settle mode only allows unsettled deliveries.
+    pub async fn send_settled(&self, message: Message) -> Result<()> {
+        if self.link.settle_modes().0 == SenderSettleMode::Unsettled {
+            return Err(AmqpError::generic(
+                "sender settle mode does not allow settled deliveries",
+            ));
+        }
+        self.send_pending_until(message, true, None, self.connection.deadline())
+            .await?;
+        Ok(())
+    }
+
+    // Deliveries are sent settled if the negotiated sender settle mode requires it.
+    fn presettled(&self) -> bool {
+        self.link.settle_modes().0 == SenderSettleMode::Settled
+    }
+
+    async fn send_pending_until(
+        &self,
+        mut message: Message,
+        settled: bool,
+        state: Option<DeliveryState>,
+        deadline: Option<Instant>,
+    ) -> Result<PendingDelivery> {
         let message_id = Some(Value::Ulong(
             self.next_message_id.fetch_add(1, Ordering::SeqCst),
         ));
@@ -533,40 +1358,143 @@ impl Sender {
                 Some(p)
             },
         );
-        let settled = false;
-        let delivery = self.link.send_message(message, settled).await?;
+        let delivery = self
+            .link
+            .send_message(message, settled, state, deadline)
+            .await?;
         self.waker.wake()?;
+        Ok(PendingDelivery {
+            waker: self.waker.clone(),
+            connection: self.connection.clone(),
+            link: self.link.clone(),
+            delivery,
+            aborted: AtomicBool::new(false),
+        })
+    }
 
-        if !settled {
-            loop {
-                let frame = self.link.recv()?;
-                match frame.performative {
+    /// Close the sender link, sending the detach performative.
+    pub fn close(&self, error: Option<ErrorCondition>) -> Result<()> {
+        self.link.close(error)?;
+        self.waker.wake()?;
+        Ok(())
+    }
+}
+
+impl PendingDelivery {
+    /// Abort the delivery if it has not been completely sent. Returns false if the delivery was
+    /// already sent.
+    pub fn abort(&self) -> Result<bool> {
+        let aborted = self.link.abort(&self.delivery.current())?;
+        if aborted {
+            self.aborted.store(true, Ordering::SeqCst);
+            self.waker.wake()?;
+        }
+        Ok(aborted)
+    }
+
+    /// Wait for the disposition of the delivery. Fails if the delivery was aborted.
+    pub async fn disposition(self) -> Result<Disposition> {
+        let deadline = self.connection.deadline();
+        self.disposition_until(deadline).await
+    }
+
+    /// Wait for the disposition of the delivery, failing with a timeout error if it is not
+    /// received within the given time.
+    pub async fn disposition_timeout(self, timeout: Duration) -> Result<Disposition> {
+        self.disposition_until(Some(Instant::now() + timeout)).await
+    }
+
+    async fn disposition_until(self, deadline: Option<Instant>) -> Result<Disposition> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(AmqpError::generic("delivery aborted"));
+        }
+        if self.delivery.settled {
+            return Ok(Disposition {
+                delivery: self.delivery,
+                state: None,
+            });
+        }
+        // The delivery may have been sent again after reconnecting
+        let id = self.delivery.current().id;
+        let frame = self
+            .link
+            .recv_matching(
+                |f| match f.performative {
                     Some(Performative::Disposition(ref disposition)) => {
                         let first = disposition.first;
                         let last = disposition.last.unwrap_or(first);
-                        if first <= delivery.id && last >= delivery.id {
-                            // TODO: Better error checking
-                            return Ok(Disposition { delivery });
-                        } else {
-                            self.link.unrecv(frame)?;
-                        }
+                        first <= id && last >= id
                     }
-                    _ => {
-                        // TODO: Prevent reordering
-                        self.link.unrecv(frame)?;
+                    Some(Performative::Detach(_)) => true,
+                    _ => false,
+                },
+                deadline,
+            )
+            .await?;
+        match frame.performative {
+            // TODO: Better error checking
+            Some(Performative::Disposition(disposition)) => {
+                let delivery = self.delivery.current();
+                // The receiver may wait for the sender to settle first, such as in receiver
+                // settle mode second
+                if disposition.settled != Some(true) {
+                    if let Some(state) = disposition.state.as_ref().filter(|s| s.is_terminal()) {
+                        self.link.disposition(&delivery, true, state.clone())?;
+                        self.waker.wake()?;
                     }
                 }
+                Ok(Disposition {
+                    delivery,
+                    state: disposition.state,
+                })
             }
-        } else {
-            Ok(Disposition { delivery })
+            Some(Performative::Detach(ref detach)) => {
+                let error = detach.error.clone();
+                // Kept for other operations waiting on the link
+                self.link.unrecv(frame)?;
+                Err(AmqpError::LinkDetached(error))
+            }
+            _ => Err(AmqpError::internal_error()),
         }
     }
+}
 
-    /// Close the sender link, sending the detach performative.
-    pub fn close(&self, error: Option<ErrorCondition>) -> Result<()> {
-        self.link.close(error)?;
-        self.waker.wake()?;
-        Ok(())
+// Wait for the detach of a link refused by the remote endpoint.
+async fn link_detached(link: &LinkDriver, deadline: Option<Instant>) -> Result<AmqpError> {
+    let frame = link
+        .recv_matching(
+            |f| matches!(f.performative, Some(Performative::Detach(_))),
+            deadline,
+        )
+        .await?;
+    match frame.performative {
+        Some(Performative::Detach(detach)) => Ok(AmqpError::LinkDetached(detach.error)),
+        _ => Err(AmqpError::internal_error()),
+    }
+}
+
+// Open performative sent by the container to the remote endpoint.
+fn local_open(container_id: &str, hostname: Option<&str>) -> Open {
+    let mut open = Open::new(container_id);
+    open.hostname = hostname.map(|h| h.to_string());
+    open.channel_max = Some(u16::MAX);
+    open.idle_timeout = Some(5000);
+    open
+}
+
+fn is_open_or_close(frame: &AmqpFrame) -> bool {
+    matches!(
+        frame.performative,
+        Some(Performative::Open(_)) | Some(Performative::Close(_))
+    )
+}
+
+// Whether the frame is the remote attach of the named link, or ends the session.
+fn is_attach_or_end(frame: &AmqpFrame, name: &str) -> bool {
+    match frame.performative {
+        Some(Performative::Attach(ref attach)) => attach.name == name,
+        Some(Performative::End(_)) => true,
+        _ => false,
     }
 }
 
@@ -577,6 +1505,11 @@ impl Drop for Sender {
 }
 
 impl Receiver {
+    /// The address of the node this receiver is attached to.
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+
     /// Issue credits to the remote sender link, signalling that the receiver canaldigital
     /// accept more messages.
     pub async fn flow(&self, credit: u32) -> Result<()> {
@@ -588,32 +1521,57 @@ impl Receiver {
     /// Receive a single message across the link. The delivery is returned
     /// when a message is received.
     pub async fn receive(&self) -> Result<Delivery> {
-        loop {
-            let frame = self.link.recv()?;
-            match frame.performative {
-                Some(Performative::Transfer(ref transfer)) => {
-                    let mut input = frame.payload.unwrap();
-                    let message = Message::decode(&mut input)?;
-                    let delivery = Arc::new(DeliveryDriver {
-                        state: transfer.state.clone(),
-                        tag: transfer.delivery_tag.clone().unwrap(),
-                        id: transfer.delivery_id.unwrap(),
-                        remotely_settled: transfer.settled.unwrap_or(false),
-                        settled: false,
-                        message,
-                    });
-                    return Ok(Delivery {
-                        waker: self.waker.clone(),
-                        settled: false,
-                        link: self.link.clone(),
-                        delivery,
-                    });
-                }
-                _ => {
-                    // TODO: Prevent reordering
-                    self.link.unrecv(frame)?;
-                }
+        self.receive_until(self.connection.deadline()).await
+    }
+
+    /// Receive a single message across the link, failing with a timeout error if no message
+    /// is received within the given time.
+    pub async fn receive_timeout(&self, timeout: Duration) -> Result<Delivery> {
+        self.receive_until(Some(Instant::now() + timeout)).await
+    }
+
+    async fn receive_until(&self, deadline: Option<Instant>) -> Result<Delivery> {
+        let frame = self
+            .link
+            .recv_matching(
+                |f| {
+                    matches!(
+                        f.performative,
+                        Some(Performative::Transfer(_)) | Some(Performative::Detach(_))
+                    )
+                },
+                deadline,
+            )
+            .await?;
+        match frame.performative {
+            Some(Performative::Transfer(ref transfer)) => {
+                let mut input = frame.payload.unwrap();
+                let message = Message::decode(&mut input)?;
+                let delivery = Arc::new(DeliveryDriver {
+                    state: transfer.state.clone(),
+                    tag: transfer.delivery_tag.clone().unwrap(),
+                    id: transfer.delivery_id.unwrap(),
+                    remotely_settled: transfer.settled.unwrap_or(false),
+                    settled: false,
+                    resumed: Mutex::new(None),
+                    message,
+                });
+                Ok(Delivery {
+                    waker: self.waker.clone(),
+                    connection: self.connection.clone(),
+                    settled: transfer.settled == Some(true),
+                    link: self.link.clone(),
+                    delivery,
+                    txn_id: None,
+                })
             }
+            Some(Performative::Detach(ref detach)) => {
+                let error = detach.error.clone();
+                // Kept for other operations waiting on the link
+                self.link.unrecv(frame)?;
+                Err(AmqpError::LinkDetached(error))
+            }
+            _ => Err(AmqpError::internal_error()),
         }
     }
 
@@ -637,25 +1595,1536 @@ impl Delivery {
         &self.delivery.message
     }
 
+    /// The delivery state sent by the remote sender, if any.
+    pub fn state(&self) -> Option<&DeliveryState> {
+        self.delivery.state.as_ref()
+    }
+
     /// Send a disposition for this delivery, indicating message settlement and delivery state.
+    /// In receiver settle mode second, settling waits for the sender to settle the delivery.
     pub async fn disposition(&mut self, settled: bool, state: DeliveryState) -> Result<()> {
         if !self.settled {
+            let state = self.transactional(state);
             self.link.disposition(&self.delivery, settled, state)?;
             self.settled = settled;
             self.waker.wake()?;
+            if settled && self.link.settle_modes().1 == ReceiverSettleMode::Second {
+                self.link
+                    .settlement(&self.delivery, self.connection.deadline())
+                    .await?;
+            }
         }
         Ok(())
     }
+
+    // Wrap the outcome in the state of the transaction the delivery is settled within.
+    fn transactional(&self, state: DeliveryState) -> DeliveryState {
+        match self.txn_id {
+            Some(ref txn_id) => DeliveryState::Transactional(TransactionalState {
+                txn_id: txn_id.clone(),
+                outcome: Some(Box::new(state)),
+            }),
+            None => state,
+        }
+    }
 }
 
 impl Drop for Delivery {
     fn drop(&mut self) {
         if !self.settled {
             self.settled = true;
-            let _ = self
-                .link
-                .disposition(&self.delivery, true, DeliveryState::Accepted);
+            let state = self.transactional(DeliveryState::Accepted);
+            let _ = self.link.disposition(&self.delivery, true, state);
             let _ = self.waker.wake();
         }
     }
 }
+
+impl Disposition {
+    /// The delivery state sent by the remote receiver, if the delivery was not sent settled.
+    pub fn state(&self) -> Option<&DeliveryState> {
+        self.state.as_ref()
+    }
+}
+
+impl Transaction {
+    /// The identifier assigned to the transaction by the coordinator.
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Send a message across the sender link as part of this transaction. The message is not
+    /// delivered to the receiving node until the transaction is committed.
+    pub async fn send(&self, sender: &Sender, message: Message) -> Result<Disposition> {
+        let deadline = self.connection.deadline();
+        let state = DeliveryState::Transactional(TransactionalState {
+            txn_id: self.id.clone(),
+            outcome: None,
+        });
+        sender
+            .send_pending_until(message, sender.presettled(), Some(state), deadline)
+            .await?
+            .disposition_until(deadline)
+            .await
+    }
+
+    /// Receive a message across the receiver link. The message is not received as part of this
+    /// transaction; only the settlement of the delivery is, so its outcome takes effect when the
+    /// transaction is committed.
+    pub async fn receive(&self, receiver: &Receiver) -> Result<Delivery> {
+        let mut delivery = receiver.receive().await?;
+        delivery.txn_id = Some(self.id.clone());
+        Ok(delivery)
+    }
+
+    /// Send a disposition for a delivery as part of this transaction.
+    pub async fn disposition(
+        &self,
+        delivery: &mut Delivery,
+        settled: bool,
+        state: DeliveryState,
+    ) -> Result<()> {
+        delivery.txn_id = Some(self.id.clone());
+        delivery.disposition(settled, state).await
+    }
+
+    /// Commit the transaction, making its work take effect.
+    pub async fn commit(self) -> Result<()> {
+        self.discharge(false).await
+    }
+
+    /// Roll back the transaction, discarding its work.
+    pub async fn rollback(self) -> Result<()> {
+        self.discharge(true).await
+    }
+
+    async fn discharge(self, fail: bool) -> Result<()> {
+        let discharge = Discharge {
+            txn_id: self.id.clone(),
+            fail: Some(fail),
+        };
+        let disposition = self
+            .controller
+            .send(Message::amqp_value(discharge.to_value()?))
+            .await?;
+        match disposition.state {
+            Some(DeliveryState::Accepted) => Ok(()),
+            Some(DeliveryState::Rejected(rejected)) => {
+                Err(AmqpError::Amqp(rejected.error.unwrap_or_else(|| {
+                    ErrorCondition::new(Condition::TransactionRollback, "transaction rolled back")
+                })))
+            }
+            _ => Err(AmqpError::generic("unexpected discharge outcome")),
+        }
+    }
+}
+
+impl RequestResponseClient {
+    /// The address replies are sent to.
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+
+    /// Send a request to the service, and wait for its reply. The reply-to address, message id
+    /// and correlation id of the request are set by the client, and the service is expected to
+    /// reply with the same id as correlation id.
+    pub async fn request(&self, message: Message) -> Result<Message> {
+        self.request_until(message, self.sender.connection.deadline())
+            .await
+    }
+
+    /// Send a request to the service, failing with a timeout error if the reply is not received
+    /// within the given time.
+    pub async fn request_timeout(&self, message: Message, timeout: Duration) -> Result<Message> {
+        self.request_until(message, Some(Instant::now() + timeout))
+            .await
+    }
+
+    async fn request_until(&self, message: Message, deadline: Option<Instant>) -> Result<Message> {
+        let id = Value::Ulong(self.next_correlation_id.fetch_add(1, Ordering::SeqCst));
+        // Registered before sending, so that a reply can not arrive before the request waits
+        self.waiting.lock().unwrap().insert(id.clone(), None);
+        let result = self.send_request(message, &id, deadline).await;
+        self.waiting.lock().unwrap().remove(&id);
+        result
+    }
+
+    async fn send_request(
+        &self,
+        mut message: Message,
+        id: &Value,
+        deadline: Option<Instant>,
+    ) -> Result<Message> {
+        match message.properties {
+            Some(ref mut p) => {
+                p.message_id = Some(id.clone());
+                p.reply_to = Some(self.reply_to.clone());
+                p.correlation_id = Some(id.clone());
+            }
+            None => {
+                message.properties = Some(MessageProperties {
+                    message_id: Some(id.clone()),
+                    user_id: None,
+                    to: None,
+                    subject: None,
+                    reply_to: Some(self.reply_to.clone()),
+                    correlation_id: Some(id.clone()),
+                    content_type: None,
+                    content_encoding: None,
+                    absolute_expiry_time: None,
+                    creation_time: None,
+                    group_id: None,
+                    group_sequence: None,
+                    reply_to_group_id: None,
+                })
+            }
+        }
+        let disposition = self
+            .sender
+            .send_pending_until(message, self.sender.presettled(), None, deadline)
+            .await?
+            .disposition_until(deadline)
+            .await?;
+        match disposition.state {
+            Some(DeliveryState::Rejected(rejected)) => {
+                return Err(AmqpError::Amqp(rejected.error.unwrap_or_else(|| {
+                    ErrorCondition::new(Condition::InternalError, "request rejected")
+                })))
+            }
+            Some(DeliveryState::Released) | Some(DeliveryState::Modified(_)) => {
+                return Err(AmqpError::generic("request not accepted"))
+            }
+            _ => {}
+        }
+        self.reply_until(id, deadline).await
+    }
+
+    // Wait for the reply to a request. One request at a time receives replies, passing them on
+    // to the requests waiting for them. Replies that no request is waiting for are settled and
+    // dropped.
+    async fn reply_until(&self, id: &Value, deadline: Option<Instant>) -> Result<Message> {
+        loop {
+            let reply = self
+                .replied
+                .wait(
+                    || {
+                        if let Some(reply) = self.waiting.lock().unwrap().get_mut(id) {
+                            if reply.is_some() {
+                                return Some(reply.take());
+                            }
+                        }
+                        if self.receiving.swap(true, Ordering::SeqCst) {
+                            None
+                        } else {
+                            Some(None)
+                        }
+                    },
+                    deadline,
+                )
+                .await?;
+            if let Some(reply) = reply {
+                return Ok(reply);
+            }
+
+            let _receiving = ReceivingReplies(self);
+            let delivery = self.receiver.receive_until(deadline).await?;
+            let correlation_id = delivery
+                .message()
+                .properties
+                .as_ref()
+                .and_then(|p| p.correlation_id.as_ref());
+            let mut waiting = self.waiting.lock().unwrap();
+            match correlation_id {
+                Some(c) if c == id => return Ok(delivery.message().clone()),
+                Some(c) if waiting.contains_key(c) => {
+                    waiting.insert(c.clone(), Some(delivery.message().clone()));
+                }
+                _ => trace!("Dropping reply without a waiting request"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::convert::TryFromValue;
+    use crate::framing::{Begin, End, Flow, Frame, Target, Transfer};
+    use crate::message::MessageBody;
+    use crate::symbol::Symbol;
+    use futures::executor::block_on;
+    use std::future::Future;
+
+    // Run the server body on a session accepted by a server container, and the client body on a
+    // session of a client container connected to it.
+    fn run_sessions<S, SF, C, CF>(server: S, client: C)
+    where
+        S: FnOnce(Session) -> SF + Send + 'static,
+        SF: Future<Output = ()>,
+        C: FnOnce(Session) -> CF,
+        CF: Future<Output = ()>,
+    {
+        run_sessions_with(
+            ListenOptions::new(),
+            "127.0.0.1",
+            ConnectionOptions::new(),
+            server,
+            client,
+        );
+    }
+
+    // Like run_sessions, with the given listener and connection options.
+    fn run_sessions_with<S, SF, C, CF>(
+        listen_opts: ListenOptions,
+        host: &str,
+        connect_opts: ConnectionOptions,
+        server: S,
+        client: C,
+    ) where
+        S: FnOnce(Session) -> SF + Send + 'static,
+        SF: Future<Output = ()>,
+        C: FnOnce(Session) -> CF,
+        CF: Future<Output = ()>,
+    {
+        let server_container = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server_container
+            .listen("127.0.0.1", 0, listen_opts)
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            block_on(async move {
+                let connection = listener.accept().await.expect("connection not accepted");
+                assert_eq!("client", connection.remote_container_id);
+                let session = connection
+                    .accept_session()
+                    .await
+                    .expect("session not accepted");
+                server(session).await;
+            });
+            server_container
+        });
+
+        let client_container = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async move {
+            let connection = client_container
+                .connect(host, port, connect_opts)
+                .await
+                .expect("connection not created");
+            assert_eq!("server", connection.remote_container_id);
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            client(session).await;
+        });
+        t.join().expect("server error");
+    }
+
+    // Send a message from a client container to a server container listening with the given options.
+    fn send_to_listener(
+        listen_opts: ListenOptions,
+        host: &str,
+        connect_opts: ConnectionOptions,
+        body: &str,
+    ) {
+        let expected = body.to_string();
+        run_sessions_with(
+            listen_opts,
+            host,
+            connect_opts,
+            |session| async move {
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                assert_eq!(Some("queue1"), receiver.address());
+                let delivery = receiver.receive().await.expect("unable to receive message");
+                if let MessageBody::AmqpValue(Value::String(ref s)) = delivery.message().body {
+                    assert_eq!(&expected, s);
+                } else {
+                    panic!("unexpected message body");
+                }
+            },
+            |session| async move {
+                let sender = session
+                    .new_sender("queue1")
+                    .await
+                    .expect("sender not created");
+                sender
+                    .send(Message::amqp_value(Value::String(body.to_string())))
+                    .await
+                    .expect("delivery not settled");
+            },
+        );
+    }
+
+    #[test]
+    fn accept_connection_session_and_links() {
+        send_to_listener(
+            ListenOptions::new(),
+            "127.0.0.1",
+            ConnectionOptions::new(),
+            "Hello, server",
+        );
+    }
+
+    #[test]
+    fn multi_frame_message() {
+        let body: String = (0..100_000)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        send_to_listener(
+            ListenOptions::new(),
+            "127.0.0.1",
+            ConnectionOptions::new(),
+            &body,
+        );
+    }
+
+    #[test]
+    fn resume_sender_after_reconnect() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let (received_tx, received_rx) = std::sync::mpsc::channel();
+        let t = thread::spawn(move || {
+            block_on(async {
+                // Deliveries are kept unsettled until both have been received
+                let mut deliveries = Vec::new();
+                for _ in 0..2 {
+                    let connection = listener.accept().await.expect("connection not accepted");
+                    let session = connection
+                        .accept_session()
+                        .await
+                        .expect("session not accepted");
+                    let receiver = session
+                        .accept_receiver()
+                        .await
+                        .expect("receiver not accepted");
+                    let delivery = receiver.receive().await.expect("unable to receive message");
+                    if let MessageBody::AmqpValue(Value::String(ref s)) = delivery.message().body {
+                        assert_eq!("unsettled", s);
+                    } else {
+                        panic!("unexpected message body");
+                    }
+                    deliveries.push(delivery);
+                    received_tx.send(()).unwrap();
+                }
+                // The delivery is sent again with the same tag
+                assert_eq!(deliveries[0].delivery.tag, deliveries[1].delivery.tag);
+            });
+            server
+        });
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let connection = client
+                .connect("127.0.0.1", port, ConnectionOptions::new())
+                .await
+                .expect("connection not created");
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            let sender = session
+                .new_sender("queue1")
+                .await
+                .expect("sender not created");
+            let _pending = sender
+                .send_pending(Message::amqp_value(Value::String("unsettled".to_string())))
+                .await
+                .expect("unable to send message");
+            received_rx.recv().unwrap();
+
+            let connection = client
+                .connect("127.0.0.1", port, ConnectionOptions::new())
+                .await
+                .expect("connection not created");
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            let resumed = session
+                .resume_sender(&sender)
+                .await
+                .expect("sender not resumed");
+            assert_eq!(Some("queue1"), resumed.address());
+            received_rx.recv().unwrap();
+        });
+        t.join().expect("server error");
+    }
+
+    #[test]
+    fn remote_detach_reported() {
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        run_sessions(
+            move |session| async move {
+                let sender = session.accept_sender().await.expect("sender not accepted");
+                sender
+                    .close(Some(ErrorCondition::new(
+                        Condition::DetachForced,
+                        "shutting down",
+                    )))
+                    .expect("unable to detach");
+                done_rx.recv().unwrap();
+            },
+            |session| async move {
+                let receiver = session
+                    .new_receiver("queue1")
+                    .await
+                    .expect("receiver not created");
+                match receiver.receive().await {
+                    Err(AmqpError::LinkDetached(Some(e))) => {
+                        assert_eq!(Condition::DetachForced, e.kind());
+                        assert_eq!("shutting down", e.description);
+                    }
+                    result => panic!("unexpected result {:?}", result.map(|_| ())),
+                }
+                done_tx.send(()).unwrap();
+            },
+        );
+    }
+
+    #[test]
+    fn connection_lost_while_receiving() {
+        let mut server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            block_on(async {
+                let connection = listener.accept().await.expect("connection not accepted");
+                let session = connection
+                    .accept_session()
+                    .await
+                    .expect("session not accepted");
+                let _sender = session.accept_sender().await.expect("sender not accepted");
+                // Give the client time to wait for a message
+                thread::sleep(Duration::from_millis(200));
+                server.close().expect("unable to close container");
+            });
+        });
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let connection = client
+                .connect("127.0.0.1", port, ConnectionOptions::new())
+                .await
+                .expect("connection not created");
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            let receiver = session
+                .new_receiver("queue1")
+                .await
+                .expect("receiver not created");
+            match receiver.receive().await {
+                Err(AmqpError::ConnectionClosed(_)) => {}
+                result => panic!("unexpected result {:?}", result.map(|_| ())),
+            }
+        });
+        t.join().expect("server error");
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // Accepts TCP connections but never replies
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").expect("unable to listen");
+        let port = silent.local_addr().unwrap().port();
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let opts = ConnectionOptions::new().connect_timeout(Duration::from_millis(200));
+            let result = client.connect("127.0.0.1", port, opts).await;
+            assert!(matches!(result, Err(AmqpError::Timeout)));
+
+            let result = client
+                .connect_timeout(
+                    "127.0.0.1",
+                    port,
+                    ConnectionOptions::new(),
+                    Duration::from_millis(200),
+                )
+                .await;
+            assert!(matches!(result, Err(AmqpError::Timeout)));
+        });
+    }
+
+    #[test]
+    fn operation_timeouts() {
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        run_sessions_with(
+            ListenOptions::new(),
+            "127.0.0.1",
+            ConnectionOptions::new().operation_timeout(Duration::from_millis(200)),
+            move |session| async move {
+                // No credit is issued and no messages are sent
+                let _receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                let _sender = session.accept_sender().await.expect("sender not accepted");
+                done_rx.recv().unwrap();
+            },
+            |session| async move {
+                let sender = session
+                    .new_sender("queue1")
+                    .await
+                    .expect("sender not created");
+                let receiver = session
+                    .new_receiver("queue2")
+                    .await
+                    .expect("receiver not created");
+
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                let result = sender
+                    .send_timeout(message, Duration::from_millis(100))
+                    .await;
+                assert!(matches!(result, Err(AmqpError::Timeout)));
+
+                let result = receiver.receive().await;
+                assert!(matches!(result, Err(AmqpError::Timeout)));
+                done_tx.send(()).unwrap();
+            },
+        );
+    }
+
+    #[test]
+    fn transaction_commit() {
+        run_sessions(
+            // The server acts as the transaction coordinator
+            |session| async move {
+                let coordinator = session
+                    .accept_receiver()
+                    .await
+                    .expect("coordinator not accepted");
+                let mut declare = coordinator.receive().await.expect("declare not received");
+                if let MessageBody::AmqpValue(ref value) = declare.message().body {
+                    Declare::try_from(value.clone()).expect("not a declare");
+                } else {
+                    panic!("unexpected message body");
+                }
+                let declared = Declared {
+                    txn_id: b"txn1".to_vec(),
+                };
+                declare
+                    .disposition(true, DeliveryState::Declared(declared))
+                    .await
+                    .unwrap();
+
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                let mut delivery = receiver.receive().await.expect("message not received");
+                let state = TransactionalState {
+                    txn_id: b"txn1".to_vec(),
+                    outcome: None,
+                };
+                assert_eq!(Some(&DeliveryState::Transactional(state)), delivery.state());
+                let state = TransactionalState {
+                    txn_id: b"txn1".to_vec(),
+                    outcome: Some(Box::new(DeliveryState::Accepted)),
+                };
+                delivery
+                    .disposition(true, DeliveryState::Transactional(state))
+                    .await
+                    .unwrap();
+
+                let mut discharge = coordinator.receive().await.expect("discharge not received");
+                if let MessageBody::AmqpValue(ref value) = discharge.message().body {
+                    let discharge = Discharge::try_from(value.clone()).expect("not a discharge");
+                    assert_eq!(b"txn1".to_vec(), discharge.txn_id);
+                    assert_eq!(Some(false), discharge.fail);
+                } else {
+                    panic!("unexpected message body");
+                }
+                discharge
+                    .disposition(true, DeliveryState::Accepted)
+                    .await
+                    .unwrap();
+            },
+            |session| async move {
+                let transaction = session.transaction().await.expect("not declared");
+                assert_eq!(b"txn1", transaction.id());
+
+                let sender = session
+                    .new_sender("queue1")
+                    .await
+                    .expect("sender not created");
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                let disposition = transaction
+                    .send(&sender, message)
+                    .await
+                    .expect("delivery not settled");
+                match disposition.state() {
+                    Some(DeliveryState::Transactional(state)) => {
+                        assert_eq!(Some(&DeliveryState::Accepted), state.outcome.as_deref())
+                    }
+                    state => panic!("unexpected state {:?}", state),
+                }
+                transaction.commit().await.expect("not committed");
+            },
+        );
+    }
+
+    #[test]
+    fn presettled_sender() {
+        run_sessions(
+            |session| async move {
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                for _ in 0..2 {
+                    let mut delivery = receiver.receive().await.expect("message not received");
+                    assert!(delivery.settled);
+                    // Nothing is sent for a delivery settled by the sender
+                    delivery
+                        .disposition(true, DeliveryState::Accepted)
+                        .await
+                        .unwrap();
+                }
+                let _receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+            },
+            |session| async move {
+                let opts = LinkOptions::new().sender_settle_mode(SenderSettleMode::Settled);
+                let sender = session
+                    .new_sender_with("queue1", opts)
+                    .await
+                    .expect("sender not created");
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                let disposition = sender.send(message).await.expect("message not sent");
+                assert_eq!(None, disposition.state());
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                sender
+                    .send_settled(message)
+                    .await
+                    .expect("message not sent");
+
+                let opts = LinkOptions::new().sender_settle_mode(SenderSettleMode::Unsettled);
+                let sender = session
+                    .new_sender_with("queue2", opts)
+                    .await
+                    .expect("sender not created");
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                assert!(sender.send_settled(message).await.is_err());
+            },
+        );
+    }
+
+    #[test]
+    fn receiver_settle_mode_second() {
+        run_sessions(
+            |session| async move {
+                let sender = session.accept_sender().await.expect("sender not accepted");
+                let message = Message::amqp_value(Value::String("hello".to_string()));
+                // The sender settles once the receiver has sent the outcome
+                let disposition = sender.send(message).await.expect("message not sent");
+                assert_eq!(Some(&DeliveryState::Accepted), disposition.state());
+            },
+            |session| async move {
+                let opts = LinkOptions::new().receiver_settle_mode(ReceiverSettleMode::Second);
+                let receiver = session
+                    .new_receiver_with("queue1", opts)
+                    .await
+                    .expect("receiver not created");
+                let mut delivery = receiver.receive().await.expect("message not received");
+                delivery
+                    .disposition(true, DeliveryState::Accepted)
+                    .await
+                    .expect("delivery not settled");
+                assert_eq!(ReceiverSettleMode::Second, receiver.link.settle_modes().1);
+            },
+        );
+    }
+
+    #[test]
+    fn link_options_terminus() {
+        run_sessions(
+            |session| async move {
+                let frame = session
+                    .session
+                    .recv_incoming(LinkRole::Receiver)
+                    .await
+                    .expect("attach not received");
+                let attach = match frame.performative {
+                    Some(Performative::Attach(attach)) => attach,
+                    _ => panic!("unexpected frame"),
+                };
+                assert_eq!("sub1", attach.name);
+                let source = attach.source.clone().expect("no source");
+                assert_eq!(Some("topic1".to_string()), source.address);
+                assert!(matches!(
+                    source.durable,
+                    Some(TerminusDurability::UnsettledState)
+                ));
+                assert!(matches!(
+                    source.expiry_policy,
+                    Some(TerminusExpiryPolicy::Never)
+                ));
+                assert_eq!(Some(Symbol::from_string("copy")), source.distribution_mode);
+                assert_eq!(
+                    Some(vec![Symbol::from_string("topic")]),
+                    source.capabilities
+                );
+                assert_eq!(Some(Outcome::Released), source.default_outcome);
+                assert_eq!(
+                    Some(vec![Outcome::Accepted, Outcome::Released]),
+                    source.outcomes
+                );
+                let properties = attach.properties.clone().expect("no properties");
+                assert_eq!(
+                    Some(&Value::Bool(true)),
+                    properties.get(&Symbol::from_string("shared"))
+                );
+                session.session.accept_link(&attach).unwrap();
+                session.waker.wake().unwrap();
+            },
+            |session| async move {
+                let opts = LinkOptions::new()
+                    .name("sub1")
+                    .durable(TerminusDurability::UnsettledState)
+                    .expiry_policy(TerminusExpiryPolicy::Never)
+                    .distribution_mode(DistributionMode::Copy)
+                    .capabilities(&["topic"])
+                    .default_outcome(Outcome::Released)
+                    .outcomes(&[Outcome::Accepted, Outcome::Released])
+                    .property("shared", Value::Bool(true));
+                let receiver = session
+                    .new_receiver_with("topic1", opts)
+                    .await
+                    .expect("receiver not created");
+                assert_eq!(Some("topic1"), receiver.address());
+                assert_eq!("sub1", receiver.link.name);
+            },
+        );
+    }
+
+    #[test]
+    fn dynamic_links() {
+        let (address_tx, address_rx) = std::sync::mpsc::channel();
+        run_sessions(
+            move |session| async move {
+                let sender = session.accept_sender().await.expect("sender not accepted");
+                address_tx
+                    .send(sender.address().map(|a| a.to_string()))
+                    .unwrap();
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                address_tx
+                    .send(receiver.address().map(|a| a.to_string()))
+                    .unwrap();
+                let message = Message::amqp_value(Value::String("reply".to_string()));
+                sender.send(message).await.expect("message not sent");
+            },
+            |session| async move {
+                let receiver = session
+                    .new_dynamic_receiver()
+                    .await
+                    .expect("receiver not created");
+                let sender = session
+                    .new_dynamic_sender()
+                    .await
+                    .expect("sender not created");
+
+                let assigned = address_rx.recv().unwrap();
+                assert!(assigned.is_some());
+                assert_eq!(assigned.as_deref(), receiver.address());
+                let assigned = address_rx.recv().unwrap();
+                assert!(assigned.is_some());
+                assert_eq!(assigned.as_deref(), sender.address());
+                assert_ne!(receiver.address(), sender.address());
+
+                let delivery = receiver.receive().await.expect("message not received");
+                if let MessageBody::AmqpValue(Value::String(ref s)) = delivery.message().body {
+                    assert_eq!("reply", s);
+                } else {
+                    panic!("unexpected message body");
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn late_attach_not_taken_by_next_link() {
+        let (go_tx, go_rx) = std::sync::mpsc::channel();
+        let (address_tx, address_rx) = std::sync::mpsc::channel();
+        run_sessions_with(
+            ListenOptions::new(),
+            "127.0.0.1",
+            ConnectionOptions::new().operation_timeout(Duration::from_millis(200)),
+            move |session| async move {
+                // The first link is only attached once the client has given up on it
+                go_rx.recv().unwrap();
+                let mut receivers = Vec::new();
+                for _ in 0..2 {
+                    let receiver = session
+                        .accept_receiver()
+                        .await
+                        .expect("receiver not accepted");
+                    address_tx
+                        .send(receiver.address().map(|a| a.to_string()))
+                        .unwrap();
+                    receivers.push(receiver);
+                }
+            },
+            |session| async move {
+                let result = session.new_dynamic_sender().await;
+                assert!(matches!(result, Err(AmqpError::Timeout)));
+                go_tx.send(()).unwrap();
+
+                let sender = session
+                    .new_dynamic_sender()
+                    .await
+                    .expect("sender not created");
+                let first = address_rx.recv().unwrap();
+                let second = address_rx.recv().unwrap();
+                assert_ne!(first, second);
+                assert_eq!(second.as_deref(), sender.address());
+            },
+        );
+    }
+
+    #[test]
+    fn request_response() {
+        run_sessions(
+            |session| async move {
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                assert_eq!(Some("service1"), receiver.address());
+                let sender = session.accept_sender().await.expect("sender not accepted");
+
+                let mut requests = Vec::new();
+                for _ in 0..2 {
+                    let delivery = receiver.receive().await.expect("request not received");
+                    requests.push(delivery.message().clone());
+                }
+                // A reply that no request is waiting for is settled and dropped
+                let mut orphan = Message::amqp_value(Value::String("orphan".to_string()));
+                orphan.properties = Some(MessageProperties {
+                    correlation_id: Some(Value::Ulong(99)),
+                    ..requests[0].properties.clone().expect("no properties")
+                });
+                let disposition = sender.send(orphan).await.expect("orphan not settled");
+                assert_eq!(Some(&DeliveryState::Accepted), disposition.state());
+
+                // Reply in reverse order, so replies are routed by correlation id
+                for request in requests.into_iter().rev() {
+                    let properties = request.properties.expect("no properties");
+                    assert_eq!(properties.reply_to.as_deref(), sender.address());
+                    let body = match request.body {
+                        MessageBody::AmqpValue(Value::String(s)) => s.to_uppercase(),
+                        _ => panic!("unexpected message body"),
+                    };
+                    let mut reply = Message::amqp_value(Value::String(body));
+                    reply.properties = Some(MessageProperties {
+                        correlation_id: properties.correlation_id,
+                        ..properties
+                    });
+                    sender.send(reply).await.expect("reply not sent");
+                }
+            },
+            |session| async move {
+                let client = session
+                    .new_request_response_client("service1")
+                    .await
+                    .expect("client not created");
+                assert!(!client.reply_to().is_empty());
+
+                let (first, second) = futures::join!(
+                    client.request(Message::amqp_value(Value::String("first".to_string()))),
+                    client.request(Message::amqp_value(Value::String("second".to_string())))
+                );
+                for (reply, expected) in [(first, "FIRST"), (second, "SECOND")] {
+                    let reply = reply.expect("reply not received");
+                    if let MessageBody::AmqpValue(Value::String(ref s)) = reply.body {
+                        assert_eq!(expected, s);
+                    } else {
+                        panic!("unexpected message body");
+                    }
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn request_response_message_id() {
+        run_sessions(
+            |session| async move {
+                let receiver = session
+                    .accept_receiver()
+                    .await
+                    .expect("receiver not accepted");
+                let sender = session.accept_sender().await.expect("sender not accepted");
+
+                // Reply with the message id of the request as correlation id
+                let request = receiver.receive().await.expect("request not received");
+                let properties = request.message().properties.clone().expect("no properties");
+                drop(request);
+                let mut reply = Message::amqp_value(Value::String("reply".to_string()));
+                reply.properties = Some(MessageProperties {
+                    message_id: None,
+                    correlation_id: properties.message_id.clone(),
+                    ..properties
+                });
+                sender.send(reply).await.expect("reply not sent");
+            },
+            |session| async move {
+                let client = session
+                    .new_request_response_client("service1")
+                    .await
+                    .expect("client not created");
+                let request = Message::amqp_value(Value::String("request".to_string()));
+                let reply = client
+                    .request_timeout(request, Duration::from_secs(5))
+                    .await
+                    .expect("reply not received");
+                assert!(matches!(
+                    reply.body,
+                    MessageBody::AmqpValue(Value::String(ref s)) if s == "reply"
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn single_threaded_executor() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+
+        // Both ends run as futures on the same thread; waiting must not block the other one.
+        let receive = async {
+            let connection = listener.accept().await.expect("connection not accepted");
+            let session = connection
+                .accept_session()
+                .await
+                .expect("session not accepted");
+            let receiver = session
+                .accept_receiver()
+                .await
+                .expect("receiver not accepted");
+            let delivery = receiver.receive().await.expect("unable to receive message");
+            match delivery.message().body {
+                MessageBody::AmqpValue(Value::String(ref s)) => s.clone(),
+                _ => panic!("unexpected message body"),
+            }
+        };
+        let send = async {
+            let connection = client
+                .connect("127.0.0.1", port, ConnectionOptions::new())
+                .await
+                .expect("connection not created");
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            let sender = session
+                .new_sender("queue1")
+                .await
+                .expect("sender not created");
+            sender
+                .send(Message::amqp_value(Value::String("hello".to_string())))
+                .await
+                .expect("delivery not settled");
+        };
+        let (body, _) = block_on(async { futures::join!(receive, send) });
+        assert_eq!("hello", body);
+    }
+
+    // Accept a connection with a single receiver link and return the body of the first message.
+    async fn receive_one(listener: &Listener) -> String {
+        let connection = listener.accept().await.expect("connection not accepted");
+        let session = connection
+            .accept_session()
+            .await
+            .expect("session not accepted");
+        let receiver = session
+            .accept_receiver()
+            .await
+            .expect("receiver not accepted");
+        let delivery = receiver.receive().await.expect("unable to receive message");
+        match delivery.message().body {
+            MessageBody::AmqpValue(Value::String(ref s)) => s.clone(),
+            _ => panic!("unexpected message body"),
+        }
+    }
+
+    #[test]
+    fn reconnect_to_failover() {
+        let mut primary = Container::with_id("primary")
+            .expect("unable to create container")
+            .start();
+        let primary_listener = primary
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let primary_port = primary_listener.local_addr().port();
+
+        let backup = Container::with_id("backup")
+            .expect("unable to create container")
+            .start();
+        let backup_listener = backup
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let backup_port = backup_listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            block_on(async {
+                assert_eq!("before", receive_one(&primary_listener).await);
+                drop(primary_listener);
+                primary.close().expect("unable to close container");
+                assert_eq!("after", receive_one(&backup_listener).await);
+            });
+            backup
+        });
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let policy = ReconnectPolicy::new()
+                .failover("127.0.0.1", backup_port)
+                .initial_delay(Duration::from_millis(10))
+                .max_delay(Duration::from_millis(100));
+            let connection = client
+                .connect(
+                    "127.0.0.1",
+                    primary_port,
+                    ConnectionOptions::new().reconnect(policy),
+                )
+                .await
+                .expect("connection not created");
+            let session = connection
+                .new_session(None)
+                .await
+                .expect("session not created");
+            let sender = session
+                .new_sender("queue1")
+                .await
+                .expect("sender not created");
+            sender
+                .send(Message::amqp_value(Value::String("before".to_string())))
+                .await
+                .expect("delivery not settled");
+
+            assert_eq!(
+                ConnectionEvent::Disconnected,
+                connection.next_event().await.unwrap()
+            );
+            loop {
+                match connection.next_event().await.unwrap() {
+                    ConnectionEvent::Reconnecting { .. } => {}
+                    ConnectionEvent::Reconnected { host, port } => {
+                        assert_eq!("127.0.0.1", host);
+                        assert_eq!(backup_port, port);
+                        break;
+                    }
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+            sender
+                .send(Message::amqp_value(Value::String("after".to_string())))
+                .await
+                .expect("delivery not settled");
+        });
+        t.join().expect("server error");
+    }
+
+    #[test]
+    fn follow_redirect() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+        let accepted = thread::spawn(move || {
+            block_on(async {
+                let connection = listener.accept().await.expect("connection not accepted");
+                assert_eq!("client", connection.remote_container_id);
+            });
+            server
+        });
+
+        let mut redirector = conn::listen("127.0.0.1", 0, ListenOptions::new()).unwrap();
+        let redirector_port = redirector.listener.local_addr().unwrap().port();
+        let t = thread::spawn(move || {
+            let mut connection = loop {
+                match redirector.accept() {
+                    Ok(connection) => break connection,
+                    Err(AmqpError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => panic!("error accepting connection: {:?}", e),
+                }
+            };
+            let mut opened = false;
+            while !opened {
+                let mut frames = Vec::new();
+                let _ = connection.process(&mut frames);
+                let _ = connection.flush();
+                opened = frames.iter().any(|f| {
+                    matches!(
+                        f,
+                        Frame::AMQP(AmqpFrame {
+                            performative: Some(Performative::Open(_)),
+                            ..
+                        })
+                    )
+                });
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            // Open and close are written together, as brokers do when redirecting
+            let mut data = Vec::new();
+            let frames = vec![
+                Performative::Open(Open::new("redirector")),
+                Performative::Close(Close {
+                    error: Some(
+                        ErrorCondition::new(Condition::ConnectionRedirect, "moved")
+                            .with_info("network-host", Value::String("127.0.0.1".to_string()))
+                            .with_info("port", Value::Ushort(port)),
+                    ),
+                }),
+            ];
+            for performative in frames {
+                Frame::AMQP(AmqpFrame {
+                    channel: 0,
+                    performative: Some(performative),
+                    payload: None,
+                })
+                .encode(&mut data)
+                .unwrap();
+            }
+            connection.transport().write(&data).unwrap();
+            connection
+        });
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let connection = client
+                .connect("127.0.0.1", redirector_port, ConnectionOptions::new())
+                .await
+                .expect("connection not created");
+            assert_eq!("server", connection.remote_container_id);
+        });
+        t.join().expect("redirector error");
+        accepted.join().expect("server error");
+    }
+
+    #[test]
+    fn reconnect_gives_up() {
+        let mut server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            block_on(async {
+                listener.accept().await.expect("connection not accepted");
+            });
+            drop(listener);
+            server.close().expect("unable to close container");
+        });
+
+        let client = Container::with_id("client")
+            .expect("unable to create container")
+            .start();
+        block_on(async {
+            let policy = ReconnectPolicy::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_attempts(2);
+            let connection = client
+                .connect(
+                    "127.0.0.1",
+                    port,
+                    ConnectionOptions::new().reconnect(policy),
+                )
+                .await
+                .expect("connection not created");
+            t.join().expect("server error");
+
+            let mut attempts = 0;
+            loop {
+                match connection.next_event().await.unwrap() {
+                    ConnectionEvent::Disconnected => {}
+                    ConnectionEvent::Reconnecting { attempt, .. } => attempts = attempt,
+                    ConnectionEvent::ReconnectFailed => break,
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+            assert_eq!(2, attempts);
+        });
+    }
+
+    // A peer driven frame by frame, for sending frame sequences the container would not produce.
+    struct RawPeer {
+        connection: conn::Connection<MioNetwork>,
+        frames: Vec<Frame>,
+    }
+
+    impl RawPeer {
+        fn connect(port: u16) -> RawPeer {
+            let network = MioNetwork::connect("127.0.0.1", port).unwrap();
+            let transport = transport::Transport::new(network, 1024);
+            let mut connection = conn::connect(transport, ConnectionOptions::new()).unwrap();
+            connection.open(Open::new("raw")).unwrap();
+            connection.begin(0, Begin::new(0, 100, 100)).unwrap();
+            let mut peer = RawPeer {
+                connection,
+                frames: Vec::new(),
+            };
+            peer.expect(|p| matches!(p, Performative::Begin(_)));
+            peer
+        }
+
+        // Process frames until a matching performative is received, and return it.
+        fn expect<F: Fn(&Performative) -> bool>(&mut self, filter: F) -> Performative {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let _ = self.connection.process(&mut self.frames);
+                let _ = self.connection.flush();
+                let found = self.frames.iter().position(|f| {
+                    matches!(f, Frame::AMQP(AmqpFrame { performative: Some(p), .. }) if filter(p))
+                });
+                if let Some(i) = found {
+                    match self.frames.drain(..=i).next_back() {
+                        Some(Frame::AMQP(AmqpFrame {
+                            performative: Some(p),
+                            ..
+                        })) => return p,
+                        _ => unreachable!(),
+                    }
+                }
+                assert!(Instant::now() < deadline, "expected frame not received");
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    #[test]
+    fn delivery_ids_independent_of_transfer_ids() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            let mut peer = RawPeer::connect(port);
+            let attach =
+                Attach::new("sender", 0, LinkRole::Sender).target(Target::new().address("queue1"));
+            peer.connection.attach(0, attach).unwrap();
+            peer.expect(|p| matches!(p, Performative::Flow(_)));
+
+            let mut payload = Vec::new();
+            Message::amqp_value(Value::String("first".to_string()))
+                .encode(&mut payload)
+                .unwrap();
+            // The first delivery spans three transfer frames, so the next delivery id lags behind
+            // the transfer id.
+            let chunks: Vec<&[u8]> = payload.chunks(payload.len() / 3 + 1).collect();
+            assert_eq!(3, chunks.len());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let transfer = if i == 0 {
+                    Transfer::new(0).delivery_id(0).delivery_tag(b"1")
+                } else {
+                    Transfer::new(0)
+                };
+                let transfer = transfer.settled(true).more(i < 2);
+                peer.connection
+                    .transfer(0, transfer, Some(chunk.to_vec()))
+                    .unwrap();
+            }
+            let mut payload = Vec::new();
+            Message::amqp_value(Value::String("second".to_string()))
+                .encode(&mut payload)
+                .unwrap();
+            let transfer = Transfer::new(0)
+                .delivery_id(1)
+                .delivery_tag(b"2")
+                .settled(true);
+            peer.connection
+                .transfer(0, transfer, Some(payload))
+                .unwrap();
+            peer.connection.flush().unwrap();
+            peer
+        });
+
+        block_on(async {
+            let connection = listener.accept().await.expect("connection not accepted");
+            let session = connection
+                .accept_session()
+                .await
+                .expect("session not accepted");
+            let receiver = session
+                .accept_receiver()
+                .await
+                .expect("receiver not accepted");
+            for expected in ["first", "second"].iter() {
+                let delivery = receiver.receive().await.expect("message not received");
+                match delivery.message().body {
+                    MessageBody::AmqpValue(Value::String(ref s)) => assert_eq!(expected, s),
+                    _ => panic!("unexpected message body"),
+                }
+            }
+        });
+        t.join().expect("peer error");
+        drop(server);
+    }
+
+    // A flow frame granting the given credit on a link.
+    fn link_flow(handle: u32, credit: u32) -> Flow {
+        Flow {
+            next_incoming_id: Some(0),
+            incoming_window: 100,
+            next_outgoing_id: 0,
+            outgoing_window: 100,
+            handle: Some(handle),
+            delivery_count: Some(0),
+            link_credit: Some(credit),
+            available: None,
+            drain: None,
+            echo: None,
+            properties: None,
+        }
+    }
+
+    #[test]
+    fn flow_before_link_accepted() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        // Credit is granted right after the attach, before the link is accepted
+        let t = thread::spawn(move || {
+            let mut peer = RawPeer::connect(port);
+            peer.connection
+                .attach(0, Attach::new("receiver", 0, LinkRole::Receiver))
+                .unwrap();
+            peer.connection.flow(0, link_flow(0, 10)).unwrap();
+            peer.expect(|p| matches!(p, Performative::Transfer(_)));
+            peer
+        });
+
+        block_on(async {
+            let connection = listener.accept().await.expect("connection not accepted");
+            let session = connection
+                .accept_session()
+                .await
+                .expect("session not accepted");
+            thread::sleep(Duration::from_millis(200));
+            let sender = session.accept_sender().await.expect("sender not accepted");
+            sender
+                .send_settled(Message::amqp_value(Value::String("hello".to_string())))
+                .await
+                .expect("message not sent");
+        });
+        t.join().expect("peer error");
+        drop(server);
+    }
+
+    #[test]
+    fn unattached_handle_ends_session() {
+        let server = Container::with_id("server")
+            .expect("unable to create container")
+            .start();
+        let listener = server
+            .listen("127.0.0.1", 0, ListenOptions::new())
+            .expect("unable to listen");
+        let port = listener.local_addr().port();
+
+        let t = thread::spawn(move || {
+            let mut peer = RawPeer::connect(port);
+            peer.connection.flow(0, link_flow(7, 10)).unwrap();
+            match peer.expect(|p| matches!(p, Performative::End(_))) {
+                Performative::End(End { error: Some(e) }) => {
+                    assert_eq!(Condition::UnattachedHandle, e.kind())
+                }
+                p => panic!("unexpected end {:?}", p),
+            }
+        });
+
+        block_on(async {
+            let connection = listener.accept().await.expect("connection not accepted");
+            let _session = connection
+                .accept_session()
+                .await
+                .expect("session not accepted");
+            t.join().expect("peer error");
+        });
+        drop(server);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_connection() {
+        let ca = include_bytes!("../tests/certs/ca.pem");
+        let server_tls = TlsOptions::new().ca_certs(ca).certificate(
+            include_bytes!("../tests/certs/server.pem"),
+            include_bytes!("../tests/certs/server.key"),
+        );
+        let client_tls = TlsOptions::new()
+            .ca_certs(ca)
+            .certificate(
+                include_bytes!("../tests/certs/client.pem"),
+                include_bytes!("../tests/certs/client.key"),
+            )
+            .server_name("localhost");
+        send_to_listener(
+            ListenOptions::new().tls(server_tls),
+            "127.0.0.1",
+            ConnectionOptions::new().tls(client_tls),
+            "Hello, server",
+        );
+    }
+}
@@ -8,7 +8,9 @@
 //! could use some refactoring to simplify.                                           *
 
 use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
+use uuid::Uuid;
 
 use crate::error::*;
 use crate::frame_codec::*;
@@ -99,10 +101,9 @@ impl TryFromValue for u32 {
     fn try_from(value: Value) -> Result<Self> {
         match value {
             Value::Uint(v) => Ok(v),
-            _ => Err(AmqpError::amqp_error(
-                condition::DECODE_ERROR,
-                Some("Error converting value to u32"),
-            )),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to u32",
+            ))),
         }
     }
 }
@@ -111,10 +112,77 @@ impl TryFromValue for u16 {
     fn try_from(value: Value) -> Result<Self> {
         match value {
             Value::Ushort(v) => Ok(v),
-            _ => Err(AmqpError::amqp_error(
-                condition::DECODE_ERROR,
-                Some("Error converting value to u32"),
-            )),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to u16",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for i64 {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Long(v) => Ok(v),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to i64",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for f32 {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Float(v) => Ok(v.0),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to f32",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for f64 {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Double(v) => Ok(v.0),
+            Value::Float(v) => Ok(v.0 as f64),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to f64",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for char {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Char(v) => Ok(v),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to char",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for Uuid {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Uuid(v) => Ok(v),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to Uuid",
+            ))),
+        }
+    }
+}
+
+impl TryFromValue for SystemTime {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Timestamp(v) if v >= 0 => Ok(UNIX_EPOCH + Duration::from_millis(v as u64)),
+            Value::Timestamp(v) => Ok(UNIX_EPOCH - Duration::from_millis(v.unsigned_abs())),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to SystemTime",
+            ))),
         }
     }
 }
@@ -136,10 +204,9 @@ impl TryFromValue for String {
         match value {
             Value::Symbol(v) => Ok(String::from_utf8_lossy(&v[..]).to_string()),
             Value::String(v) => Ok(v),
-            _ => Err(AmqpError::amqp_error(
-                condition::DECODE_ERROR,
-                Some("Error converting value to String"),
-            )),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to String",
+            ))),
         }
     }
 }
@@ -172,10 +239,9 @@ impl TryFromValue for Symbol {
     fn try_from(value: Value) -> Result<Self> {
         match value {
             Value::Symbol(v) => Ok(Symbol::from_vec(v)),
-            _ => Err(AmqpError::amqp_error(
-                condition::DECODE_ERROR,
-                Some("Error converting value to Symbol"),
-            )),
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to Symbol",
+            ))),
         }
     }
 }
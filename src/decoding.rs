@@ -10,6 +10,7 @@ use byteorder::ReadBytesExt;
 use std::collections::BTreeMap;
 use std::io::Read;
 use std::vec::Vec;
+use uuid::Uuid;
 
 use crate::error::*;
 use crate::frame_codec::*;
@@ -92,6 +93,45 @@ fn decode_value_with_ctor(raw_code: u8, reader: &mut dyn Read) -> Result<Value>
             let val = reader.read_i8()? as i64;
             Ok(Value::Long(val))
         }
+        TypeCode::Float => {
+            let val = reader.read_f32::<NetworkEndian>()?;
+            Ok(Value::Float(F32(val)))
+        }
+        TypeCode::Double => {
+            let val = reader.read_f64::<NetworkEndian>()?;
+            Ok(Value::Double(F64(val)))
+        }
+        TypeCode::Decimal32 => {
+            let mut val = [0; 4];
+            reader.read_exact(&mut val)?;
+            Ok(Value::Decimal32(val))
+        }
+        TypeCode::Decimal64 => {
+            let mut val = [0; 8];
+            reader.read_exact(&mut val)?;
+            Ok(Value::Decimal64(val))
+        }
+        TypeCode::Decimal128 => {
+            let mut val = [0; 16];
+            reader.read_exact(&mut val)?;
+            Ok(Value::Decimal128(val))
+        }
+        TypeCode::Char => {
+            let val = reader.read_u32::<NetworkEndian>()?;
+            let c = std::char::from_u32(val).ok_or_else(|| {
+                AmqpError::decode_error(Some(format!("Invalid char 0x{:X}", val).as_str()))
+            })?;
+            Ok(Value::Char(c))
+        }
+        TypeCode::Timestamp => {
+            let val = reader.read_i64::<NetworkEndian>()?;
+            Ok(Value::Timestamp(val))
+        }
+        TypeCode::Uuid => {
+            let mut val = [0; 16];
+            reader.read_exact(&mut val)?;
+            Ok(Value::Uuid(Uuid::from_bytes(val)))
+        }
         TypeCode::Str8 => {
             let len = reader.read_u8()? as usize;
             let mut buffer = vec![0u8; len];
@@ -222,6 +262,14 @@ fn decode_type(code: u8) -> Result<TypeCode> {
         0x54 => Ok(TypeCode::Intsmall),
         0x81 => Ok(TypeCode::Long),
         0x55 => Ok(TypeCode::Longsmall),
+        0x72 => Ok(TypeCode::Float),
+        0x82 => Ok(TypeCode::Double),
+        0x74 => Ok(TypeCode::Decimal32),
+        0x84 => Ok(TypeCode::Decimal64),
+        0x94 => Ok(TypeCode::Decimal128),
+        0x73 => Ok(TypeCode::Char),
+        0x83 => Ok(TypeCode::Timestamp),
+        0x98 => Ok(TypeCode::Uuid),
         0xA0 => Ok(TypeCode::Bin8),
         0xA1 => Ok(TypeCode::Str8),
         0xA3 => Ok(TypeCode::Sym8),
@@ -235,10 +283,9 @@ fn decode_type(code: u8) -> Result<TypeCode> {
         0xD1 => Ok(TypeCode::Map32),
         0xE0 => Ok(TypeCode::Array8),
         0xF0 => Ok(TypeCode::Array32),
-        _ => Err(AmqpError::amqp_error(
-            condition::DECODE_ERROR,
-            Some(format!("Unknown type code: 0x{:X}", code).as_str()),
-        )),
+        _ => Err(AmqpError::decode_error(Some(
+            format!("Unknown type code: 0x{:X}", code).as_str(),
+        ))),
     }
 }
 
@@ -247,9 +294,11 @@ impl ErrorCondition {
         let mut condition = ErrorCondition {
             condition: String::new(),
             description: String::new(),
+            info: None,
         };
         decoder.decode_required(&mut condition.condition)?;
         decoder.decode_optional(&mut condition.description)?;
+        decoder.decode_optional(&mut condition.info)?;
         Ok(condition)
     }
 }
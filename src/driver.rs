@@ -10,23 +10,47 @@ use crate::conn::ChannelId;
 use crate::error::*;
 use crate::framing;
 use crate::framing::{
-    AmqpFrame, Attach, Begin, Close, DeliveryState, Detach, End, Flow, Frame, LinkRole,
-    Performative, Source, Target, Transfer,
+    txn_capability, AmqpFrame, Attach, Begin, Close, Coordinator, DeliveryState, Detach,
+    DistributionMode, End, Flow, Frame, LinkRole, LinkTarget, Outcome, Performative,
+    ReceiverSettleMode, SenderSettleMode, Source, Target, TerminusDurability, TerminusExpiryPolicy,
+    Transfer,
 };
 use crate::message::Message;
+use crate::symbol::Symbol;
 use crate::transport::mio::MioNetwork;
+use crate::transport::FRAME_HEADER_SIZE;
+use crate::types::{Encoder, Value};
 use log::{trace, warn};
 use mio::{Interest, Poll, Token};
 use rand::Rng;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::mpsc;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
 use std::time::{Duration, Instant};
 
 pub type DeliveryTag = Vec<u8>;
 pub type HandleId = u32;
 
+/// Events reported on a connection with a reconnect policy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection to the remote endpoint was lost.
+    Disconnected,
+    /// Attempting to connect to the given endpoint.
+    Reconnecting {
+        host: String,
+        port: u16,
+        attempt: u32,
+    },
+    /// Connected to the given endpoint again. Sessions and links are being re-established.
+    Reconnected { host: String, port: u16 },
+    /// Reconnecting was abandoned, and the connection is closed.
+    ReconnectFailed,
+}
+
 #[derive(Debug)]
 pub struct ConnectionDriver {
     channel_max: u16,
@@ -36,11 +60,22 @@ pub struct ConnectionDriver {
 
     // Frames received on this connection
     rx: Channel<AmqpFrame>,
+    // Begin frames for sessions initiated by the remote endpoint
+    incoming: Channel<AmqpFrame>,
     remote_channel_map: Mutex<HashMap<ChannelId, ChannelId>>,
-    remote_idle_timeout: Duration,
+    remote_idle_timeout: Mutex<Duration>,
+    events: Channel<ConnectionEvent>,
+    timers: Arc<Timers>,
 
     // State
+    opened: AtomicBool,
     closed: AtomicBool,
+    // Endpoint of a reconnect waiting for the remote open
+    resuming: Mutex<Option<(String, u16)>>,
+    // Redirect error received from the remote when closing the connection
+    redirect: Mutex<Option<ErrorCondition>>,
+    // Default timeout for operations on this connection
+    operation_timeout: Option<Duration>,
 }
 
 #[derive(Debug)]
@@ -49,20 +84,40 @@ pub struct SessionDriver {
     driver: Arc<Mutex<conn::Connection<MioNetwork>>>,
     local_channel: ChannelId,
     rx: Channel<AmqpFrame>,
+    // Attach frames for links initiated by the remote endpoint, by remote role
+    incoming_senders: Channel<AmqpFrame>,
+    incoming_receivers: Channel<AmqpFrame>,
     links: Mutex<HashMap<HandleId, Arc<LinkDriver>>>,
+    remote_handle_map: Mutex<HashMap<HandleId, HandleId>>,
+    // Frames received for remote links that are not yet accepted, by remote handle
+    pending: Mutex<HashMap<HandleId, Vec<AmqpFrame>>>,
     #[allow(clippy::type_complexity)]
     did_to_delivery: Arc<Mutex<HashMap<u32, (HandleId, Arc<DeliveryDriver>)>>>,
     handle_generator: AtomicU32,
     initial_outgoing_id: u32,
+    resuming: AtomicBool,
+    // Set when the session is ended before the remote begin is received. It is removed once the
+    // remote endpoint has ended it too, as the begin may still arrive.
+    abandoned: AtomicBool,
 
     flow_control: Arc<Mutex<SessionFlowControl>>,
+    // Notified when flow frames update the session window or link credit
+    flow_signal: Arc<Signal>,
+    timers: Arc<Timers>,
 }
 
+// Initial incoming and outgoing window of sessions
+const SESSION_WINDOW: u32 = i32::MAX as u32;
+
 // TODO: Make this use atomic operations
 #[derive(Clone, Debug)]
 struct SessionFlowControl {
     next_outgoing_id: u32,
     next_incoming_id: u32,
+    // Delivery ids are counted separately from transfer ids, as a delivery may span several
+    // transfer frames
+    next_delivery_id: u32,
+    last_incoming_delivery_id: Option<u32>,
 
     incoming_window: u32,
     outgoing_window: u32,
@@ -76,35 +131,60 @@ impl SessionFlowControl {
         SessionFlowControl {
             next_outgoing_id: 0,
             next_incoming_id: 0,
+            next_delivery_id: 0,
+            last_incoming_delivery_id: None,
 
-            incoming_window: std::i32::MAX as u32,
-            outgoing_window: std::i32::MAX as u32,
+            incoming_window: SESSION_WINDOW,
+            outgoing_window: SESSION_WINDOW,
 
             remote_incoming_window: 0,
             remote_outgoing_window: 0,
         }
     }
 
-    fn accept(&mut self, delivery_id: u32) -> Result<bool> {
-        if delivery_id + 1 < self.next_incoming_id || self.remote_outgoing_window == 0 {
+    // Every transfer frame consumes a transfer id and a slot in the incoming window. Delivery ids
+    // of new deliveries must not go back, while continuation frames of a delivery may repeat its
+    // delivery id.
+    fn accept(&mut self, delivery_id: Option<u32>) -> Result<()> {
+        if let (Some(id), Some(last)) = (delivery_id, self.last_incoming_delivery_id) {
+            if (id.wrapping_sub(last) as i32) < 0 {
+                return Err(AmqpError::framing_error());
+            }
+        }
+        if self.remote_outgoing_window == 0 {
             Err(AmqpError::framing_error())
         } else if self.incoming_window == 0 {
-            Ok(false)
+            Err(AmqpError::amqp_error(
+                condition::session::WINDOW_VIOLATION,
+                Some("transfer exceeds the incoming window"),
+            ))
         } else {
             self.incoming_window -= 1;
-            self.next_incoming_id = delivery_id + 1;
+            self.next_incoming_id = self.next_incoming_id.wrapping_add(1);
             self.remote_outgoing_window -= 1;
-            Ok(true)
+            if delivery_id.is_some() {
+                self.last_incoming_delivery_id = delivery_id;
+            }
+            Ok(())
         }
     }
 
-    fn next(&mut self) -> Option<SessionFlowControl> {
-        if self.outgoing_window > 0 && self.remote_incoming_window > 0 {
-            let original = self.clone();
-            self.next_outgoing_id += 1;
-            self.outgoing_window -= 1;
-            self.remote_incoming_window -= 1;
-            Some(original)
+    // The outgoing window does not limit what this endpoint sends beyond the remote incoming
+    // window, so it is restored whenever the flow state is exchanged.
+    fn refill_outgoing_window(&mut self) {
+        self.outgoing_window = SESSION_WINDOW;
+    }
+
+    // Allocate a delivery id and the transfer ids of all frames of the delivery, if the windows
+    // allow sending them.
+    fn allocate(&mut self, frames: u32) -> Option<u32> {
+        if self.outgoing_window >= frames && self.remote_incoming_window >= frames {
+            let delivery_id = self.next_delivery_id;
+            self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+            self.next_outgoing_id = self.next_outgoing_id.wrapping_add(frames);
+            self.outgoing_window -= frames;
+            self.remote_incoming_window -= frames;
+            Some(delivery_id)
         } else {
             None
         }
@@ -113,6 +193,7 @@ impl SessionFlowControl {
 
 #[derive(Debug)]
 pub struct LinkDriver {
+    pub name: String,
     pub handle: u32,
     pub role: LinkRole,
     pub channel: ChannelId,
@@ -120,11 +201,34 @@ pub struct LinkDriver {
     rx: Channel<AmqpFrame>,
 
     session_flow_control: Arc<Mutex<SessionFlowControl>>,
+    flow_signal: Arc<Signal>,
 
     #[allow(clippy::type_complexity)]
     did_to_delivery: Arc<Mutex<HashMap<u32, (HandleId, Arc<DeliveryDriver>)>>>,
     credit: AtomicU32,
     delivery_count: AtomicU32,
+    // First frame of an incoming delivery that is still being received, and the number of frames
+    // received
+    partial: Mutex<Option<(AmqpFrame, u32)>>,
+    // Local state of incoming deliveries that are not yet settled
+    unsettled: Mutex<BTreeMap<DeliveryTag, Option<DeliveryState>>>,
+    // Number of transfer frames of incoming deliveries, which hold slots in the incoming window
+    // of the session until the deliveries are settled
+    incoming_frames: Mutex<HashMap<DeliveryTag, u32>>,
+    resuming: AtomicBool,
+    // Set when the link is detached before the remote attach is received. It is removed once the
+    // remote endpoint has detached it too, as the attach may still arrive.
+    abandoned: AtomicBool,
+    // Settle modes, as negotiated with the remote endpoint once attached
+    settle_modes: Mutex<(SenderSettleMode, ReceiverSettleMode)>,
+    // Incoming deliveries waiting for the sender to settle them, in receiver settle mode second
+    settling: Mutex<HashMap<u32, DeliveryTag>>,
+    settled_signal: Signal,
+    // Attach frame sent for this link, used to attach it again when resuming
+    attach: Mutex<Option<Attach>>,
+    // Deliveries of a previous link waiting for credit to be sent again, and whether they are
+    // resumed
+    recovering: Mutex<VecDeque<(Arc<DeliveryDriver>, bool)>>,
 }
 
 #[derive(Debug)]
@@ -135,26 +239,265 @@ pub struct DeliveryDriver {
     pub state: Option<DeliveryState>,
     pub tag: DeliveryTag,
     pub id: u32,
+    // Delivery that replaced this one when it was resumed or sent again
+    pub resumed: Mutex<Option<Arc<DeliveryDriver>>>,
+}
+
+impl DeliveryDriver {
+    /// The latest delivery of this message, following deliveries that replaced it.
+    pub fn current(self: &Arc<Self>) -> Arc<DeliveryDriver> {
+        let mut current = self.clone();
+        loop {
+            let next = current.resumed.lock().unwrap().clone();
+            match next {
+                Some(next) => current = next,
+                None => return current,
+            }
+        }
+    }
 }
 
 pub struct SessionOpts {
     pub max_frame_size: u32,
 }
 
+/// Options for creating a link. The settle modes are requested from the remote endpoint, and
+/// settlement follows the modes it agrees to. Terminus options apply to the terminus of the
+/// remote node: the source of a receiver, and the target of a sender.
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    pub name: Option<String>,
+    pub snd_settle_mode: Option<SenderSettleMode>,
+    pub rcv_settle_mode: Option<ReceiverSettleMode>,
+    pub durable: Option<TerminusDurability>,
+    pub expiry_policy: Option<TerminusExpiryPolicy>,
+    pub timeout: Option<u32>,
+    pub distribution_mode: Option<DistributionMode>,
+    pub capabilities: Option<Vec<Symbol>>,
+    pub default_outcome: Option<Outcome>,
+    pub outcomes: Option<Vec<Outcome>>,
+    pub properties: Option<BTreeMap<Symbol, Value>>,
+    pub dynamic: Option<bool>,
+}
+
+impl LinkOptions {
+    pub fn new() -> LinkOptions {
+        LinkOptions::default()
+    }
+
+    /// The link name, which defaults to the address. Naming a link allows it to be resumed,
+    /// such as a durable subscription.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn durable(mut self, durable: TerminusDurability) -> Self {
+        self.durable = Some(durable);
+        self
+    }
+
+    pub fn expiry_policy(mut self, expiry_policy: TerminusExpiryPolicy) -> Self {
+        self.expiry_policy = Some(expiry_policy);
+        self
+    }
+
+    /// Time in seconds the terminus is kept after the expiry policy takes effect.
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn distribution_mode(mut self, mode: DistributionMode) -> Self {
+        self.distribution_mode = Some(mode);
+        self
+    }
+
+    /// Capabilities of the node, such as "queue" or "topic".
+    pub fn capabilities(mut self, capabilities: &[&str]) -> Self {
+        self.capabilities = Some(
+            capabilities
+                .iter()
+                .map(|c| Symbol::from_string(c))
+                .collect(),
+        );
+        self
+    }
+
+    /// Outcome of deliveries that are not settled with an outcome, such as when the link is
+    /// closed.
+    pub fn default_outcome(mut self, outcome: Outcome) -> Self {
+        self.default_outcome = Some(outcome);
+        self
+    }
+
+    /// Outcomes supported by the source.
+    pub fn outcomes(mut self, outcomes: &[Outcome]) -> Self {
+        self.outcomes = Some(outcomes.to_vec());
+        self
+    }
+
+    pub fn property(mut self, key: &str, value: Value) -> Self {
+        self.properties
+            .get_or_insert_with(BTreeMap::new)
+            .insert(Symbol::from_string(key), value);
+        self
+    }
+
+    /// Ask the remote endpoint to create a temporary node for the link. The address of the
+    /// node is assigned by the remote endpoint, and the address given for the link is ignored.
+    pub fn dynamic(mut self, dynamic: bool) -> Self {
+        self.dynamic = Some(dynamic);
+        self
+    }
+
+    /// Whether the sender settles deliveries before sending them (at-most-once), leaves them
+    /// unsettled until the receiver has processed them (at-least-once), or chooses per delivery.
+    pub fn sender_settle_mode(mut self, mode: SenderSettleMode) -> Self {
+        self.snd_settle_mode = Some(mode);
+        self
+    }
+
+    /// Whether the receiver settles deliveries as soon as it has processed them, or only
+    /// after the sender has settled them (exactly-once).
+    pub fn receiver_settle_mode(mut self, mode: ReceiverSettleMode) -> Self {
+        self.rcv_settle_mode = Some(mode);
+        self
+    }
+}
+
 impl ConnectionDriver {
-    pub fn new(conn: conn::Connection<MioNetwork>) -> ConnectionDriver {
+    pub fn new(conn: conn::Connection<MioNetwork>, timers: Arc<Timers>) -> ConnectionDriver {
         ConnectionDriver {
             driver: Arc::new(Mutex::new(conn)),
-            rx: Channel::new(),
+            rx: Channel::new(timers.clone()),
+            incoming: Channel::new(timers.clone()),
             sessions: Mutex::new(HashMap::new()),
             remote_channel_map: Mutex::new(HashMap::new()),
             idle_timeout: Duration::from_secs(5),
-            remote_idle_timeout: Duration::from_secs(0),
-            channel_max: std::u16::MAX,
+            remote_idle_timeout: Mutex::new(Duration::from_secs(0)),
+            events: Channel::new(timers.clone()),
+            timers,
+            channel_max: u16::MAX,
+            opened: AtomicBool::new(false),
             closed: AtomicBool::new(false),
+            resuming: Mutex::new(None),
+            redirect: Mutex::new(None),
+            operation_timeout: None,
         }
     }
 
+    /// Set the default timeout for operations on this connection.
+    pub fn with_operation_timeout(mut self, timeout: Option<Duration>) -> ConnectionDriver {
+        self.operation_timeout = timeout;
+        self
+    }
+
+    /// The deadline of an operation started now, according to the default operation timeout.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.operation_timeout.map(|t| Instant::now() + t)
+    }
+
+    /// Timers of the container processing this connection.
+    pub fn timers(&self) -> Arc<Timers> {
+        self.timers.clone()
+    }
+
+    /// Take the redirect error received from the remote endpoint, if any.
+    pub fn take_redirect(&self) -> Option<ErrorCondition> {
+        self.redirect.lock().unwrap().take()
+    }
+
+    /// Replace the underlying connection after the previous one was lost. Sessions and links
+    /// are re-established once the remote endpoint has sent its open performative.
+    pub fn reset(&self, connection: conn::Connection<MioNetwork>, host: &str, port: u16) {
+        *self.driver.lock().unwrap() = connection;
+        self.remote_channel_map.lock().unwrap().clear();
+        *self.resuming.lock().unwrap() = Some((host.to_string(), port));
+    }
+
+    /// Returns true if the connection has been reset and the remote open is not yet received.
+    pub fn is_resuming(&self) -> bool {
+        self.resuming.lock().unwrap().is_some()
+    }
+
+    /// Returns true once the remote open performative has been received.
+    pub fn is_opened(&self) -> bool {
+        self.opened.load(Ordering::SeqCst)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn event(&self, event: ConnectionEvent) -> Result<()> {
+        trace!("Connection event: {:?}", event);
+        self.events.send(event)
+    }
+
+    /// Wait for the next event on this connection.
+    pub async fn recv_event(&self) -> Result<ConnectionEvent> {
+        self.events.recv().await
+    }
+
+    pub fn try_recv_event(&self) -> Result<ConnectionEvent> {
+        self.events.try_recv()
+    }
+
+    // Begin all sessions again on a new connection.
+    fn resume_sessions(&self) -> Result<()> {
+        let sessions: Vec<Arc<SessionDriver>> =
+            self.sessions.lock().unwrap().values().cloned().collect();
+        let mut driver = self.driver.lock().unwrap();
+        for session in sessions {
+            // Transfer and delivery ids continue from the previous connection, so that deliveries
+            // that are sent again can not be confused with deliveries from before the reconnect.
+            let flow_control = {
+                let mut f = session.flow_control.lock().unwrap();
+                let (next_outgoing_id, next_delivery_id) = (f.next_outgoing_id, f.next_delivery_id);
+                *f = SessionFlowControl::new();
+                f.next_outgoing_id = next_outgoing_id;
+                f.next_delivery_id = next_delivery_id;
+                f.clone()
+            };
+            session.remote_handle_map.lock().unwrap().clear();
+            session.pending.lock().unwrap().clear();
+            // Nothing can be sent on links until they are attached again
+            for link in session.links.lock().unwrap().values() {
+                link.credit.store(0, Ordering::SeqCst);
+                link.delivery_count.store(0, Ordering::SeqCst);
+                *link.partial.lock().unwrap() = None;
+                link.incoming_frames.lock().unwrap().clear();
+                // A detach received before the connection was lost does not apply to the
+                // link once it is attached again
+                let mut frames = Vec::new();
+                while let Ok(frame) = link.rx.try_recv() {
+                    if !matches!(frame.performative, Some(Performative::Detach(_))) {
+                        frames.push(frame);
+                    }
+                }
+                for frame in frames {
+                    link.rx.send(frame)?;
+                }
+            }
+            session.resuming.store(true, Ordering::SeqCst);
+            driver.begin(
+                session.local_channel,
+                Begin {
+                    remote_channel: None,
+                    next_outgoing_id: flow_control.next_outgoing_id,
+                    incoming_window: flow_control.incoming_window,
+                    outgoing_window: flow_control.outgoing_window,
+                    handle_max: None,
+                    offered_capabilities: None,
+                    desired_capabilities: None,
+                    properties: None,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
     pub fn register(&self, id: Token, poll: &mut Poll) -> Result<()> {
         let mut d = self.driver.lock().unwrap();
         let network = d.transport().network();
@@ -167,13 +510,45 @@ impl ConnectionDriver {
         self.driver.lock().unwrap()
     }
 
+    /// End a session that the remote endpoint did not begin in time. The session is removed once
+    /// the remote endpoint has ended it too.
+    pub fn abandon_session(&self, session: &SessionDriver) -> Result<()> {
+        session.abandoned.store(true, Ordering::SeqCst);
+        session.close(None)
+    }
+
+    /// Fail operations waiting on this connection and its sessions and links, once the
+    /// connection is closed and will not be reconnected.
+    pub fn close_channels(&self, condition: Option<ErrorCondition>) {
+        for session in self.sessions.lock().unwrap().values() {
+            session.rx.close(condition.clone());
+            session.incoming_senders.close(condition.clone());
+            session.incoming_receivers.close(condition.clone());
+            session.flow_signal.close(condition.clone());
+            for link in session.links.lock().unwrap().values() {
+                link.rx.close(condition.clone());
+                link.settled_signal.close(condition.clone());
+            }
+        }
+        self.incoming.close(condition.clone());
+        self.events.close(condition);
+    }
+
     pub fn flowcontrol(&self, connection: &mut conn::Connection<MioNetwork>) -> Result<()> {
         let low_flow_watermark = 100;
         let high_flow_watermark = 1000;
 
         for (_, session) in self.sessions.lock().unwrap().iter_mut() {
+            // Credit is only issued once the remote endpoint has attached the link
+            let attached: Vec<HandleId> = session
+                .remote_handle_map
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect();
             for (_, link) in session.links.lock().unwrap().iter_mut() {
-                if link.role == LinkRole::Receiver {
+                if link.role == LinkRole::Receiver && attached.contains(&link.handle) {
                     let credit = link.credit.load(Ordering::SeqCst);
                     if credit <= low_flow_watermark {
                         link.flowcontrol(high_flow_watermark, connection)?;
@@ -188,7 +563,8 @@ impl ConnectionDriver {
         // Sent out keepalives...
         let now = Instant::now();
 
-        let last_received = connection.keepalive(self.remote_idle_timeout, now)?;
+        let remote_idle_timeout = *self.remote_idle_timeout.lock().unwrap();
+        let last_received = connection.keepalive(remote_idle_timeout, now)?;
         if self.idle_timeout.as_millis() > 0 {
             // Ensure our peer honors our keepalive
             if now - last_received > self.idle_timeout * 2 {
@@ -196,6 +572,7 @@ impl ConnectionDriver {
                     error: Some(ErrorCondition {
                         condition: condition::RESOURCE_LIMIT_EXCEEDED.to_string(),
                         description: "local-idle-timeout expired".to_string(),
+                        info: None,
                     }),
                 })?;
             }
@@ -221,6 +598,7 @@ impl ConnectionDriver {
 
         // Read frames until we're blocked
         let mut rx_frames = Vec::new();
+        let mut error = None;
         {
             let mut driver = self.driver.lock().unwrap();
             loop {
@@ -231,13 +609,12 @@ impl ConnectionDriver {
                 match result {
                     Ok(_) => {}
                     // This means that we should poll again to await further I/O action for this driver.
-                    Err(AmqpError::IoError(ref e))
-                        if e.kind() == std::io::ErrorKind::WouldBlock =>
-                    {
+                    Err(AmqpError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         break;
                     }
                     Err(e) => {
-                        return Err(e);
+                        error = Some(e);
+                        break;
                     }
                 }
             }
@@ -247,10 +624,32 @@ impl ConnectionDriver {
             trace!("Dispatching {:?} frames", rx_frames.len());
         }
 
-        self.dispatch(rx_frames)
+        // Frames read before an error (i.e. the remote closing the socket) are still dispatched
+        self.dispatch(rx_frames)?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     fn dispatch(&self, mut frames: Vec<Frame>) -> Result<()> {
+        // A redirect is usually sent right after the open, so it is recorded before the open is
+        // dispatched to allow the redirect to be followed instead.
+        for frame in frames.iter() {
+            if let Frame::AMQP(AmqpFrame {
+                performative:
+                    Some(Performative::Close(Close {
+                        error: Some(ref error),
+                    })),
+                ..
+            }) = frame
+            {
+                if error.redirect().is_some() {
+                    *self.redirect.lock().unwrap() = Some(error.clone());
+                }
+            }
+        }
+
         // Process received frames.
         for frame in frames.drain(..) {
             if let Frame::AMQP(frame) = frame {
@@ -258,32 +657,66 @@ impl ConnectionDriver {
                 if let Some(ref performative) = frame.performative {
                     let channel = frame.channel;
                     match performative {
-                        Performative::Open(ref _open) => {
-                            self.rx.send(frame)?;
+                        Performative::Open(ref open) => {
+                            *self.remote_idle_timeout.lock().unwrap() =
+                                Duration::from_millis(open.idle_timeout.unwrap_or(0) as u64);
+                            self.opened.store(true, Ordering::SeqCst);
+                            let resumed = self.resuming.lock().unwrap().take();
+                            match resumed {
+                                Some((host, port)) => {
+                                    self.resume_sessions()?;
+                                    self.event(ConnectionEvent::Reconnected { host, port })?;
+                                }
+                                None => self.rx.send(frame)?,
+                            }
                         }
                         Performative::Close(ref _close) => {
                             self.rx.send(frame)?;
                         }
                         Performative::Begin(ref begin) => {
-                            let m = self.sessions.lock().unwrap();
-                            let s = m.get(&channel);
-                            if let Some(s) = s {
-                                {
-                                    let mut f = s.flow_control.lock().unwrap();
-                                    f.remote_outgoing_window = begin.outgoing_window;
-                                    f.remote_incoming_window = begin.incoming_window;
+                            if let Some(local_channel) = begin.remote_channel {
+                                // Response to a session we initiated
+                                let m = self.sessions.lock().unwrap();
+                                let s = m.get(&local_channel);
+                                if let Some(s) = s {
+                                    self.remote_channel_map
+                                        .lock()
+                                        .unwrap()
+                                        .insert(channel, local_channel);
+                                    {
+                                        let mut f = s.flow_control.lock().unwrap();
+                                        f.next_incoming_id = begin.next_outgoing_id;
+                                        f.remote_outgoing_window = begin.outgoing_window;
+                                        f.remote_incoming_window = begin.incoming_window;
+                                    }
+                                    if s.resuming.swap(false, Ordering::SeqCst) {
+                                        s.resume_links()?;
+                                    } else if !s.abandoned.load(Ordering::SeqCst) {
+                                        s.rx.send(frame)?;
+                                    }
                                 }
-                                s.rx.send(frame)?;
+                            } else {
+                                // Session initiated by the remote endpoint
+                                self.incoming.send(frame)?;
                             }
                         }
                         Performative::End(ref _end) => {
+                            let local_channel = self.local_channel(channel);
                             let mut m = self.sessions.lock().unwrap();
-                            m.get_mut(&channel).map(|s| s.rx.send(frame));
+                            match m.get(&local_channel) {
+                                Some(s) if s.abandoned.load(Ordering::SeqCst) => {
+                                    m.remove(&local_channel);
+                                    self.remote_channel_map.lock().unwrap().remove(&channel);
+                                }
+                                Some(s) => s.rx.send(frame)?,
+                                None => {}
+                            }
                         }
                         _ => {
+                            let local_channel = self.local_channel(channel);
                             let session = {
                                 let mut m = self.sessions.lock().unwrap();
-                                m.get_mut(&channel).cloned()
+                                m.get_mut(&local_channel).cloned()
                             };
 
                             if let Some(s) = session {
@@ -297,6 +730,15 @@ impl ConnectionDriver {
         Ok(())
     }
 
+    fn local_channel(&self, remote_channel: ChannelId) -> ChannelId {
+        *self
+            .remote_channel_map
+            .lock()
+            .unwrap()
+            .get(&remote_channel)
+            .unwrap_or(&remote_channel)
+    }
+
     fn allocate_session(&self, remote_channel_id: Option<ChannelId>) -> Option<Arc<SessionDriver>> {
         let mut m = self.sessions.lock().unwrap();
         for i in 0..self.channel_max {
@@ -305,11 +747,19 @@ impl ConnectionDriver {
                 let session = Arc::new(SessionDriver {
                     driver: self.driver.clone(),
                     local_channel: chan,
-                    rx: Channel::new(),
+                    rx: Channel::new(self.timers.clone()),
+                    incoming_senders: Channel::new(self.timers.clone()),
+                    incoming_receivers: Channel::new(self.timers.clone()),
                     links: Mutex::new(HashMap::new()),
+                    remote_handle_map: Mutex::new(HashMap::new()),
+                    pending: Mutex::new(HashMap::new()),
                     handle_generator: AtomicU32::new(0),
                     flow_control: Arc::new(Mutex::new(SessionFlowControl::new())),
+                    flow_signal: Arc::new(Signal::new(self.timers.clone())),
+                    timers: self.timers.clone(),
                     initial_outgoing_id: 0,
+                    resuming: AtomicBool::new(false),
+                    abandoned: AtomicBool::new(false),
 
                     did_to_delivery: Arc::new(Mutex::new(HashMap::new())),
                 });
@@ -342,8 +792,51 @@ impl ConnectionDriver {
         Ok(session)
     }
 
-    pub fn recv(&self) -> Result<AmqpFrame> {
-        self.rx.recv()
+    /// Wait for a session initiated by the remote endpoint.
+    pub async fn recv_incoming(&self) -> Result<AmqpFrame> {
+        self.incoming.recv().await
+    }
+
+    /// Accept a session initiated by the remote endpoint, responding with a begin frame.
+    pub fn accept_session(&self, channel: ChannelId, remote: &Begin) -> Result<Arc<SessionDriver>> {
+        let session = self.allocate_session(Some(channel)).unwrap();
+        let flow_control: SessionFlowControl = {
+            let mut f = session.flow_control.lock().unwrap();
+            f.next_incoming_id = remote.next_outgoing_id;
+            f.remote_outgoing_window = remote.outgoing_window;
+            f.remote_incoming_window = remote.incoming_window;
+            f.clone()
+        };
+        let begin = Begin {
+            remote_channel: Some(channel),
+            next_outgoing_id: flow_control.next_outgoing_id,
+            incoming_window: flow_control.incoming_window,
+            outgoing_window: flow_control.outgoing_window,
+            handle_max: None,
+            offered_capabilities: None,
+            desired_capabilities: None,
+            properties: None,
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .begin(session.local_channel, begin)?;
+
+        Ok(session)
+    }
+
+    pub async fn recv(&self) -> Result<AmqpFrame> {
+        self.rx.recv().await
+    }
+
+    /// Wait for the first frame accepted by the filter, leaving other frames to be received
+    /// by others.
+    pub async fn recv_matching<F: Fn(&AmqpFrame) -> bool>(
+        &self,
+        filter: F,
+        deadline: Option<Instant>,
+    ) -> Result<AmqpFrame> {
+        self.rx.recv_matching(filter, deadline).await
     }
 
     pub fn unrecv(&self, frame: AmqpFrame) -> Result<()> {
@@ -354,71 +847,93 @@ impl ConnectionDriver {
 impl SessionDriver {
     pub fn dispatch(&self, frame: AmqpFrame) -> Result<()> {
         match frame.performative {
-            Some(Performative::Attach(ref _attach)) => {
-                self.rx.send(frame)?;
-            }
-            Some(Performative::Detach(ref _detach)) => {
-                self.rx.send(frame)?;
-            }
-            Some(Performative::Transfer(ref transfer)) => {
-                // Session flow control
-                if let Some(delivery_id) = transfer.delivery_id {
-                    loop {
-                        let result = self.flow_control.lock().unwrap().accept(delivery_id);
-                        match result {
-                            Err(AmqpError::Amqp(cond)) => {
-                                self.close(Some(cond))?;
-                            }
-                            Err(_) => {
-                                self.close(None)?;
-                            }
-                            Ok(true) => {
-                                break;
-                            }
-                            _ => {}
+            Some(Performative::Attach(ref attach)) => {
+                // Match the attach against a link we initiated, otherwise it is a new remote link
+                let local = {
+                    let links = self.links.lock().unwrap();
+                    let mut remote_handles = self.remote_handle_map.lock().unwrap();
+                    let link = links.values().find(|l| {
+                        l.name == attach.name
+                            && l.role != attach.role
+                            && !remote_handles.values().any(|h| *h == l.handle)
+                    });
+                    link.map(|l| {
+                        remote_handles.insert(attach.handle, l.handle);
+                        l.clone()
+                    })
+                };
+                if let Some(ref link) = local {
+                    link.negotiate(attach);
+                }
+                match (local, attach.role) {
+                    // The link was detached when its attach did not arrive in time
+                    (Some(link), _) if link.abandoned.load(Ordering::SeqCst) => {}
+                    (Some(link), _) if link.resuming.swap(false, Ordering::SeqCst) => {
+                        if let Err(e) = link.recover(&link, attach) {
+                            warn!("Error recovering link {}: {:?}", link.name, e);
+                        }
+                    }
+                    (Some(_), _) => self.rx.send(frame)?,
+                    (None, role) => {
+                        self.pending
+                            .lock()
+                            .unwrap()
+                            .insert(attach.handle, Vec::new());
+                        match role {
+                            LinkRole::Sender => self.incoming_senders.send(frame)?,
+                            LinkRole::Receiver => self.incoming_receivers.send(frame)?,
                         }
                     }
                 }
-
-                let link = {
-                    let mut m = self.links.lock().unwrap();
-                    m.get_mut(&transfer.handle).unwrap().clone()
-                };
-
-                let count_down = |x| {
-                    if x == 0 {
-                        Some(0)
+            }
+            Some(Performative::Detach(ref detach)) => {
+                if let Some(link) = self.remote_link(detach.handle, &frame)? {
+                    if link.abandoned.load(Ordering::SeqCst) {
+                        self.links.lock().unwrap().remove(&link.handle);
+                        self.remote_handle_map
+                            .lock()
+                            .unwrap()
+                            .remove(&detach.handle);
                     } else {
-                        Some(x - 1)
+                        self.dispatch_link(&link, frame)?;
                     }
-                };
-                // Link flow control
-                if link
-                    .credit
-                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, count_down)
-                    == Ok(0)
-                {
-                    trace!("Transfer but no space left!");
-                } else {
-                    trace!(
-                        "Received transfer. Credit: {:?}",
-                        link.credit.load(Ordering::SeqCst)
-                    );
-                    link.delivery_count.fetch_add(1, Ordering::SeqCst);
-                    link.rx.send(frame)?;
+                }
+            }
+            Some(Performative::Transfer(ref transfer)) => {
+                // Session flow control
+                let accepted = self
+                    .flow_control
+                    .lock()
+                    .unwrap()
+                    .accept(transfer.delivery_id);
+                if let Err(e) = accepted {
+                    return self.close(e.error_condition());
+                }
+                if let Some(link) = self.remote_link(transfer.handle, &frame)? {
+                    self.dispatch_link(&link, frame)?;
                 }
             }
             Some(Performative::Disposition(ref disposition)) => {
                 trace!("Received disposition: {:?}", disposition);
                 let last = disposition.last.unwrap_or(disposition.first);
+                if disposition.role == LinkRole::Sender && disposition.settled == Some(true) {
+                    let links: Vec<Arc<LinkDriver>> =
+                        self.links.lock().unwrap().values().cloned().collect();
+                    for link in links {
+                        link.settled_remotely(disposition.first, last);
+                    }
+                }
                 for id in disposition.first..=last {
-                    if let Some((handle, _)) = self.did_to_delivery.lock().unwrap().get(&id) {
-                        let link = {
-                            let mut m = self.links.lock().unwrap();
-                            m.get_mut(&handle).unwrap().clone()
-                        };
-                        if link.role == disposition.role {
+                    let mut deliveries = self.did_to_delivery.lock().unwrap();
+                    let link = deliveries
+                        .get(&id)
+                        .and_then(|(handle, _)| self.links.lock().unwrap().get(handle).cloned());
+                    if let Some(link) = link {
+                        if link.role != disposition.role {
                             link.rx.send(frame.clone())?;
+                            if disposition.settled == Some(true) {
+                                deliveries.remove(&id);
+                            }
                         }
                     }
                 }
@@ -428,6 +943,7 @@ impl SessionDriver {
                 // Session flow control
                 {
                     let mut control = self.flow_control.lock().unwrap();
+                    control.refill_outgoing_window();
                     control.next_incoming_id = flow.next_outgoing_id;
                     control.remote_outgoing_window = flow.outgoing_window;
                     if let Some(next_incoming_id) = flow.next_incoming_id {
@@ -440,16 +956,17 @@ impl SessionDriver {
                     }
                 }
                 if let Some(handle) = flow.handle {
-                    let link = {
-                        let mut m = self.links.lock().unwrap();
-                        m.get_mut(&handle).unwrap().clone()
-                    };
-                    if let Some(credit) = flow.link_credit {
-                        let credit = flow.delivery_count.unwrap_or(0) + credit
-                            - link.delivery_count.load(Ordering::SeqCst);
-                        link.credit.store(credit, Ordering::SeqCst);
+                    if let Some(link) = self.remote_link(handle, &frame)? {
+                        self.dispatch_link(&link, frame.clone())?;
                     }
                 }
+                // Recovered deliveries are sent once there is credit and room in the window
+                let links: Vec<Arc<LinkDriver>> =
+                    self.links.lock().unwrap().values().cloned().collect();
+                for link in links {
+                    link.send_recovered()?;
+                }
+                self.flow_signal.notify();
             }
             _ => {
                 warn!("Unexpected performative for session: {:?}", frame);
@@ -458,61 +975,269 @@ impl SessionDriver {
         Ok(())
     }
 
+    /// Detach a link that the remote endpoint did not attach in time. The link is removed once
+    /// the remote endpoint has detached it too.
+    pub fn abandon_link(&self, link: &LinkDriver) -> Result<()> {
+        link.abandoned.store(true, Ordering::SeqCst);
+        link.close(None)
+    }
+
+    // Handle the part of a frame that applies to the link it was received on.
+    fn dispatch_link(&self, link: &Arc<LinkDriver>, frame: AmqpFrame) -> Result<()> {
+        match frame.performative {
+            Some(Performative::Detach(_)) => {
+                // Deliver to the attached link so that operations waiting on it fail
+                link.rx.send(frame)?;
+            }
+            Some(Performative::Transfer(_)) => {
+                if link.partial.lock().unwrap().is_some() {
+                    if let Some((frame, frames)) = link.assemble(frame) {
+                        link.deliver(frame, frames)?;
+                    }
+                    return Ok(());
+                }
+
+                let count_down = |x| {
+                    if x == 0 {
+                        Some(0)
+                    } else {
+                        Some(x - 1)
+                    }
+                };
+                // Link flow control
+                if link
+                    .credit
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, count_down)
+                    == Ok(0)
+                {
+                    trace!("Transfer but no space left!");
+                    link.release_frames(1);
+                } else {
+                    trace!(
+                        "Received transfer. Credit: {:?}",
+                        link.credit.load(Ordering::SeqCst)
+                    );
+                    link.delivery_count.fetch_add(1, Ordering::SeqCst);
+                    if let Some((frame, frames)) = link.assemble(frame) {
+                        link.deliver(frame, frames)?;
+                    }
+                }
+            }
+            Some(Performative::Flow(ref flow)) => {
+                if let Some(credit) = flow.link_credit {
+                    let credit = flow.delivery_count.unwrap_or(0) + credit
+                        - link.delivery_count.load(Ordering::SeqCst);
+                    link.credit.store(credit, Ordering::SeqCst);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The link attached by the remote endpoint with the given handle. Frames for links that have
+    // not been accepted yet are kept until they are, and an unknown handle ends the session.
+    fn remote_link(
+        &self,
+        remote_handle: HandleId,
+        frame: &AmqpFrame,
+    ) -> Result<Option<Arc<LinkDriver>>> {
+        if let Some(frames) = self.pending.lock().unwrap().get_mut(&remote_handle) {
+            frames.push(frame.clone());
+            return Ok(None);
+        }
+        let link = self
+            .local_handle(remote_handle)
+            .and_then(|handle| self.links.lock().unwrap().get(&handle).cloned());
+        if link.is_none() {
+            warn!("Received frame for unattached handle {}", remote_handle);
+            self.close(Some(ErrorCondition::new(
+                Condition::UnattachedHandle,
+                "link handle is not attached",
+            )))?;
+        }
+        Ok(link)
+    }
+
     pub fn close(&self, error: Option<ErrorCondition>) -> Result<()> {
         let mut driver = self.driver.lock().unwrap();
         driver.end(self.local_channel, End { error })?;
         driver.flush()
     }
 
-    pub fn new_link(&self, addr: &str, role: LinkRole) -> Result<Arc<LinkDriver>> {
-        trace!("Creating new link!");
+    fn local_handle(&self, remote_handle: HandleId) -> Option<HandleId> {
+        self.remote_handle_map
+            .lock()
+            .unwrap()
+            .get(&remote_handle)
+            .cloned()
+    }
+
+    fn create_link(&self, name: &str, role: LinkRole) -> Arc<LinkDriver> {
         let handle = self.handle_generator.fetch_add(1, Ordering::SeqCst);
-        let link = Arc::new(LinkDriver {
+        Arc::new(LinkDriver {
+            name: name.to_string(),
             role,
             channel: self.local_channel,
             driver: self.driver.clone(),
             handle,
-            rx: Channel::new(),
+            rx: Channel::new(self.timers.clone()),
             session_flow_control: self.flow_control.clone(),
+            flow_signal: self.flow_signal.clone(),
             did_to_delivery: self.did_to_delivery.clone(),
             credit: AtomicU32::new(0),
             delivery_count: AtomicU32::new(0),
-        });
-        // TODO: Increment id
-        {
-            let mut m = self.links.lock().unwrap();
-            m.insert(handle, link.clone());
+            partial: Mutex::new(None),
+            unsettled: Mutex::new(BTreeMap::new()),
+            incoming_frames: Mutex::new(HashMap::new()),
+            resuming: AtomicBool::new(false),
+            abandoned: AtomicBool::new(false),
+            settle_modes: Mutex::new((SenderSettleMode::Mixed, ReceiverSettleMode::First)),
+            settling: Mutex::new(HashMap::new()),
+            settled_signal: Signal::new(self.timers.clone()),
+            recovering: Mutex::new(VecDeque::new()),
+            attach: Mutex::new(None),
+        })
+    }
+
+    fn attach_link(&self, link: &Arc<LinkDriver>, attach: Attach) -> Result<()> {
+        *link.settle_modes.lock().unwrap() = (
+            attach.snd_settle_mode.unwrap_or(SenderSettleMode::Mixed),
+            attach.rcv_settle_mode.unwrap_or(ReceiverSettleMode::First),
+        );
+        *link.attach.lock().unwrap() = Some(attach.clone());
+        // Hold the connection lock so that no flow frame for this link is sent before the attach.
+        let mut driver = self.driver.lock().unwrap();
+        self.links.lock().unwrap().insert(link.handle, link.clone());
+        driver.attach(self.local_channel, attach)
+    }
+
+    pub fn new_link(
+        &self,
+        addr: &str,
+        role: LinkRole,
+        opts: &LinkOptions,
+    ) -> Result<Arc<LinkDriver>> {
+        trace!("Creating new link!");
+        let link = self.create_link(opts.name.as_deref().unwrap_or(addr), role);
+        let attach = Self::link_attach(&link, addr, opts);
+        self.attach_link(&link, attach)?;
+        Ok(link)
+    }
+
+    // Attach all links again after the session has been resumed on a new connection.
+    fn resume_links(&self) -> Result<()> {
+        let links: Vec<Arc<LinkDriver>> = self.links.lock().unwrap().values().cloned().collect();
+        let mut driver = self.driver.lock().unwrap();
+        for link in links {
+            if let Some(mut attach) = link.resume_attach() {
+                link.resuming.store(true, Ordering::SeqCst);
+                attach.unsettled = Some(link.unsettled_map()?);
+                attach.incomplete_unsettled = Some(false);
+                driver.attach(self.local_channel, attach)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Re-attach a link from a previous session using the same link name, sending the state of
+    /// the deliveries that the previous link had not settled.
+    pub fn resume_link(&self, previous: &LinkDriver) -> Result<Arc<LinkDriver>> {
+        trace!("Resuming link {}", previous.name);
+        let link = self.create_link(&previous.name, previous.role);
+        *link.unsettled.lock().unwrap() = previous.unsettled.lock().unwrap().clone();
+
+        let mut attach = previous
+            .resume_attach()
+            .ok_or_else(|| AmqpError::generic("link was never attached"))?;
+        attach.handle = link.handle;
+        attach.unsettled = Some(previous.unsettled_map()?);
+        attach.incomplete_unsettled = Some(false);
+        self.attach_link(&link, attach)?;
+        Ok(link)
+    }
 
-        // Send attach frame
-        let attach = Attach {
-            name: addr.to_string(),
-            handle: handle as u32,
+    /// Create a link controlling transactions at the remote transaction coordinator.
+    pub fn new_coordinator(&self, name: &str) -> Result<Arc<LinkDriver>> {
+        trace!("Creating new coordinator link!");
+        let link = self.create_link(name, LinkRole::Sender);
+        let source = Source {
+            address: None,
+            durable: None,
+            expiry_policy: None,
+            timeout: None,
+            dynamic: None,
+            dynamic_node_properties: None,
+            distribution_mode: None,
+            filter: None,
+            default_outcome: None,
+            outcomes: None,
+            capabilities: None,
+        };
+        let attach = Attach::new(name, link.handle, LinkRole::Sender)
+            .source(source)
+            .coordinator(Coordinator::new(&[txn_capability::LOCAL_TRANSACTIONS]))
+            .initial_delivery_count(0);
+        self.attach_link(&link, attach)?;
+        Ok(link)
+    }
+
+    fn link_attach(link: &LinkDriver, addr: &str, opts: &LinkOptions) -> Attach {
+        let role = link.role;
+        let dynamic = opts.dynamic == Some(true);
+        let address = if dynamic {
+            None
+        } else {
+            Some(addr.to_string())
+        };
+        let mut source = Source {
+            address: address.clone(),
+            durable: None,
+            expiry_policy: None,
+            timeout: None,
+            dynamic: Some(false),
+            dynamic_node_properties: None,
+            default_outcome: opts.default_outcome,
+            distribution_mode: opts
+                .distribution_mode
+                .map(|mode| Symbol::from_string(mode.to_str())),
+            filter: None,
+            outcomes: opts.outcomes.clone(),
+            capabilities: None,
+        };
+        let mut target = Target {
+            address,
+            durable: None,
+            expiry_policy: None,
+            timeout: None,
+            dynamic: Some(false),
+            dynamic_node_properties: None,
+            capabilities: None,
+        };
+        match role {
+            LinkRole::Receiver => {
+                source.dynamic = Some(dynamic);
+                source.durable = opts.durable;
+                source.expiry_policy = opts.expiry_policy;
+                source.timeout = opts.timeout;
+                source.capabilities = opts.capabilities.clone();
+            }
+            LinkRole::Sender => {
+                target.dynamic = Some(dynamic);
+                target.durable = opts.durable;
+                target.expiry_policy = opts.expiry_policy;
+                target.timeout = opts.timeout;
+                target.capabilities = opts.capabilities.clone();
+            }
+        }
+        Attach {
+            name: link.name.clone(),
+            handle: link.handle,
             role,
-            snd_settle_mode: None,
-            rcv_settle_mode: None,
-            source: Some(Source {
-                address: Some(addr.to_string()),
-                durable: None,
-                expiry_policy: None,
-                timeout: None,
-                dynamic: Some(false),
-                dynamic_node_properties: None,
-                default_outcome: None,
-                distribution_mode: None,
-                filter: None,
-                outcomes: None,
-                capabilities: None,
-            }),
-            target: Some(Target {
-                address: Some(addr.to_string()),
-                durable: None,
-                expiry_policy: None,
-                timeout: None,
-                dynamic: Some(false),
-                dynamic_node_properties: None,
-                capabilities: None,
-            }),
+            snd_settle_mode: opts.snd_settle_mode,
+            rcv_settle_mode: opts.rcv_settle_mode,
+            source: Some(source),
+            target: Some(LinkTarget::Target(target)),
             unsettled: None,
             incomplete_unsettled: None,
             initial_delivery_count: if role == LinkRole::Sender {
@@ -523,17 +1248,61 @@ impl SessionDriver {
             max_message_size: None,
             offered_capabilities: None,
             desired_capabilities: None,
-            properties: None,
+            properties: opts.properties.clone(),
+        }
+    }
+
+    /// Wait for a link initiated by the remote endpoint with the given remote role.
+    pub async fn recv_incoming(&self, remote_role: LinkRole) -> Result<AmqpFrame> {
+        match remote_role {
+            LinkRole::Sender => self.incoming_senders.recv().await,
+            LinkRole::Receiver => self.incoming_receivers.recv().await,
+        }
+    }
+
+    /// Accept a link initiated by the remote endpoint, responding with an attach frame
+    /// mirroring the remote source and target.
+    pub fn accept_link(&self, remote: &Attach) -> Result<Arc<LinkDriver>> {
+        let role = match remote.role {
+            LinkRole::Sender => LinkRole::Receiver,
+            LinkRole::Receiver => LinkRole::Sender,
         };
-        self.driver
+        let link = self.create_link(&remote.name, role);
+        // Frames received until now are dispatched once attached, before any that follow them
+        let mut pending = self.pending.lock().unwrap();
+        self.remote_handle_map
             .lock()
             .unwrap()
-            .attach(self.local_channel, attach)?;
+            .insert(remote.handle, link.handle);
+
+        // The settle modes requested by the remote endpoint are agreed to
+        let mut attach = Attach::new(&remote.name, link.handle, role);
+        attach.snd_settle_mode = remote.snd_settle_mode;
+        attach.rcv_settle_mode = remote.rcv_settle_mode;
+        attach.source = remote.source.clone();
+        attach.target = remote.target.clone();
+        if role == LinkRole::Sender {
+            attach.initial_delivery_count = Some(0);
+        }
+        self.attach_link(&link, attach)?;
+        for frame in pending.remove(&remote.handle).unwrap_or_default() {
+            self.dispatch_link(&link, frame)?;
+        }
         Ok(link)
     }
 
-    pub fn recv(&self) -> Result<AmqpFrame> {
-        self.rx.recv()
+    pub async fn recv(&self) -> Result<AmqpFrame> {
+        self.rx.recv().await
+    }
+
+    /// Wait for the first frame accepted by the filter, leaving other frames to be received
+    /// by others.
+    pub async fn recv_matching<F: Fn(&AmqpFrame) -> bool>(
+        &self,
+        filter: F,
+        deadline: Option<Instant>,
+    ) -> Result<AmqpFrame> {
+        self.rx.recv_matching(filter, deadline).await
     }
 
     pub fn unrecv(&self, frame: AmqpFrame) -> Result<()> {
@@ -546,86 +1315,365 @@ impl LinkDriver {
         self.driver.lock().unwrap()
     }
 
+    /// The sender and receiver settle modes of this link.
+    pub fn settle_modes(&self) -> (SenderSettleMode, ReceiverSettleMode) {
+        *self.settle_modes.lock().unwrap()
+    }
+
+    // The attach frame for attaching this link again, using the negotiated settle modes.
+    fn resume_attach(&self) -> Option<Attach> {
+        let mut attach = self.attach.lock().unwrap().clone()?;
+        let (snd_settle_mode, rcv_settle_mode) = self.settle_modes();
+        attach.snd_settle_mode = Some(snd_settle_mode);
+        attach.rcv_settle_mode = Some(rcv_settle_mode);
+        Some(attach)
+    }
+
+    // Use the settle modes of the remote attach, which are the ones the remote endpoint follows.
+    fn negotiate(&self, remote: &Attach) {
+        *self.settle_modes.lock().unwrap() = (
+            remote.snd_settle_mode.unwrap_or(SenderSettleMode::Mixed),
+            remote.rcv_settle_mode.unwrap_or(ReceiverSettleMode::First),
+        );
+    }
+
     pub async fn send_message(
         &self,
         message: Message,
         settled: bool,
+        state: Option<DeliveryState>,
+        deadline: Option<Instant>,
     ) -> Result<Arc<DeliveryDriver>> {
-        let semaphore_fn = |x| {
-            if x == 0 {
-                Some(0)
-            } else {
-                Some(x - 1)
-            }
-        };
+        self.acquire_credit(deadline).await?;
+        let delivery_tag = rand::thread_rng().gen::<[u8; 16]>().to_vec();
+        let queue = self.queue_delivery(message, delivery_tag, settled, state, false)?;
+        self.flow_signal.wait(queue, None).await?
+    }
 
-        // Link flow control
-        while self
-            .credit
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, semaphore_fn)
-            == Ok(0)
-        {
-            std::thread::sleep(Duration::from_millis(500));
-            /*
-            return Err(AmqpError::amqp_error(
-                "not enough available credits to send message",
-                None,
-            ));
-            */
-        }
+    // Wait for link credit to send a delivery.
+    async fn acquire_credit(&self, deadline: Option<Instant>) -> Result<()> {
+        self.flow_signal
+            .wait(
+                || if self.take_credit() { Some(()) } else { None },
+                deadline,
+            )
+            .await?;
+        self.delivery_count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
 
-        // Session flow control
-        let next_outgoing_id;
-        loop {
-            let props = self.session_flow_control.lock().unwrap().next();
-            if let Some(props) = props {
-                next_outgoing_id = props.next_outgoing_id;
-                break;
-            }
-            std::thread::sleep(Duration::from_millis(500));
-        }
+    fn take_credit(&self) -> bool {
+        self.credit
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1))
+            .is_ok()
+    }
 
-        self.delivery_count.fetch_add(1, Ordering::SeqCst);
-        let delivery_tag = rand::thread_rng().gen::<[u8; 16]>().to_vec();
-        let delivery = Arc::new(DeliveryDriver {
-            message,
-            id: next_outgoing_id,
-            tag: delivery_tag.clone(),
-            state: None,
-            remotely_settled: false,
-            settled,
-        });
-
-        if !settled {
-            self.did_to_delivery
-                .lock()
-                .unwrap()
-                .insert(next_outgoing_id, (self.handle, delivery.clone()));
+    /// Abort a delivery that has not been completely sent. Returns false if all frames of the
+    /// delivery were already written.
+    pub fn abort(&self, delivery: &DeliveryDriver) -> Result<bool> {
+        let aborted = {
+            let mut driver = self.driver.lock().unwrap();
+            let aborted = driver.abort_transfer(self.channel, self.handle, delivery.id)?;
+            driver.flush()?;
+            aborted
+        };
+        if aborted {
+            self.did_to_delivery.lock().unwrap().remove(&delivery.id);
         }
+        Ok(aborted)
+    }
+
+    // Split a delivery into transfer frames, returning a function that allocates the ids of all
+    // frames and queues them in one step, so that deliveries on other links of the session can not
+    // take ids in between. The function returns None while the session window is full.
+    fn queue_delivery(
+        &self,
+        message: Message,
+        delivery_tag: DeliveryTag,
+        settled: bool,
+        state: Option<DeliveryState>,
+        resume: bool,
+    ) -> Result<impl FnMut() -> Option<Result<Arc<DeliveryDriver>>> + Unpin + '_> {
+        let mut msgbuf = Vec::new();
+        message.encode(&mut msgbuf)?;
 
-        let transfer = Transfer {
+        // The frame overhead allows for the largest delivery id, as it is allocated once the
+        // frames are ready to be sent
+        let mut transfer = Transfer {
             handle: self.handle,
-            delivery_id: Some(next_outgoing_id),
-            delivery_tag: Some(delivery_tag),
+            delivery_id: Some(u32::MAX),
+            delivery_tag: Some(delivery_tag.clone()),
             message_format: Some(0),
             settled: Some(settled),
             more: Some(false),
             rcv_settle_mode: None,
-            state: None,
-            resume: None,
+            state: state.clone(),
+            resume: if resume { Some(true) } else { None },
             aborted: None,
             batchable: None,
         };
 
-        let mut msgbuf = Vec::new();
-        delivery.message.encode(&mut msgbuf)?;
+        // Split the message into frames that fit within the max frame size
+        let max_frame_size = self.driver.lock().unwrap().max_frame_size();
+        let mut frames = Vec::new();
+        let mut remaining = &msgbuf[..];
+        loop {
+            let mut encoded = Vec::new();
+            transfer.encode(&mut encoded)?;
+            let overhead = FRAME_HEADER_SIZE + encoded.len();
+            if overhead >= max_frame_size {
+                return Err(AmqpError::amqp_error(
+                    condition::connection::FRAMING_ERROR,
+                    Some("max frame size too small for transfer"),
+                ));
+            }
+            let len = std::cmp::min(remaining.len(), max_frame_size - overhead);
+            let (payload, rest) = remaining.split_at(len);
+            remaining = rest;
+            transfer.more = Some(!remaining.is_empty());
+            frames.push((transfer.clone(), payload.to_vec()));
+            if remaining.is_empty() {
+                break;
+            }
 
-        self.driver
+            transfer.delivery_id = None;
+            transfer.delivery_tag = None;
+            transfer.message_format = None;
+        }
+
+        let mut message = Some(message);
+        let mut frames = Some(frames);
+        let count = frames.as_ref().map_or(0, |f| f.len() as u32);
+        Ok(move || {
+            let mut driver = self.driver.lock().unwrap();
+            let delivery_id = self.session_flow_control.lock().unwrap().allocate(count)?;
+            let delivery = Arc::new(DeliveryDriver {
+                message: message.take()?,
+                id: delivery_id,
+                tag: delivery_tag.clone(),
+                state: state.clone(),
+                remotely_settled: false,
+                settled,
+                resumed: Mutex::new(None),
+            });
+            if !settled {
+                self.did_to_delivery
+                    .lock()
+                    .unwrap()
+                    .insert(delivery_id, (self.handle, delivery.clone()));
+            }
+            for (mut transfer, payload) in frames.take()? {
+                if transfer.delivery_id.is_some() {
+                    transfer.delivery_id = Some(delivery_id);
+                }
+                if let Err(e) = driver.transfer(self.channel, transfer, Some(payload)) {
+                    return Some(Err(e));
+                }
+            }
+            Some(Ok(delivery))
+        })
+    }
+
+    // Collect the frames of an incoming delivery, returning the complete delivery and the number
+    // of frames once its last frame has been received. An aborted delivery is discarded.
+    fn assemble(&self, frame: AmqpFrame) -> Option<(AmqpFrame, u32)> {
+        let (more, aborted) = match &frame.performative {
+            Some(Performative::Transfer(transfer)) => {
+                (transfer.more == Some(true), transfer.aborted == Some(true))
+            }
+            _ => (false, false),
+        };
+        let mut partial = self.partial.lock().unwrap();
+        if aborted {
+            trace!("{}: discarding aborted delivery", self.handle);
+            let frames = partial.take().map_or(0, |(_, frames)| frames) + 1;
+            drop(partial);
+            self.release_frames(frames);
+            return None;
+        }
+        let (frame, frames) = match partial.take() {
+            Some((mut first, frames)) => {
+                if let Some(payload) = frame.payload {
+                    first
+                        .payload
+                        .get_or_insert_with(Vec::new)
+                        .extend_from_slice(&payload[..]);
+                }
+                if let (
+                    Some(Performative::Transfer(ref mut first)),
+                    Some(Performative::Transfer(last)),
+                ) = (&mut first.performative, frame.performative)
+                {
+                    first.more = last.more;
+                    first.settled = last.settled.or(first.settled);
+                    first.state = last.state.or_else(|| first.state.take());
+                }
+                (first, frames + 1)
+            }
+            None => (frame, 1),
+        };
+        if more {
+            *partial = Some((frame, frames));
+            None
+        } else {
+            Some((frame, frames))
+        }
+    }
+
+    // Pass a complete incoming delivery to the receiver, keeping track of it until it is settled.
+    // A resumed delivery that has already reached a terminal state is settled instead.
+    fn deliver(&self, frame: AmqpFrame, frames: u32) -> Result<()> {
+        if let Some(Performative::Transfer(ref transfer)) = frame.performative {
+            if let (Some(tag), Some(id)) = (&transfer.delivery_tag, transfer.delivery_id) {
+                *self
+                    .incoming_frames
+                    .lock()
+                    .unwrap()
+                    .entry(tag.clone())
+                    .or_insert(0) += frames;
+                let mut unsettled = self.unsettled.lock().unwrap();
+                if transfer.resume == Some(true) {
+                    let state = unsettled.get(tag).cloned().flatten();
+                    if transfer.settled == Some(true) {
+                        unsettled.remove(tag);
+                        self.release_incoming(tag);
+                        return Ok(());
+                    }
+                    match state {
+                        Some(state) if state.is_terminal() => {
+                            unsettled.remove(tag);
+                            self.release_incoming(tag);
+                            return self.driver().disposition(
+                                self.channel,
+                                framing::Disposition {
+                                    role: self.role,
+                                    first: id,
+                                    last: Some(id),
+                                    settled: Some(true),
+                                    state: Some(state),
+                                    batchable: None,
+                                },
+                            );
+                        }
+                        _ if frame.payload.is_none() => return Ok(()),
+                        _ => {}
+                    }
+                }
+                if transfer.settled != Some(true) {
+                    unsettled.insert(tag.clone(), None);
+                } else {
+                    // A delivery settled by the sender needs no disposition to free the window
+                    self.release_incoming(tag);
+                }
+            }
+        }
+        self.rx.send(frame)
+    }
+
+    /// The unsettled map of this link, mapping delivery tags to the local delivery state.
+    pub fn unsettled_map(&self) -> Result<BTreeMap<Value, Value>> {
+        let mut map = BTreeMap::new();
+        let state_value = |state: &Option<DeliveryState>| -> Result<Value> {
+            state.as_ref().map_or(Ok(Value::Null), |s| s.to_value())
+        };
+        match self.role {
+            LinkRole::Sender => {
+                for (handle, delivery) in self.did_to_delivery.lock().unwrap().values() {
+                    if *handle == self.handle {
+                        map.insert(
+                            Value::Binary(delivery.tag.clone()),
+                            state_value(&delivery.state)?,
+                        );
+                    }
+                }
+            }
+            LinkRole::Receiver => {
+                for (tag, state) in self.unsettled.lock().unwrap().iter() {
+                    map.insert(Value::Binary(tag.clone()), state_value(state)?);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Recover the unsettled deliveries of a previous link after this link has been attached
+    /// in its place. Deliveries known by the remote are resumed with the same delivery tag,
+    /// allowing a remote that has already reached an outcome to settle them, and other deliveries
+    /// are sent again. Deliveries are sent as credit is granted, without waiting for it.
+    pub fn recover(&self, previous: &LinkDriver, remote: &Attach) -> Result<()> {
+        if self.role != LinkRole::Sender {
+            return Ok(());
+        }
+        let mut deliveries: Vec<Arc<DeliveryDriver>> = {
+            let mut map = previous.did_to_delivery.lock().unwrap();
+            let ids: Vec<u32> = map
+                .iter()
+                .filter(|(_, (handle, _))| *handle == previous.handle)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter()
+                .filter_map(|id| map.remove(id))
+                .map(|(_, d)| d)
+                .collect()
+        };
+        deliveries.sort_by_key(|d| d.id);
+
+        let empty = BTreeMap::new();
+        let remote_unsettled = remote.unsettled.as_ref().unwrap_or(&empty);
+        let incomplete = remote.incomplete_unsettled == Some(true);
+        self.recovering
             .lock()
             .unwrap()
-            .transfer(self.channel, transfer, Some(msgbuf))?;
+            .extend(deliveries.into_iter().map(|delivery| {
+                let known = remote_unsettled.contains_key(&Value::Binary(delivery.tag.clone()));
+                (delivery, known || incomplete)
+            }));
+        self.send_recovered()
+    }
+
+    // Send recovered deliveries for as long as there is credit and room in the session window.
+    fn send_recovered(&self) -> Result<()> {
+        let mut recovering = self.recovering.lock().unwrap();
+        while let Some((delivery, resume)) = recovering.front().cloned() {
+            if !self.take_credit() {
+                break;
+            }
+            let mut queue = self.queue_delivery(
+                delivery.message.clone(),
+                delivery.tag.clone(),
+                delivery.settled,
+                delivery.state.clone(),
+                resume,
+            )?;
+            match queue() {
+                Some(sent) => {
+                    recovering.pop_front();
+                    self.delivery_count.fetch_add(1, Ordering::SeqCst);
+                    *delivery.resumed.lock().unwrap() = Some(sent?);
+                }
+                None => {
+                    self.credit.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        Ok(delivery)
+    /// Wait until the deliveries recovered from a previous link have been sent.
+    pub async fn recovered(&self, deadline: Option<Instant>) -> Result<()> {
+        self.flow_signal
+            .wait(
+                || {
+                    if self.recovering.lock().unwrap().is_empty() {
+                        Some(())
+                    } else {
+                        None
+                    }
+                },
+                deadline,
+            )
+            .await
     }
 
     pub async fn flow(&self, credit: u32) -> Result<()> {
@@ -640,7 +1688,11 @@ impl LinkDriver {
     ) -> Result<()> {
         trace!("{}: issuing {} credits", self.handle, credit);
         self.credit.store(credit, Ordering::SeqCst);
-        let props = { self.session_flow_control.lock().unwrap().clone() };
+        let props = {
+            let mut control = self.session_flow_control.lock().unwrap();
+            control.refill_outgoing_window();
+            control.clone()
+        };
         connection.flow(
             self.channel,
             Flow {
@@ -672,30 +1724,61 @@ impl LinkDriver {
         driver.flush()
     }
 
-    pub fn recv(&self) -> Result<AmqpFrame> {
-        self.rx.recv()
+    pub async fn recv(&self) -> Result<AmqpFrame> {
+        self.rx.recv().await
+    }
+
+    /// Wait for the first frame accepted by the filter, leaving other frames to be received
+    /// by others.
+    pub async fn recv_matching<F: Fn(&AmqpFrame) -> bool>(
+        &self,
+        filter: F,
+        deadline: Option<Instant>,
+    ) -> Result<AmqpFrame> {
+        self.rx.recv_matching(filter, deadline).await
     }
 
     pub fn unrecv(&self, frame: AmqpFrame) -> Result<()> {
         self.rx.send(frame)
     }
 
+    /// Send a disposition for a delivery. In receiver settle mode second, an incoming delivery
+    /// is not settled until the sender has settled it, which can be waited for using
+    /// `settlement`.
     pub fn disposition(
         &self,
         delivery: &DeliveryDriver,
         settled: bool,
         state: DeliveryState,
     ) -> Result<()> {
-        if settled {
+        let second = settled
+            && self.role == LinkRole::Receiver
+            && self.settle_modes().1 == ReceiverSettleMode::Second;
+        if second {
+            self.settling
+                .lock()
+                .unwrap()
+                .insert(delivery.id, delivery.tag.clone());
+        }
+        if settled && !second {
             self.did_to_delivery.lock().unwrap().remove(&delivery.id);
-            let mut control = self.session_flow_control.lock().unwrap();
-            control.incoming_window += 1;
+            if self
+                .unsettled
+                .lock()
+                .unwrap()
+                .remove(&delivery.tag)
+                .is_some()
+            {
+                self.release_incoming(&delivery.tag);
+            }
+        } else if let Some(local) = self.unsettled.lock().unwrap().get_mut(&delivery.tag) {
+            *local = Some(state.clone());
         }
         let disposition = framing::Disposition {
             role: self.role,
             first: delivery.id,
             last: Some(delivery.id),
-            settled: Some(settled),
+            settled: Some(settled && !second),
             state: Some(state),
             batchable: None,
         };
@@ -703,36 +1786,278 @@ impl LinkDriver {
         self.driver().disposition(self.channel, disposition)?;
         Ok(())
     }
+
+    /// Wait for the sender to settle an incoming delivery that is settled in receiver settle
+    /// mode second.
+    pub async fn settlement(
+        &self,
+        delivery: &DeliveryDriver,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.settled_signal
+            .wait(
+                || {
+                    if self.settling.lock().unwrap().contains_key(&delivery.id) {
+                        None
+                    } else {
+                        Some(())
+                    }
+                },
+                deadline,
+            )
+            .await
+    }
+
+    // Free the slots in the incoming window of the session held by a settled incoming delivery.
+    fn release_incoming(&self, tag: &DeliveryTag) {
+        let frames = self.incoming_frames.lock().unwrap().remove(tag);
+        if let Some(frames) = frames {
+            self.release_frames(frames);
+        }
+    }
+
+    // Free slots in the incoming window of the session.
+    fn release_frames(&self, frames: u32) {
+        self.session_flow_control.lock().unwrap().incoming_window += frames;
+        self.flow_signal.notify();
+    }
+
+    // Settle incoming deliveries in the given range that were waiting for the sender to settle.
+    fn settled_remotely(&self, first: u32, last: u32) {
+        let mut settling = self.settling.lock().unwrap();
+        let ids: Vec<u32> = settling
+            .keys()
+            .filter(|id| **id >= first && **id <= last)
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        for id in ids {
+            if let Some(tag) = settling.remove(&id) {
+                if self.unsettled.lock().unwrap().remove(&tag).is_some() {
+                    self.release_incoming(&tag);
+                }
+            }
+        }
+        self.settled_signal.notify();
+    }
+}
+
+/// Deadlines of waiting tasks. The tasks are woken by the I/O loop of the container once their
+/// deadline has passed.
+#[derive(Debug)]
+pub struct Timers {
+    deadlines: Mutex<Vec<(Instant, usize, Waker)>>,
+    next_id: AtomicUsize,
+    // Wakes the I/O loop, so that it polls until the new earliest deadline
+    waker: Arc<mio::Waker>,
+}
+
+impl Timers {
+    pub fn new(waker: Arc<mio::Waker>) -> Timers {
+        Timers {
+            deadlines: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+            waker,
+        }
+    }
+
+    fn schedule(&self, id: usize, deadline: Instant, waker: Waker) {
+        let earliest = {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            deadlines.retain(|(_, i, _)| *i != id);
+            let earliest = deadlines.iter().all(|(d, _, _)| deadline < *d);
+            deadlines.push((deadline, id, waker));
+            earliest
+        };
+        if earliest {
+            let _ = self.waker.wake();
+        }
+    }
+
+    fn cancel(&self, id: usize) {
+        self.deadlines.lock().unwrap().retain(|(_, i, _)| *i != id);
+    }
+
+    /// The earliest deadline of a waiting task.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(d, _, _)| *d)
+            .min()
+    }
+
+    /// Wake tasks whose deadline has passed.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let expired: Vec<(Instant, usize, Waker)> = {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            let (expired, pending) = deadlines.drain(..).partition(|(d, _, _)| *d <= now);
+            *deadlines = pending;
+            expired
+        };
+        for (_, _, waker) in expired {
+            waker.wake();
+        }
+    }
+}
+
+/// Tasks waiting for state that is changed by the connection driver. Waiting tasks are woken
+/// whenever the state may have changed, and check their condition again.
+#[derive(Debug)]
+pub struct Signal {
+    // Wakers of pending waits, by wait id
+    wakers: Mutex<HashMap<usize, Waker>>,
+    // Set with the error condition of the connection once it is closed
+    closed: Mutex<Option<Option<ErrorCondition>>>,
+    timers: Arc<Timers>,
+}
+
+impl Signal {
+    pub fn new(timers: Arc<Timers>) -> Signal {
+        Signal {
+            wakers: Mutex::new(HashMap::new()),
+            closed: Mutex::new(None),
+            timers,
+        }
+    }
+
+    /// Fail waiting tasks whose condition is not met, as the connection is closed.
+    pub fn close(&self, condition: Option<ErrorCondition>) {
+        *self.closed.lock().unwrap() = Some(condition);
+        self.notify();
+    }
+
+    /// Wake all waiting tasks.
+    pub fn notify(&self) {
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+
+    /// Wait until the condition returns a value, failing with a timeout error if it does not
+    /// before the deadline.
+    pub fn wait<R, F: FnMut() -> Option<R>>(
+        &self,
+        condition: F,
+        deadline: Option<Instant>,
+    ) -> Wait<'_, F> {
+        Wait {
+            signal: self,
+            id: self.timers.next_id.fetch_add(1, Ordering::SeqCst),
+            condition,
+            deadline,
+            timer: None,
+        }
+    }
+}
+
+/// Future returned by `Signal::wait`.
+pub struct Wait<'a, F> {
+    signal: &'a Signal,
+    id: usize,
+    condition: F,
+    deadline: Option<Instant>,
+    // Waker scheduled to be woken at the deadline
+    timer: Option<Waker>,
+}
+
+impl<'a, R, F: FnMut() -> Option<R> + Unpin> Future for Wait<'a, F> {
+    type Output = Result<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> std::task::Poll<Result<R>> {
+        let this = &mut *self;
+        // Registered before checking the condition, so that a notification in between is not lost
+        {
+            let mut wakers = this.signal.wakers.lock().unwrap();
+            if !matches!(wakers.get(&this.id), Some(w) if w.will_wake(cx.waker())) {
+                wakers.insert(this.id, cx.waker().clone());
+            }
+        }
+        if let Some(value) = (this.condition)() {
+            return std::task::Poll::Ready(Ok(value));
+        }
+        if let Some(ref condition) = *this.signal.closed.lock().unwrap() {
+            return std::task::Poll::Ready(Err(AmqpError::ConnectionClosed(condition.clone())));
+        }
+        if let Some(deadline) = this.deadline {
+            if Instant::now() >= deadline {
+                return std::task::Poll::Ready(Err(AmqpError::Timeout));
+            }
+            if !matches!(&this.timer, Some(w) if w.will_wake(cx.waker())) {
+                this.signal
+                    .timers
+                    .schedule(this.id, deadline, cx.waker().clone());
+                this.timer = Some(cx.waker().clone());
+            }
+        }
+        std::task::Poll::Pending
+    }
 }
 
+impl<'a, F> Drop for Wait<'a, F> {
+    fn drop(&mut self) {
+        self.signal.wakers.lock().unwrap().remove(&self.id);
+        if self.timer.is_some() {
+            self.signal.timers.cancel(self.id);
+        }
+    }
+}
+
+/// Queue of values passed from the connection driver to application tasks.
 #[derive(Debug)]
 pub struct Channel<T> {
-    tx: Mutex<mpsc::Sender<T>>,
-    rx: Mutex<mpsc::Receiver<T>>,
+    queue: Mutex<VecDeque<T>>,
+    signal: Signal,
 }
 
 impl<T> Channel<T> {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Channel<T> {
-        let (tx, rx) = mpsc::channel();
+    pub fn new(timers: Arc<Timers>) -> Channel<T> {
         Channel {
-            tx: Mutex::new(tx),
-            rx: Mutex::new(rx),
+            queue: Mutex::new(VecDeque::new()),
+            signal: Signal::new(timers),
         }
     }
 
     pub fn send(&self, value: T) -> Result<()> {
-        self.tx.lock().unwrap().send(value)?;
+        self.queue.lock().unwrap().push_back(value);
+        self.signal.notify();
         Ok(())
     }
 
     pub fn try_recv(&self) -> Result<T> {
-        let r = self.rx.lock().unwrap().try_recv()?;
-        Ok(r)
+        self.queue
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| AmqpError::Io(std::io::ErrorKind::WouldBlock.into()))
+    }
+
+    pub async fn recv(&self) -> Result<T> {
+        self.recv_matching(|_| true, None).await
+    }
+
+    /// Receive the first value accepted by the filter, leaving other values in the channel.
+    /// Fails with a timeout error if there is no such value before the deadline.
+    pub async fn recv_matching<F: Fn(&T) -> bool>(
+        &self,
+        filter: F,
+        deadline: Option<Instant>,
+    ) -> Result<T> {
+        let next = || {
+            let mut queue = self.queue.lock().unwrap();
+            let position = queue.iter().position(&filter)?;
+            queue.remove(position)
+        };
+        self.signal.wait(next, deadline).await
     }
 
-    pub fn recv(&self) -> Result<T> {
-        let r = self.rx.lock().unwrap().recv()?;
-        Ok(r)
+    /// Fail receiving once the queued values are taken, as the connection is closed.
+    pub fn close(&self, condition: Option<ErrorCondition>) {
+        self.signal.close(condition);
     }
 }
@@ -8,9 +8,12 @@
 use byteorder::NetworkEndian;
 use byteorder::WriteBytesExt;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::io::Write;
 use std::iter::FromIterator;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
+use uuid::Uuid;
 
 use crate::error::*;
 use crate::frame_codec::*;
@@ -166,6 +169,46 @@ impl Encoder for ValueRef<'_> {
                     Ok(TypeCode::Longsmall)
                 }
             }
+            ValueRef::Float(val) => {
+                writer.write_u8(TypeCode::Float as u8)?;
+                writer.write_f32::<NetworkEndian>(val.0)?;
+                Ok(TypeCode::Float)
+            }
+            ValueRef::Double(val) => {
+                writer.write_u8(TypeCode::Double as u8)?;
+                writer.write_f64::<NetworkEndian>(val.0)?;
+                Ok(TypeCode::Double)
+            }
+            ValueRef::Decimal32(val) => {
+                writer.write_u8(TypeCode::Decimal32 as u8)?;
+                writer.write_all(&val[..])?;
+                Ok(TypeCode::Decimal32)
+            }
+            ValueRef::Decimal64(val) => {
+                writer.write_u8(TypeCode::Decimal64 as u8)?;
+                writer.write_all(&val[..])?;
+                Ok(TypeCode::Decimal64)
+            }
+            ValueRef::Decimal128(val) => {
+                writer.write_u8(TypeCode::Decimal128 as u8)?;
+                writer.write_all(&val[..])?;
+                Ok(TypeCode::Decimal128)
+            }
+            ValueRef::Char(val) => {
+                writer.write_u8(TypeCode::Char as u8)?;
+                writer.write_u32::<NetworkEndian>(*val as u32)?;
+                Ok(TypeCode::Char)
+            }
+            ValueRef::Timestamp(val) => {
+                writer.write_u8(TypeCode::Timestamp as u8)?;
+                writer.write_i64::<NetworkEndian>(*val)?;
+                Ok(TypeCode::Timestamp)
+            }
+            ValueRef::Uuid(val) => {
+                writer.write_u8(TypeCode::Uuid as u8)?;
+                writer.write_all(val.as_bytes())?;
+                Ok(TypeCode::Uuid)
+            }
             ValueRef::Array(vec) => {
                 let mut arraybuf = Vec::new();
                 let mut code = 0;
@@ -179,10 +222,9 @@ impl Encoder for ValueRef<'_> {
                 }
 
                 if arraybuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded array size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded array size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if arraybuf.len() > LIST8_MAX {
                     writer.write_u8(TypeCode::Array32 as u8)?;
                     writer.write_u32::<NetworkEndian>((5 + arraybuf.len()) as u32)?;
@@ -209,10 +251,9 @@ impl Encoder for ValueRef<'_> {
                 }
 
                 if listbuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded list size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded list size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if listbuf.len() > LIST8_MAX {
                     writer.write_u8(TypeCode::List32 as u8)?;
                     writer.write_u32::<NetworkEndian>((4 + listbuf.len()) as u32)?;
@@ -240,10 +281,9 @@ impl Encoder for ValueRef<'_> {
                 let n_items = m.len() * 2;
 
                 if listbuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded map size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded map size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if listbuf.len() > LIST8_MAX || n_items > U8_MAX {
                     writer.write_u8(TypeCode::Map32 as u8)?;
                     writer.write_u32::<NetworkEndian>((4 + listbuf.len()) as u32)?;
@@ -271,10 +311,9 @@ impl Encoder for ValueRef<'_> {
                 }
 
                 if arraybuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded array size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded array size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if arraybuf.len() > LIST8_MAX {
                     writer.write_u8(TypeCode::Array32 as u8)?;
                     writer.write_u32::<NetworkEndian>((5 + arraybuf.len()) as u32)?;
@@ -301,10 +340,9 @@ impl Encoder for ValueRef<'_> {
                 }
 
                 if listbuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded list size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded list size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if listbuf.len() > LIST8_MAX {
                     writer.write_u8(TypeCode::List32 as u8)?;
                     writer.write_u32::<NetworkEndian>((4 + listbuf.len()) as u32)?;
@@ -332,10 +370,9 @@ impl Encoder for ValueRef<'_> {
                 let n_items = m.len() * 2;
 
                 if listbuf.len() > LIST32_MAX {
-                    Err(AmqpError::amqp_error(
-                        condition::DECODE_ERROR,
-                        Some("Encoded map size cannot be longer than 4294967291 bytes"),
-                    ))
+                    Err(AmqpError::decode_error(Some(
+                        "Encoded map size cannot be longer than 4294967291 bytes",
+                    )))
                 } else if listbuf.len() > LIST8_MAX || n_items > U8_MAX {
                     writer.write_u8(TypeCode::Map32 as u8)?;
                     writer.write_u32::<NetworkEndian>((4 + listbuf.len()) as u32)?;
@@ -366,6 +403,7 @@ impl Encoder for ErrorCondition {
         let mut encoder = FrameEncoder::new(DESC_ERROR);
         encoder.encode_arg(&self.condition)?;
         encoder.encode_arg(&self.description)?;
+        encoder.encode_arg(&self.info)?;
         encoder.encode(writer)
     }
 }
@@ -431,6 +469,44 @@ impl Encoder for u8 {
     }
 }
 
+impl Encoder for f32 {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        ValueRef::Float(&F32(*self)).encode(writer)
+    }
+}
+
+impl Encoder for f64 {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        ValueRef::Double(&F64(*self)).encode(writer)
+    }
+}
+
+impl Encoder for char {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        ValueRef::Char(self).encode(writer)
+    }
+}
+
+impl Encoder for Uuid {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        ValueRef::Uuid(self).encode(writer)
+    }
+}
+
+/**
+ * SystemTime is encoded as an AMQP timestamp, with millisecond precision.
+ */
+impl Encoder for SystemTime {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let millis = match self.duration_since(UNIX_EPOCH) {
+            Ok(d) => i64::try_from(d.as_millis()),
+            Err(e) => i64::try_from(e.duration().as_millis()).map(|m| -m),
+        }
+        .map_err(|_| AmqpError::decode_error(Some("Timestamp cannot be encoded in 64 bits")))?;
+        ValueRef::Timestamp(&millis).encode(writer)
+    }
+}
+
 impl<T: Encoder> Encoder for Option<T> {
     fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
         match self {
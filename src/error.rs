@@ -5,16 +5,46 @@
 
 //! The error module implements all AMQP 1.0 error types that is supported by dove. Conversion from many different error types are supported.
 
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 use std::io;
 
+use crate::framing::SaslCode;
+use crate::symbol::Symbol;
+use crate::types::Value;
+
 pub type Result<T> = std::result::Result<T, AmqpError>;
 
+/// The underlying cause of an error.
+pub type Cause = Box<dyn error::Error + Send + Sync>;
+
 #[derive(Debug)]
 pub enum AmqpError {
-    IoError(io::Error),
+    /// Failure of the underlying network connection.
+    Io(io::Error),
+    /// Protocol error raised locally, which is sent to the remote endpoint when closing.
     Amqp(ErrorCondition),
+    /// Data could not be decoded or parsed.
+    Decode {
+        description: String,
+        source: Option<Cause>,
+    },
+    /// SASL authentication failed. The code is the outcome sent by the server, if any.
+    Sasl {
+        code: Option<SaslCode>,
+        description: String,
+        source: Option<Cause>,
+    },
+    /// The connection was closed, with the error sent by the remote endpoint, if any.
+    ConnectionClosed(Option<ErrorCondition>),
+    /// The session was ended, with the error sent by the remote endpoint, if any.
+    SessionEnded(Option<ErrorCondition>),
+    /// The link was detached, with the error sent by the remote endpoint, if any.
+    LinkDetached(Option<ErrorCondition>),
+    /// The operation did not complete in time.
+    Timeout,
     Generic(String),
 }
 
@@ -22,26 +52,261 @@ pub enum AmqpError {
 pub struct ErrorCondition {
     pub condition: String,
     pub description: String,
+    pub info: Option<BTreeMap<Symbol, Value>>,
+}
+
+/// Where to connect instead, as given by a connection redirect error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub network_host: String,
+    pub port: u16,
+    pub hostname: Option<String>,
+}
+
+impl ErrorCondition {
+    pub fn new(condition: Condition, description: &str) -> ErrorCondition {
+        ErrorCondition {
+            condition: condition.as_str().to_string(),
+            description: description.to_string(),
+            info: None,
+        }
+    }
+
+    /// Add an entry to the info map.
+    pub fn with_info(mut self, key: &str, value: Value) -> ErrorCondition {
+        self.info
+            .get_or_insert_with(BTreeMap::new)
+            .insert(Symbol::from_string(key), value);
+        self
+    }
+
+    /// The typed condition, for matching without comparing strings.
+    pub fn kind(&self) -> Condition {
+        Condition::from(self.condition.as_str())
+    }
+
+    pub fn is(&self, condition: Condition) -> bool {
+        self.kind() == condition
+    }
+
+    /// Look up an entry in the info map.
+    pub fn info(&self, key: &str) -> Option<&Value> {
+        self.info
+            .as_ref()
+            .and_then(|info| info.get(&Symbol::from_string(key)))
+    }
+
+    /// The redirect target if this is a connection redirect error with the required network-host
+    /// and port entries.
+    pub fn redirect(&self) -> Option<Redirect> {
+        if !self.is(Condition::ConnectionRedirect) {
+            return None;
+        }
+        let network_host = match self.info("network-host")? {
+            Value::String(host) => host.clone(),
+            _ => return None,
+        };
+        // A port that is out of range is not a usable redirect
+        let port = match self.info("port")? {
+            Value::Ushort(port) => *port,
+            Value::Uint(port) => u16::try_from(*port).ok()?,
+            Value::Int(port) => u16::try_from(*port).ok()?,
+            Value::String(port) => port.parse().ok()?,
+            _ => return None,
+        };
+        let hostname = match self.info("hostname") {
+            Some(Value::String(hostname)) => Some(hostname.clone()),
+            _ => None,
+        };
+        Some(Redirect {
+            network_host,
+            port,
+            hostname,
+        })
+    }
 }
 
 pub mod condition {
     pub const INTERNAL_ERROR: &str = "amqp:internal-error";
     pub const NOT_FOUND: &str = "amqp:not-found";
+    pub const UNAUTHORIZED_ACCESS: &str = "amqp:unauthorized-access";
     pub const DECODE_ERROR: &str = "amqp:decode-error";
-    pub const NOT_IMPLEMENTED: &str = "amqp:not-implemented";
     pub const RESOURCE_LIMIT_EXCEEDED: &str = "amqp:resource-limit-exceeded";
+    pub const NOT_ALLOWED: &str = "amqp:not-allowed";
+    pub const INVALID_FIELD: &str = "amqp:invalid-field";
+    pub const NOT_IMPLEMENTED: &str = "amqp:not-implemented";
+    pub const RESOURCE_LOCKED: &str = "amqp:resource-locked";
+    pub const PRECONDITION_FAILED: &str = "amqp:precondition-failed";
+    pub const RESOURCE_DELETED: &str = "amqp:resource-deleted";
+    pub const ILLEGAL_STATE: &str = "amqp:illegal-state";
+    pub const FRAME_SIZE_TOO_SMALL: &str = "amqp:frame-size-too-small";
 
     pub mod connection {
         pub const CONNECTION_FORCED: &str = "amqp:connection:forced";
         pub const FRAMING_ERROR: &str = "amqp:connection:framing-error";
         pub const REDIRECT: &str = "amqp:connection:redirect";
     }
+
+    pub mod session {
+        pub const WINDOW_VIOLATION: &str = "amqp:session:window-violation";
+        pub const ERRANT_LINK: &str = "amqp:session:errant-link";
+        pub const HANDLE_IN_USE: &str = "amqp:session:handle-in-use";
+        pub const UNATTACHED_HANDLE: &str = "amqp:session:unattached-handle";
+    }
+
+    pub mod link {
+        pub const DETACH_FORCED: &str = "amqp:link:detach-forced";
+        pub const TRANSFER_LIMIT_EXCEEDED: &str = "amqp:link:transfer-limit-exceeded";
+        pub const MESSAGE_SIZE_EXCEEDED: &str = "amqp:link:message-size-exceeded";
+        pub const REDIRECT: &str = "amqp:link:redirect";
+        pub const STOLEN: &str = "amqp:link:stolen";
+    }
+
+    pub mod transaction {
+        pub const UNKNOWN_ID: &str = "amqp:transaction:unknown-id";
+        pub const ROLLBACK: &str = "amqp:transaction:rollback";
+        pub const TIMEOUT: &str = "amqp:transaction:timeout";
+    }
+}
+
+/// Typed representation of the error conditions defined by AMQP 1.0. Conditions not defined by
+/// the specification are kept as `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Condition {
+    InternalError,
+    NotFound,
+    UnauthorizedAccess,
+    DecodeError,
+    ResourceLimitExceeded,
+    NotAllowed,
+    InvalidField,
+    NotImplemented,
+    ResourceLocked,
+    PreconditionFailed,
+    ResourceDeleted,
+    IllegalState,
+    FrameSizeTooSmall,
+    ConnectionForced,
+    FramingError,
+    ConnectionRedirect,
+    WindowViolation,
+    ErrantLink,
+    HandleInUse,
+    UnattachedHandle,
+    DetachForced,
+    TransferLimitExceeded,
+    MessageSizeExceeded,
+    LinkRedirect,
+    Stolen,
+    UnknownTransaction,
+    TransactionRollback,
+    TransactionTimeout,
+    Other(String),
+}
+
+impl Condition {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Condition::InternalError => condition::INTERNAL_ERROR,
+            Condition::NotFound => condition::NOT_FOUND,
+            Condition::UnauthorizedAccess => condition::UNAUTHORIZED_ACCESS,
+            Condition::DecodeError => condition::DECODE_ERROR,
+            Condition::ResourceLimitExceeded => condition::RESOURCE_LIMIT_EXCEEDED,
+            Condition::NotAllowed => condition::NOT_ALLOWED,
+            Condition::InvalidField => condition::INVALID_FIELD,
+            Condition::NotImplemented => condition::NOT_IMPLEMENTED,
+            Condition::ResourceLocked => condition::RESOURCE_LOCKED,
+            Condition::PreconditionFailed => condition::PRECONDITION_FAILED,
+            Condition::ResourceDeleted => condition::RESOURCE_DELETED,
+            Condition::IllegalState => condition::ILLEGAL_STATE,
+            Condition::FrameSizeTooSmall => condition::FRAME_SIZE_TOO_SMALL,
+            Condition::ConnectionForced => condition::connection::CONNECTION_FORCED,
+            Condition::FramingError => condition::connection::FRAMING_ERROR,
+            Condition::ConnectionRedirect => condition::connection::REDIRECT,
+            Condition::WindowViolation => condition::session::WINDOW_VIOLATION,
+            Condition::ErrantLink => condition::session::ERRANT_LINK,
+            Condition::HandleInUse => condition::session::HANDLE_IN_USE,
+            Condition::UnattachedHandle => condition::session::UNATTACHED_HANDLE,
+            Condition::DetachForced => condition::link::DETACH_FORCED,
+            Condition::TransferLimitExceeded => condition::link::TRANSFER_LIMIT_EXCEEDED,
+            Condition::MessageSizeExceeded => condition::link::MESSAGE_SIZE_EXCEEDED,
+            Condition::LinkRedirect => condition::link::REDIRECT,
+            Condition::Stolen => condition::link::STOLEN,
+            Condition::UnknownTransaction => condition::transaction::UNKNOWN_ID,
+            Condition::TransactionRollback => condition::transaction::ROLLBACK,
+            Condition::TransactionTimeout => condition::transaction::TIMEOUT,
+            Condition::Other(s) => s,
+        }
+    }
+}
+
+impl From<&str> for Condition {
+    fn from(s: &str) -> Condition {
+        match s {
+            condition::INTERNAL_ERROR => Condition::InternalError,
+            condition::NOT_FOUND => Condition::NotFound,
+            condition::UNAUTHORIZED_ACCESS => Condition::UnauthorizedAccess,
+            condition::DECODE_ERROR => Condition::DecodeError,
+            condition::RESOURCE_LIMIT_EXCEEDED => Condition::ResourceLimitExceeded,
+            condition::NOT_ALLOWED => Condition::NotAllowed,
+            condition::INVALID_FIELD => Condition::InvalidField,
+            condition::NOT_IMPLEMENTED => Condition::NotImplemented,
+            condition::RESOURCE_LOCKED => Condition::ResourceLocked,
+            condition::PRECONDITION_FAILED => Condition::PreconditionFailed,
+            condition::RESOURCE_DELETED => Condition::ResourceDeleted,
+            condition::ILLEGAL_STATE => Condition::IllegalState,
+            condition::FRAME_SIZE_TOO_SMALL => Condition::FrameSizeTooSmall,
+            condition::connection::CONNECTION_FORCED => Condition::ConnectionForced,
+            condition::connection::FRAMING_ERROR => Condition::FramingError,
+            condition::connection::REDIRECT => Condition::ConnectionRedirect,
+            condition::session::WINDOW_VIOLATION => Condition::WindowViolation,
+            condition::session::ERRANT_LINK => Condition::ErrantLink,
+            condition::session::HANDLE_IN_USE => Condition::HandleInUse,
+            condition::session::UNATTACHED_HANDLE => Condition::UnattachedHandle,
+            condition::link::DETACH_FORCED => Condition::DetachForced,
+            condition::link::TRANSFER_LIMIT_EXCEEDED => Condition::TransferLimitExceeded,
+            condition::link::MESSAGE_SIZE_EXCEEDED => Condition::MessageSizeExceeded,
+            condition::link::REDIRECT => Condition::LinkRedirect,
+            condition::link::STOLEN => Condition::Stolen,
+            condition::transaction::UNKNOWN_ID => Condition::UnknownTransaction,
+            condition::transaction::ROLLBACK => Condition::TransactionRollback,
+            condition::transaction::TIMEOUT => Condition::TransactionTimeout,
+            s => Condition::Other(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Display for ErrorCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.description.is_empty() {
+            write!(f, "{}", self.condition)
+        } else {
+            write!(f, "{}: {}", self.condition, self.description)
+        }
+    }
 }
 
+impl error::Error for ErrorCondition {}
+
 impl AmqpError {
     pub fn generic(s: &str) -> AmqpError {
         AmqpError::Generic(s.to_string())
     }
+
+    pub fn sasl(description: &str) -> AmqpError {
+        AmqpError::Sasl {
+            code: None,
+            description: description.to_string(),
+            source: None,
+        }
+    }
+
     pub fn internal_error() -> AmqpError {
         AmqpError::amqp_error(condition::INTERNAL_ERROR, None)
     }
@@ -55,28 +320,84 @@ impl AmqpError {
     }
 
     pub fn decode_error(description: Option<&str>) -> AmqpError {
-        AmqpError::amqp_error(condition::DECODE_ERROR, description)
+        AmqpError::Decode {
+            description: description.unwrap_or("").to_string(),
+            source: None,
+        }
+    }
+
+    /// The error condition, if this error was signalled by or to the remote endpoint.
+    pub fn condition(&self) -> Option<Condition> {
+        match self {
+            AmqpError::Amqp(e)
+            | AmqpError::ConnectionClosed(Some(e))
+            | AmqpError::SessionEnded(Some(e))
+            | AmqpError::LinkDetached(Some(e)) => Some(e.kind()),
+            AmqpError::Decode { .. } => Some(Condition::DecodeError),
+            _ => None,
+        }
+    }
+
+    /// The error condition to send to the remote endpoint when this error closes the
+    /// connection, session or link.
+    pub fn error_condition(&self) -> Option<ErrorCondition> {
+        match self {
+            AmqpError::Amqp(e) => Some(e.clone()),
+            AmqpError::Decode { description, .. } => {
+                Some(ErrorCondition::new(Condition::DecodeError, description))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if the operation failed because the network would block.
+    pub fn is_would_block(&self) -> bool {
+        matches!(self, AmqpError::Io(e) if e.kind() == io::ErrorKind::WouldBlock)
     }
 
     pub fn amqp_error(condition: &'static str, description: Option<&str>) -> AmqpError {
         AmqpError::Amqp(ErrorCondition {
             condition: condition.to_string(),
             description: description.unwrap_or("").to_string(),
+            info: None,
         })
     }
 }
 
 impl error::Error for AmqpError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
+        match self {
+            AmqpError::Io(e) => Some(e),
+            AmqpError::Decode {
+                source: Some(e), ..
+            }
+            | AmqpError::Sasl {
+                source: Some(e), ..
+            } => Some(e.as_ref()),
+            AmqpError::ConnectionClosed(Some(e))
+            | AmqpError::SessionEnded(Some(e))
+            | AmqpError::LinkDetached(Some(e)) => Some(e),
+            _ => None,
+        }
     }
 }
 
 impl fmt::Display for AmqpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            AmqpError::IoError(e) => write!(f, "{}", e.to_string()),
-            AmqpError::Amqp(s) => write!(f, "{:?}", s),
+            AmqpError::Io(e) => write!(f, "{}", e),
+            AmqpError::Amqp(e) => write!(f, "{}", e),
+            AmqpError::Decode { description, .. } => write!(f, "decode error: {}", description),
+            AmqpError::Sasl { description, .. } => {
+                write!(f, "SASL authentication failed: {}", description)
+            }
+            AmqpError::ConnectionClosed(Some(e)) => write!(f, "connection closed: {}", e),
+            AmqpError::ConnectionClosed(None) => write!(f, "connection closed"),
+            AmqpError::SessionEnded(Some(e)) => write!(f, "session ended: {}", e),
+            AmqpError::SessionEnded(None) => write!(f, "session ended"),
+            AmqpError::LinkDetached(Some(e)) => write!(f, "link detached: {}", e),
+            AmqpError::LinkDetached(None) => write!(f, "link detached"),
+            AmqpError::Timeout => write!(f, "operation timed out"),
             AmqpError::Generic(s) => write!(f, "{}", s),
         }
     }
@@ -84,48 +405,135 @@ impl fmt::Display for AmqpError {
 
 impl std::convert::From<io::Error> for AmqpError {
     fn from(error: io::Error) -> Self {
-        AmqpError::IoError(error)
-    }
-}
-
-impl<T> std::convert::From<std::sync::mpsc::SendError<T>> for AmqpError {
-    fn from(error: std::sync::mpsc::SendError<T>) -> Self {
-        AmqpError::Generic(error.to_string())
-    }
-}
-
-impl std::convert::From<std::sync::mpsc::RecvError> for AmqpError {
-    fn from(error: std::sync::mpsc::RecvError) -> Self {
-        AmqpError::Generic(error.to_string())
-    }
-}
-
-impl std::convert::From<std::sync::mpsc::TryRecvError> for AmqpError {
-    fn from(error: std::sync::mpsc::TryRecvError) -> Self {
-        AmqpError::Generic(error.to_string())
+        AmqpError::Io(error)
     }
 }
 
 impl std::convert::From<std::str::Utf8Error> for AmqpError {
     fn from(error: std::str::Utf8Error) -> Self {
-        AmqpError::Generic(error.to_string())
+        AmqpError::Decode {
+            description: error.to_string(),
+            source: Some(Box::new(error)),
+        }
     }
 }
 
 impl std::convert::From<std::string::FromUtf8Error> for AmqpError {
     fn from(error: std::string::FromUtf8Error) -> Self {
-        AmqpError::Generic(error.to_string())
+        AmqpError::Decode {
+            description: error.to_string(),
+            source: Some(Box::new(error)),
+        }
     }
 }
 
 impl std::convert::From<std::boxed::Box<dyn std::any::Any + std::marker::Send>> for AmqpError {
-    fn from(_error: std::boxed::Box<dyn std::any::Any + std::marker::Send>) -> Self {
-        AmqpError::Generic("thread error".to_string())
+    fn from(error: std::boxed::Box<dyn std::any::Any + std::marker::Send>) -> Self {
+        let message = match error.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => error
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "unknown cause".to_string()),
+        };
+        AmqpError::Generic(format!("thread panicked: {}", message))
     }
 }
 
 impl std::convert::From<std::num::ParseIntError> for AmqpError {
     fn from(error: std::num::ParseIntError) -> Self {
-        AmqpError::Generic(error.to_string())
+        AmqpError::Decode {
+            description: error.to_string(),
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_conditions() {
+        let conditions = vec![
+            Condition::UnauthorizedAccess,
+            Condition::PreconditionFailed,
+            Condition::ResourceLocked,
+            Condition::DetachForced,
+            Condition::Stolen,
+            Condition::WindowViolation,
+            Condition::TransactionRollback,
+        ];
+        for condition in conditions {
+            assert_eq!(condition, Condition::from(condition.as_str()));
+        }
+        assert_eq!(
+            Condition::Other("com.example:custom".to_string()),
+            Condition::from("com.example:custom")
+        );
+
+        let error = AmqpError::amqp_error(condition::link::STOLEN, None);
+        assert_eq!(Some(Condition::Stolen), error.condition());
+        assert_eq!(None, AmqpError::generic("error").condition());
+    }
+
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+
+        let error = AmqpError::ConnectionClosed(Some(ErrorCondition::new(
+            Condition::ResourceLimitExceeded,
+            "too many connections",
+        )));
+        assert_eq!(
+            "amqp:resource-limit-exceeded: too many connections",
+            error.source().unwrap().to_string()
+        );
+        assert_eq!(Some(Condition::ResourceLimitExceeded), error.condition());
+        assert!(AmqpError::ConnectionClosed(None).source().is_none());
+
+        let error: AmqpError = "x".parse::<u16>().unwrap_err().into();
+        assert!(matches!(error, AmqpError::Decode { .. }));
+        assert!(error.source().is_some());
+
+        let error: AmqpError = io::Error::from(io::ErrorKind::ConnectionReset).into();
+        assert!(error.source().is_some());
+        assert_eq!(None, error.error_condition());
+    }
+
+    #[test]
+    fn redirect_info() {
+        let error = ErrorCondition::new(Condition::ConnectionRedirect, "moved")
+            .with_info("network-host", Value::String("broker2".to_string()))
+            .with_info("port", Value::Uint(5673));
+        assert_eq!(
+            Some(Redirect {
+                network_host: "broker2".to_string(),
+                port: 5673,
+                hostname: None,
+            }),
+            error.redirect()
+        );
+
+        // Missing port
+        let error = ErrorCondition::new(Condition::ConnectionRedirect, "moved")
+            .with_info("network-host", Value::String("broker2".to_string()));
+        assert_eq!(None, error.redirect());
+
+        // Port out of range
+        let error = ErrorCondition::new(Condition::ConnectionRedirect, "moved")
+            .with_info("network-host", Value::String("broker2".to_string()))
+            .with_info("port", Value::Uint(70000));
+        assert_eq!(None, error.redirect());
+        let error = ErrorCondition::new(Condition::ConnectionRedirect, "moved")
+            .with_info("network-host", Value::String("broker2".to_string()))
+            .with_info("port", Value::Int(-1));
+        assert_eq!(None, error.redirect());
+
+        // Not a connection redirect
+        let error = ErrorCondition::new(Condition::LinkRedirect, "moved")
+            .with_info("network-host", Value::String("broker2".to_string()))
+            .with_info("port", Value::Uint(5673));
+        assert_eq!(None, error.redirect());
     }
 }
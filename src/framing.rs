@@ -58,8 +58,15 @@ pub struct SaslInit {
     pub hostname: Option<String>,
 }
 
-pub type SaslChallenge = Vec<u8>;
-pub type SaslResponse = Vec<u8>;
+#[derive(Debug)]
+pub struct SaslChallenge {
+    pub challenge: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct SaslResponse {
+    pub response: Vec<u8>,
+}
 
 #[derive(Debug)]
 pub struct SaslOutcome {
@@ -69,16 +76,28 @@ pub struct SaslOutcome {
 
 pub type SaslCode = u8;
 
+/** SASL outcome codes. */
+pub const SASL_CODE_OK: SaslCode = 0;
+pub const SASL_CODE_AUTH: SaslCode = 1;
+pub const SASL_CODE_SYS: SaslCode = 2;
+pub const SASL_CODE_SYS_PERM: SaslCode = 3;
+pub const SASL_CODE_SYS_TEMP: SaslCode = 4;
+
 impl Encoder for SaslMechanism {
     fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
-        let value = match self {
-            SaslMechanism::Anonymous => Symbol::from_slice(b"ANONYMOUS"),
-            SaslMechanism::Plain => Symbol::from_slice(b"PLAIN"),
-            SaslMechanism::CramMd5 => Symbol::from_slice(b"CRAM-MD5"),
-            SaslMechanism::ScramSha1 => Symbol::from_slice(b"SCRAM-SHA-1"),
-            SaslMechanism::ScramSha256 => Symbol::from_slice(b"SCRAM-SHA-256"),
-        };
-        value.encode(writer)
+        Symbol::from_slice(self.to_str().as_bytes()).encode(writer)
+    }
+}
+
+impl Encoder for SaslMechanisms {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut values = Vec::new();
+        for mechanism in self.mechanisms.iter() {
+            values.push(ValueRef::SymbolRef(mechanism.to_str()));
+        }
+        let mut encoder = FrameEncoder::new(DESC_SASL_MECHANISMS);
+        encoder.encode_arg(&ValueRef::ArrayRef(&values))?;
+        encoder.encode(writer)
     }
 }
 
@@ -92,6 +111,65 @@ impl Encoder for SaslInit {
     }
 }
 
+impl Encoder for SaslChallenge {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_SASL_CHALLENGE);
+        encoder.encode_arg(&self.challenge)?;
+        encoder.encode(writer)
+    }
+}
+
+impl Encoder for SaslResponse {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_SASL_RESPONSE);
+        encoder.encode_arg(&self.response)?;
+        encoder.encode(writer)
+    }
+}
+
+impl Encoder for SaslOutcome {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_SASL_OUTCOME);
+        encoder.encode_arg(&self.code)?;
+        encoder.encode_arg(&self.additional_data)?;
+        encoder.encode(writer)
+    }
+}
+
+impl SaslInit {
+    pub fn decode(mut decoder: FrameDecoder) -> Result<SaslInit> {
+        let mut init = SaslInit {
+            mechanism: SaslMechanism::Anonymous,
+            initial_response: None,
+            hostname: None,
+        };
+        decoder.decode_required(&mut init.mechanism)?;
+        decoder.decode_optional(&mut init.initial_response)?;
+        decoder.decode_optional(&mut init.hostname)?;
+        Ok(init)
+    }
+}
+
+impl SaslChallenge {
+    pub fn decode(mut decoder: FrameDecoder) -> Result<SaslChallenge> {
+        let mut challenge = SaslChallenge {
+            challenge: Vec::new(),
+        };
+        decoder.decode_required(&mut challenge.challenge)?;
+        Ok(challenge)
+    }
+}
+
+impl SaslResponse {
+    pub fn decode(mut decoder: FrameDecoder) -> Result<SaslResponse> {
+        let mut response = SaslResponse {
+            response: Vec::new(),
+        };
+        decoder.decode_required(&mut response.response)?;
+        Ok(response)
+    }
+}
+
 impl SaslOutcome {
     pub fn decode(mut decoder: FrameDecoder) -> Result<SaslOutcome> {
         let mut outcome = SaslOutcome {
@@ -110,12 +188,27 @@ impl SaslMechanism {
         match input {
             "ANONYMOUS" => Ok(SaslMechanism::Anonymous),
             "PLAIN" => Ok(SaslMechanism::Plain),
+            "EXTERNAL" => Ok(SaslMechanism::External),
             "CRAM-MD5" => Ok(SaslMechanism::CramMd5),
             "SCRAM-SHA-1" => Ok(SaslMechanism::ScramSha1),
             "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
-            v => Err(AmqpError::decode_error(Some(
-                format!("Unsupported SASL mechanism {:?}", v).as_str(),
-            ))),
+            "XOAUTH2" => Ok(SaslMechanism::XOAuth2),
+            "OAUTHBEARER" => Ok(SaslMechanism::OAuthBearer),
+            v => Ok(SaslMechanism::Other(v.to_string())),
+        }
+    }
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            SaslMechanism::Anonymous => "ANONYMOUS",
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+            SaslMechanism::CramMd5 => "CRAM-MD5",
+            SaslMechanism::ScramSha1 => "SCRAM-SHA-1",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::XOAuth2 => "XOAUTH2",
+            SaslMechanism::OAuthBearer => "OAUTHBEARER",
+            SaslMechanism::Other(name) => name,
         }
     }
 }
@@ -208,14 +301,14 @@ pub struct Attach {
     pub snd_settle_mode: Option<SenderSettleMode>,
     pub rcv_settle_mode: Option<ReceiverSettleMode>,
     pub source: Option<Source>,
-    pub target: Option<Target>,
+    pub target: Option<LinkTarget>,
     pub unsettled: Option<BTreeMap<Value, Value>>,
     pub incomplete_unsettled: Option<bool>,
     pub initial_delivery_count: Option<u32>,
     pub max_message_size: Option<u64>,
     pub offered_capabilities: Option<Vec<Symbol>>,
     pub desired_capabilities: Option<Vec<Symbol>>,
-    pub properties: Option<BTreeMap<String, Value>>,
+    pub properties: Option<BTreeMap<Symbol, Value>>,
 }
 
 #[derive(Debug, Clone)]
@@ -272,6 +365,8 @@ pub enum DeliveryState {
     Rejected(Rejected),
     Released,
     Modified(Modified),
+    Declared(Declared),
+    Transactional(TransactionalState),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -315,7 +410,57 @@ pub struct Target {
     pub timeout: Option<u32>,
     pub dynamic: Option<bool>,
     pub dynamic_node_properties: Option<BTreeMap<Symbol, Value>>,
-    pub capabilities: Option<Vec<String>>,
+    pub capabilities: Option<Vec<Symbol>>,
+}
+
+/// The target terminus of a link. Links controlling transactions are attached to a
+/// coordinator rather than a node.
+#[derive(Debug, Clone)]
+pub enum LinkTarget {
+    Target(Target),
+    Coordinator(Coordinator),
+}
+
+/// The target of a link to a transactional resource.
+#[derive(Debug, Clone)]
+pub struct Coordinator {
+    pub capabilities: Option<Vec<Symbol>>,
+}
+
+/// Message body used to declare a transaction.
+#[derive(Debug, Clone)]
+pub struct Declare {
+    pub global_id: Option<Value>,
+}
+
+/// Message body used to commit or roll back a transaction.
+#[derive(Debug, Clone)]
+pub struct Discharge {
+    pub txn_id: Vec<u8>,
+    pub fail: Option<bool>,
+}
+
+/// Outcome of a declare, holding the identifier of the new transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Declared {
+    pub txn_id: Vec<u8>,
+}
+
+/// Delivery state of a delivery that is part of a transaction, with the outcome that takes
+/// effect when the transaction is committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionalState {
+    pub txn_id: Vec<u8>,
+    pub outcome: Option<Box<DeliveryState>>,
+}
+
+/// Capabilities of a transaction coordinator.
+pub mod txn_capability {
+    pub const LOCAL_TRANSACTIONS: &str = "amqp:local-transactions";
+    pub const DISTRIBUTED_TRANSACTIONS: &str = "amqp:distributed-transactions";
+    pub const PROMOTABLE_TRANSACTIONS: &str = "amqp:promotable-transactions";
+    pub const MULTI_TXNS_PER_SSN: &str = "amqp:multi-txns-per-ssn";
+    pub const MULTI_SSNS_PER_TXN: &str = "amqp:multi-ssns-per-txn";
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -325,7 +470,7 @@ pub enum LinkRole {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SenderSettleMode {
     Unsettled,
     Settled,
@@ -333,7 +478,7 @@ pub enum SenderSettleMode {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReceiverSettleMode {
     First,
     Second,
@@ -354,7 +499,7 @@ pub enum TerminusExpiryPolicy {
     Never,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Outcome {
     Accepted,
     Rejected,
@@ -362,6 +507,14 @@ pub enum Outcome {
     Modified,
 }
 
+/// Whether messages are moved from the source node to the receiver, or copied leaving them
+/// available to other receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistributionMode {
+    Move,
+    Copy,
+}
+
 impl Open {
     pub fn new(container_id: &str) -> Open {
         Open {
@@ -523,7 +676,12 @@ impl Attach {
     }
 
     pub fn target(mut self, target: Target) -> Self {
-        self.target = Some(target);
+        self.target = Some(LinkTarget::Target(target));
+        self
+    }
+
+    pub fn coordinator(mut self, coordinator: Coordinator) -> Self {
+        self.target = Some(LinkTarget::Coordinator(coordinator));
         self
     }
 
@@ -677,6 +835,21 @@ impl Transfer {
         self
     }
 
+    pub fn more(mut self, more: bool) -> Self {
+        self.more = Some(more);
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = Some(resume);
+        self
+    }
+
+    pub fn aborted(mut self, aborted: bool) -> Self {
+        self.aborted = Some(aborted);
+        self
+    }
+
     pub fn decode(mut decoder: FrameDecoder) -> Result<Transfer> {
         let mut transfer = Transfer::new(0);
         decoder.decode_required(&mut transfer.handle)?;
@@ -847,6 +1020,157 @@ impl Encoder for Target {
     }
 }
 
+impl LinkTarget {
+    /// The address of the target node, if the target is a node.
+    pub fn address(&self) -> Option<&str> {
+        match self {
+            LinkTarget::Target(target) => target.address.as_deref(),
+            LinkTarget::Coordinator(_) => None,
+        }
+    }
+}
+
+impl Encoder for LinkTarget {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        match self {
+            LinkTarget::Target(target) => target.encode(writer),
+            LinkTarget::Coordinator(coordinator) => coordinator.encode(writer),
+        }
+    }
+}
+
+impl TryFromValue for LinkTarget {
+    fn try_from(value: Value) -> Result<Self> {
+        if let Value::Described(descriptor, mut body) = value {
+            let decoder = FrameDecoder::new(&descriptor, &mut body)?;
+            match *descriptor {
+                DESC_COORDINATOR => Ok(LinkTarget::Coordinator(Coordinator::decode(decoder)?)),
+                _ => Ok(LinkTarget::Target(Target::decode(decoder)?)),
+            }
+        } else {
+            Err(AmqpError::decode_error(Some(
+                "Error converting value to LinkTarget",
+            )))
+        }
+    }
+}
+
+impl Coordinator {
+    pub fn new(capabilities: &[&str]) -> Coordinator {
+        Coordinator {
+            capabilities: Some(
+                capabilities
+                    .iter()
+                    .map(|c| Symbol::from_string(c))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn decode(mut decoder: FrameDecoder) -> Result<Coordinator> {
+        let mut coordinator = Coordinator { capabilities: None };
+        decoder.decode_optional(&mut coordinator.capabilities)?;
+        Ok(coordinator)
+    }
+}
+
+impl Encoder for Coordinator {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_COORDINATOR);
+        encoder.encode_arg(&self.capabilities)?;
+        encoder.encode(writer)
+    }
+}
+
+impl Declare {
+    /// Convert to the value sent as the body of a declare message.
+    pub fn to_value(&self) -> Result<Value> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf)?;
+        decode_value(&mut &buf[..])
+    }
+}
+
+impl Encoder for Declare {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_DECLARE);
+        encoder.encode_arg(&self.global_id)?;
+        encoder.encode(writer)
+    }
+}
+
+impl TryFromValue for Declare {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Described(descriptor, mut body) if *descriptor == DESC_DECLARE => {
+                let mut decoder = FrameDecoder::new(&descriptor, &mut body)?;
+                let mut declare = Declare { global_id: None };
+                decoder.decode_optional(&mut declare.global_id)?;
+                Ok(declare)
+            }
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to Declare",
+            ))),
+        }
+    }
+}
+
+impl Discharge {
+    /// Convert to the value sent as the body of a discharge message.
+    pub fn to_value(&self) -> Result<Value> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf)?;
+        decode_value(&mut &buf[..])
+    }
+}
+
+impl Encoder for Discharge {
+    fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
+        let mut encoder = FrameEncoder::new(DESC_DISCHARGE);
+        encoder.encode_arg(&self.txn_id)?;
+        encoder.encode_arg(&self.fail)?;
+        encoder.encode(writer)
+    }
+}
+
+impl TryFromValue for Discharge {
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Described(descriptor, mut body) if *descriptor == DESC_DISCHARGE => {
+                let mut decoder = FrameDecoder::new(&descriptor, &mut body)?;
+                let mut discharge = Discharge {
+                    txn_id: Vec::new(),
+                    fail: None,
+                };
+                decoder.decode_required(&mut discharge.txn_id)?;
+                decoder.decode_optional(&mut discharge.fail)?;
+                Ok(discharge)
+            }
+            _ => Err(AmqpError::decode_error(Some(
+                "Error converting value to Discharge",
+            ))),
+        }
+    }
+}
+
+impl DeliveryState {
+    /// Returns true if the state is an outcome, meaning that the delivery has been processed.
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            DeliveryState::Received(_) => false,
+            DeliveryState::Transactional(state) => state.outcome.is_some(),
+            _ => true,
+        }
+    }
+
+    /// Convert the state to the described value representation used in unsettled maps.
+    pub fn to_value(&self) -> Result<Value> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf)?;
+        decode_value(&mut &buf[..])
+    }
+}
+
 impl Encoder for DeliveryState {
     fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
         let value = match self {
@@ -870,6 +1194,17 @@ impl Encoder for DeliveryState {
                 encoder.encode_arg(&modified.message_annotations)?;
                 encoder
             }
+            DeliveryState::Declared(declared) => {
+                let mut encoder = FrameEncoder::new(DESC_DECLARED);
+                encoder.encode_arg(&declared.txn_id)?;
+                encoder
+            }
+            DeliveryState::Transactional(state) => {
+                let mut encoder = FrameEncoder::new(DESC_TRANSACTIONAL_STATE);
+                encoder.encode_arg(&state.txn_id)?;
+                encoder.encode_arg(&state.outcome.as_deref().cloned())?;
+                encoder
+            }
         };
         value.encode(writer)
     }
@@ -907,6 +1242,22 @@ impl TryFromValue for DeliveryState {
                     decoder.decode_optional(&mut modified.message_annotations)?;
                     Ok(DeliveryState::Modified(modified))
                 }
+                DESC_DECLARED => {
+                    let mut declared = Declared { txn_id: Vec::new() };
+                    decoder.decode_required(&mut declared.txn_id)?;
+                    Ok(DeliveryState::Declared(declared))
+                }
+                DESC_TRANSACTIONAL_STATE => {
+                    let mut state = TransactionalState {
+                        txn_id: Vec::new(),
+                        outcome: None,
+                    };
+                    let mut outcome: Option<DeliveryState> = None;
+                    decoder.decode_required(&mut state.txn_id)?;
+                    decoder.decode_optional(&mut outcome)?;
+                    state.outcome = outcome.map(Box::new);
+                    Ok(DeliveryState::Transactional(state))
+                }
                 _ => Err(AmqpError::decode_error(Some(
                     "Error converting value to DeliveryState",
                 ))),
@@ -1007,9 +1358,9 @@ impl TryFromValue for LinkRole {
     fn try_from(value: Value) -> Result<Self> {
         match value {
             Value::Bool(value) => Ok(if value {
-                LinkRole::Sender
-            } else {
                 LinkRole::Receiver
+            } else {
+                LinkRole::Sender
             }),
             _ => Err(AmqpError::decode_error(Some(
                 "Error converting value to LinkRole",
@@ -1107,18 +1458,36 @@ impl Encoder for Vec<Outcome> {
 }
 
 impl Outcome {
+    /// The symbolic descriptor of the outcome, used when listing supported outcomes.
     pub fn to_str(&self) -> &'static str {
         match *self {
-            Outcome::Accepted => "Accepted",
-            Outcome::Rejected => "Rejected",
-            Outcome::Released => "Released",
-            Outcome::Modified => "Modified",
+            Outcome::Accepted => "amqp:accepted:list",
+            Outcome::Rejected => "amqp:rejected:list",
+            Outcome::Released => "amqp:released:list",
+            Outcome::Modified => "amqp:modified:list",
         }
     }
 }
+
+// A single outcome, such as the default outcome of a source, is encoded as the outcome itself.
 impl Encoder for Outcome {
     fn encode(&self, writer: &mut dyn Write) -> Result<TypeCode> {
-        ValueRef::SymbolRef(self.to_str()).encode(writer)
+        let descriptor = match *self {
+            Outcome::Accepted => DESC_DELIVERY_STATE_ACCEPTED,
+            Outcome::Rejected => DESC_DELIVERY_STATE_REJECTED,
+            Outcome::Released => DESC_DELIVERY_STATE_RELEASED,
+            Outcome::Modified => DESC_DELIVERY_STATE_MODIFIED,
+        };
+        FrameEncoder::new(descriptor).encode(writer)
+    }
+}
+
+impl DistributionMode {
+    pub fn to_str(&self) -> &'static str {
+        match *self {
+            DistributionMode::Move => "move",
+            DistributionMode::Copy => "copy",
+        }
     }
 }
 
@@ -1228,13 +1597,21 @@ impl Frame {
             Frame::SASL(sasl_frame) => {
                 header.frame_type = 1;
                 match sasl_frame {
-                    SaslFrame::SaslMechanisms(_) => {}
+                    SaslFrame::SaslMechanisms(mechanisms) => {
+                        mechanisms.encode(&mut buf)?;
+                    }
                     SaslFrame::SaslInit(init) => {
                         init.encode(&mut buf)?;
                     }
-                    SaslFrame::SaslChallenge(_) => {}
-                    SaslFrame::SaslResponse(_) => {}
-                    SaslFrame::SaslOutcome(_) => {}
+                    SaslFrame::SaslChallenge(challenge) => {
+                        challenge.encode(&mut buf)?;
+                    }
+                    SaslFrame::SaslResponse(response) => {
+                        response.encode(&mut buf)?;
+                    }
+                    SaslFrame::SaslOutcome(outcome) => {
+                        outcome.encode(&mut buf)?;
+                    }
                 }
             }
         }
@@ -1328,6 +1705,13 @@ impl Frame {
                         DESC_SASL_MECHANISMS => {
                             Some(SaslFrame::SaslMechanisms(SaslMechanisms::decode(decoder)?))
                         }
+                        DESC_SASL_INIT => Some(SaslFrame::SaslInit(SaslInit::decode(decoder)?)),
+                        DESC_SASL_CHALLENGE => {
+                            Some(SaslFrame::SaslChallenge(SaslChallenge::decode(decoder)?))
+                        }
+                        DESC_SASL_RESPONSE => {
+                            Some(SaslFrame::SaslResponse(SaslResponse::decode(decoder)?))
+                        }
                         DESC_SASL_OUTCOME => {
                             Some(SaslFrame::SaslOutcome(SaslOutcome::decode(decoder)?))
                         }
@@ -1373,4 +1757,42 @@ mod tests {
         assert_eq!(None, frm.max_frame_size);
         assert_eq!(None, frm.channel_max);
     }
+
+    #[test]
+    fn transactional_state() {
+        let state = DeliveryState::Transactional(TransactionalState {
+            txn_id: vec![1, 2, 3],
+            outcome: Some(Box::new(DeliveryState::Accepted)),
+        });
+        let value = state.to_value().unwrap();
+        assert_eq!(state, DeliveryState::try_from(value).unwrap());
+        assert!(state.is_terminal());
+
+        let state = DeliveryState::Transactional(TransactionalState {
+            txn_id: vec![1, 2, 3],
+            outcome: None,
+        });
+        let value = state.to_value().unwrap();
+        assert_eq!(state, DeliveryState::try_from(value).unwrap());
+        assert!(!state.is_terminal());
+    }
+
+    #[test]
+    fn coordinator_target() {
+        let attach = Attach::new("txn", 0, LinkRole::Sender)
+            .coordinator(Coordinator::new(&[txn_capability::LOCAL_TRANSACTIONS]));
+        let mut buf = Vec::new();
+        attach.target.encode(&mut buf).unwrap();
+        let target: Option<LinkTarget> =
+            TryFromValue::try_from(decode_value(&mut &buf[..]).unwrap()).unwrap();
+        match target {
+            Some(LinkTarget::Coordinator(coordinator)) => assert_eq!(
+                Some(vec![Symbol::from_string(
+                    txn_capability::LOCAL_TRANSACTIONS
+                )]),
+                coordinator.capabilities
+            ),
+            target => panic!("unexpected target {:?}", target),
+        }
+    }
 }
@@ -9,6 +9,7 @@ use byteorder::WriteBytesExt;
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::io::Write;
+use std::time::SystemTime;
 use std::vec::Vec;
 
 use crate::decoding::*;
@@ -47,8 +48,8 @@ pub struct MessageProperties {
     pub correlation_id: Option<Value>,
     pub content_type: Option<Symbol>,
     pub content_encoding: Option<Symbol>,
-    pub absolute_expiry_time: Option<u64>,
-    pub creation_time: Option<u64>,
+    pub absolute_expiry_time: Option<SystemTime>,
+    pub creation_time: Option<SystemTime>,
     pub group_id: Option<String>,
     pub group_sequence: Option<u32>,
     pub reply_to_group_id: Option<String>,
@@ -281,3 +282,45 @@ impl MessageBody {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    #[test]
+    fn properties_roundtrip() {
+        let id = Uuid::new_v4();
+        let created = UNIX_EPOCH + Duration::from_millis(1_311_704_463_521);
+        let mut message = Message::amqp_value(Value::Double(F64(3.5)));
+        message.properties = Some(MessageProperties {
+            message_id: Some(Value::Uuid(id)),
+            user_id: None,
+            to: None,
+            subject: None,
+            reply_to: None,
+            correlation_id: None,
+            content_type: None,
+            content_encoding: None,
+            absolute_expiry_time: None,
+            creation_time: Some(created),
+            group_id: None,
+            group_sequence: None,
+            reply_to_group_id: None,
+        });
+
+        let mut data = Vec::new();
+        message.encode(&mut data).unwrap();
+        let decoded = Message::decode(&mut data).unwrap();
+
+        let properties = decoded.properties.unwrap();
+        assert_eq!(Some(Value::Uuid(id)), properties.message_id);
+        assert_eq!(Some(created), properties.creation_time);
+        match decoded.body {
+            MessageBody::AmqpValue(value) => assert_eq!(Value::Double(F64(3.5)), value),
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+}
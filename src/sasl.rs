@@ -5,7 +5,13 @@
 
 //! The sasl module implements the SASL support in dove.
 
+use hmac::{Hmac, Mac, NewMac};
 use log::trace;
+use md5::Md5;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
 use std::str::FromStr;
 
 use crate::error::*;
@@ -21,19 +27,167 @@ pub struct Sasl {
 #[derive(Debug)]
 pub enum SaslRole {
     Server(SaslServer),
-    Client(SaslClient),
+    Client(Box<dyn SaslMechanismProvider>),
 }
 
-#[derive(Debug)]
+/**
+ * Trait implementing the client side of a SASL mechanism. A provider picks the mechanism to use
+ * from those advertised by the server, and produces the data sent to the server during the
+ * exchange. The built-in mechanisms are provided by SaslClient.
+ */
+pub trait SaslMechanismProvider: SaslMechanismProviderClone + Debug + Send {
+    /// Select a mechanism from the list advertised by the server, or None if none are usable.
+    fn select(&mut self, offered: &[SaslMechanism]) -> Option<SaslMechanism>;
+
+    /// The initial response sent with the sasl-init frame for the selected mechanism.
+    fn initial_response(&mut self, mechanism: &SaslMechanism) -> Result<Option<Vec<u8>>>;
+
+    /// The response to a sasl-challenge frame.
+    fn challenge(&mut self, _challenge: &[u8]) -> Result<Vec<u8>> {
+        Err(AmqpError::sasl("unexpected SASL challenge"))
+    }
+
+    /// Verify additional data sent with a successful sasl-outcome frame.
+    fn outcome(&mut self, _additional_data: Option<&[u8]>) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub trait SaslMechanismProviderClone {
+    fn clone_box(&self) -> Box<dyn SaslMechanismProvider>;
+}
+
+impl<T: 'static + SaslMechanismProvider + Clone> SaslMechanismProviderClone for T {
+    fn clone_box(&self) -> Box<dyn SaslMechanismProvider> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn SaslMechanismProvider> {
+    fn clone(&self) -> Box<dyn SaslMechanismProvider> {
+        self.clone_box()
+    }
+}
+
+/**
+ * Provider for the built-in mechanisms, using the credentials given in the connection options.
+ */
+#[derive(Debug, Clone)]
 pub struct SaslClient {
-    pub mechanism: SaslMechanism,
+    /// Mechanisms in order of preference. If empty, the strongest mechanism usable with the
+    /// given credentials is selected.
+    pub mechanisms: Vec<SaslMechanism>,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Bearer token used by the XOAUTH2 and OAUTHBEARER mechanisms.
+    pub token: Option<String>,
+    mechanism: Option<SaslMechanism>,
+    scram: Option<ScramClient>,
 }
 
 #[derive(Debug)]
 pub struct SaslServer {
-    supported_mechanisms: Vec<SaslMechanism>,
+    pub supported_mechanisms: Vec<SaslMechanism>,
+    pub authenticator: Box<dyn SaslAuthenticator>,
+    mechanisms_sent: bool,
+}
+
+/**
+ * The result of a single step in a server side SASL exchange. Either a challenge that is sent to
+ * the client, or the final outcome of the exchange.
+ */
+#[derive(Debug, PartialEq)]
+pub enum SaslStep {
+    Challenge(Vec<u8>),
+    Outcome(SaslCode),
+}
+
+/**
+ * Trait for checking the credentials presented by a client during server side SASL negotiation.
+ * An authenticator instance is cloned for each accepted connection, so it may keep state
+ * across challenge/response rounds.
+ *
+ * The default implementation of init handles the PLAIN and ANONYMOUS mechanisms by
+ * delegating to plain and anonymous, which deny access unless overridden.
+ */
+pub trait SaslAuthenticator: SaslAuthenticatorClone + Debug + Send {
+    /// Handle the sasl-init frame sent by the client.
+    fn init(
+        &mut self,
+        mechanism: SaslMechanism,
+        initial_response: Option<&[u8]>,
+        _hostname: Option<&str>,
+    ) -> SaslStep {
+        let allowed = match mechanism {
+            SaslMechanism::Anonymous => self.anonymous(),
+            SaslMechanism::Plain => match initial_response.and_then(parse_plain) {
+                Some((username, password)) => self.plain(&username, &password),
+                None => false,
+            },
+            _ => false,
+        };
+        SaslStep::Outcome(if allowed {
+            SASL_CODE_OK
+        } else {
+            SASL_CODE_AUTH
+        })
+    }
+
+    /// Handle a sasl-response frame sent by the client in reply to a challenge.
+    fn response(&mut self, _response: &[u8]) -> SaslStep {
+        SaslStep::Outcome(SASL_CODE_AUTH)
+    }
+
+    /// Check the username and password presented using the PLAIN mechanism.
+    fn plain(&mut self, _username: &str, _password: &str) -> bool {
+        false
+    }
+
+    /// Check if the ANONYMOUS mechanism is permitted.
+    fn anonymous(&mut self) -> bool {
+        false
+    }
+}
+
+pub trait SaslAuthenticatorClone {
+    fn clone_box(&self) -> Box<dyn SaslAuthenticator>;
+}
+
+impl<T: 'static + SaslAuthenticator + Clone> SaslAuthenticatorClone for T {
+    fn clone_box(&self) -> Box<dyn SaslAuthenticator> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn SaslAuthenticator> {
+    fn clone(&self) -> Box<dyn SaslAuthenticator> {
+        self.clone_box()
+    }
+}
+
+/// Parse a PLAIN initial response of the form authzid NUL authcid NUL passwd.
+fn parse_plain(data: &[u8]) -> Option<(String, String)> {
+    let mut parts = data.split(|b| *b == 0);
+    let _authzid = parts.next()?;
+    let username = std::str::from_utf8(parts.next()?).ok()?;
+    let password = std::str::from_utf8(parts.next()?).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((username.to_string(), password.to_string()))
+}
+
+impl SaslServer {
+    pub fn new(
+        supported_mechanisms: Vec<SaslMechanism>,
+        authenticator: Box<dyn SaslAuthenticator>,
+    ) -> SaslServer {
+        SaslServer {
+            supported_mechanisms,
+            authenticator,
+            mechanisms_sent: false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -43,13 +197,18 @@ pub enum SaslState {
     Failed,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum SaslMechanism {
     Anonymous,
     Plain,
+    External,
     CramMd5,
     ScramSha1,
     ScramSha256,
+    XOAuth2,
+    OAuthBearer,
+    /// A mechanism not known by dove, handled by a custom SaslMechanismProvider.
+    Other(String),
 }
 
 impl FromStr for SaslMechanism {
@@ -59,12 +218,18 @@ impl FromStr for SaslMechanism {
             Ok(SaslMechanism::Anonymous)
         } else if "plain".eq_ignore_ascii_case(s) {
             Ok(SaslMechanism::Plain)
+        } else if "external".eq_ignore_ascii_case(s) {
+            Ok(SaslMechanism::External)
         } else if "cram-md5".eq_ignore_ascii_case(s) {
             Ok(SaslMechanism::CramMd5)
         } else if "scram-sha-1".eq_ignore_ascii_case(s) {
             Ok(SaslMechanism::ScramSha1)
         } else if "scram-sha-256".eq_ignore_ascii_case(s) {
             Ok(SaslMechanism::ScramSha256)
+        } else if "xoauth2".eq_ignore_ascii_case(s) {
+            Ok(SaslMechanism::XOAuth2)
+        } else if "oauthbearer".eq_ignore_ascii_case(s) {
+            Ok(SaslMechanism::OAuthBearer)
         } else {
             Err(AmqpError::decode_error(Some(
                 format!("Unknown SASL mechanism {}", s).as_str(),
@@ -75,12 +240,350 @@ impl FromStr for SaslMechanism {
 
 impl ToString for SaslMechanism {
     fn to_string(&self) -> String {
+        self.to_str().to_string()
+    }
+}
+
+impl SaslClient {
+    pub fn new(
+        mechanisms: Vec<SaslMechanism>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> SaslClient {
+        SaslClient {
+            mechanisms,
+            username,
+            password,
+            token: None,
+            mechanism: None,
+            scram: None,
+        }
+    }
+
+    /// The built-in mechanisms usable with the configured credentials, strongest first.
+    /// EXTERNAL is never selected automatically.
+    pub fn usable_mechanisms(&self) -> Vec<SaslMechanism> {
+        let mut mechanisms = Vec::new();
+        if self.token.is_some() {
+            mechanisms.push(SaslMechanism::OAuthBearer);
+            mechanisms.push(SaslMechanism::XOAuth2);
+        }
+        if self.username.is_some() && self.password.is_some() {
+            mechanisms.push(SaslMechanism::ScramSha256);
+            mechanisms.push(SaslMechanism::ScramSha1);
+            mechanisms.push(SaslMechanism::CramMd5);
+            mechanisms.push(SaslMechanism::Plain);
+        }
+        if self.username.is_none() && self.password.is_none() && self.token.is_none() {
+            mechanisms.push(SaslMechanism::Anonymous);
+        }
+        mechanisms
+    }
+
+    fn token(&self, mechanism: &SaslMechanism) -> Result<&str> {
+        self.token.as_deref().ok_or_else(|| {
+            AmqpError::sasl(format!("token required for SASL {}", mechanism.to_str()).as_str())
+        })
+    }
+
+    fn credentials(&self, mechanism: &SaslMechanism) -> Result<(&str, &str)> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Ok((username, password)),
+            _ => Err(AmqpError::sasl(
+                format!(
+                    "username and password required for SASL {}",
+                    mechanism.to_str()
+                )
+                .as_str(),
+            )),
+        }
+    }
+}
+
+impl SaslMechanismProvider for SaslClient {
+    fn select(&mut self, offered: &[SaslMechanism]) -> Option<SaslMechanism> {
+        let candidates = if self.mechanisms.is_empty() {
+            self.usable_mechanisms()
+        } else {
+            self.mechanisms.clone()
+        };
+        candidates.into_iter().find(|m| offered.contains(m))
+    }
+
+    fn initial_response(&mut self, mechanism: &SaslMechanism) -> Result<Option<Vec<u8>>> {
+        self.mechanism = Some(mechanism.clone());
+        match mechanism {
+            SaslMechanism::Plain => {
+                let (username, password) = self.credentials(mechanism)?;
+                let mut data = Vec::new();
+                data.extend_from_slice(username.as_bytes());
+                data.push(0);
+                data.extend_from_slice(username.as_bytes());
+                data.push(0);
+                data.extend_from_slice(password.as_bytes());
+                Ok(Some(data))
+            }
+            SaslMechanism::External => {
+                // The identity is established by the TLS client certificate, an
+                // authorization identity may be given as the username.
+                Ok(Some(self.username.clone().unwrap_or_default().into_bytes()))
+            }
+            SaslMechanism::ScramSha1 | SaslMechanism::ScramSha256 => {
+                let hash = if *mechanism == SaslMechanism::ScramSha1 {
+                    ScramHash::Sha1
+                } else {
+                    ScramHash::Sha256
+                };
+                let (username, password) = self.credentials(mechanism)?;
+                let mut scram = ScramClient::new(hash, username, password, &scram_nonce());
+                let data = scram.client_first();
+                self.scram = Some(scram);
+                Ok(Some(data))
+            }
+            SaslMechanism::XOAuth2 => {
+                let token = self.token(mechanism)?;
+                Ok(Some(
+                    format!(
+                        "user={}\x01auth=Bearer {}\x01\x01",
+                        self.username.as_deref().unwrap_or_default(),
+                        token
+                    )
+                    .into_bytes(),
+                ))
+            }
+            SaslMechanism::OAuthBearer => {
+                let token = self.token(mechanism)?;
+                let authzid = match &self.username {
+                    Some(username) => format!("a={}", scram_username(username)),
+                    None => String::new(),
+                };
+                Ok(Some(
+                    format!("n,{},\x01auth=Bearer {}\x01\x01", authzid, token).into_bytes(),
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        match &self.mechanism {
+            Some(SaslMechanism::CramMd5) => {
+                let (username, password) = self.credentials(&SaslMechanism::CramMd5)?;
+                Ok(cram_md5_response(username, password, challenge).into_bytes())
+            }
+            // A challenge carries the error status of a failed token, the client must
+            // answer with a dummy response after which the server sends the outcome.
+            Some(SaslMechanism::XOAuth2) => Ok(Vec::new()),
+            Some(SaslMechanism::OAuthBearer) => Ok(vec![0x01]),
+            _ => match &mut self.scram {
+                Some(scram) => scram.challenge(challenge),
+                None => Err(AmqpError::sasl("unexpected SASL challenge")),
+            },
+        }
+    }
+
+    fn outcome(&mut self, additional_data: Option<&[u8]>) -> Result<()> {
+        match &mut self.scram {
+            Some(scram) => scram.outcome(additional_data),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    pub(crate) fn hash(self, data: &[u8]) -> Vec<u8> {
         match self {
-            SaslMechanism::Anonymous => "ANONYMOUS".to_string(),
-            SaslMechanism::Plain => "PLAIN".to_string(),
-            _ => panic!("Unsupported mechanism!"),
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
         }
     }
+
+    pub(crate) fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts any key length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// The Hi function (PBKDF2 with HMAC as the pseudorandom function) from RFC 5802.
+    pub(crate) fn salted_password(self, password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&1u32.to_be_bytes());
+        let mut u = self.hmac(password.as_bytes(), &input);
+        let mut result = u.clone();
+        for _ in 1..iterations {
+            u = self.hmac(password.as_bytes(), &u);
+            for (r, x) in result.iter_mut().zip(u.iter()) {
+                *r ^= x;
+            }
+        }
+        result
+    }
+}
+
+/// The CRAM-MD5 response described in RFC 2195.
+pub(crate) fn cram_md5_response(username: &str, password: &str, challenge: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Md5>::new_varkey(password.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(challenge);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{} {}", username, digest)
+}
+
+fn scram_nonce() -> String {
+    let nonce: [u8; 24] = rand::thread_rng().gen();
+    base64::encode(nonce)
+}
+
+/// Escape a username as required by RFC 5802.
+fn scram_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ScramState {
+    Initial,
+    ClientFirstSent,
+    ClientFinalSent,
+    Done,
+}
+
+/**
+ * Client side of the SCRAM exchange described in RFC 5802, including verification of the server
+ * signature.
+ */
+#[derive(Debug, Clone)]
+struct ScramClient {
+    hash: ScramHash,
+    password: String,
+    client_first_bare: String,
+    nonce: String,
+    server_signature: Vec<u8>,
+    state: ScramState,
+}
+
+impl ScramClient {
+    fn new(hash: ScramHash, username: &str, password: &str, nonce: &str) -> ScramClient {
+        ScramClient {
+            hash,
+            password: password.to_string(),
+            client_first_bare: format!("n={},r={}", scram_username(username), nonce),
+            nonce: nonce.to_string(),
+            server_signature: Vec::new(),
+            state: ScramState::Initial,
+        }
+    }
+
+    fn client_first(&mut self) -> Vec<u8> {
+        self.state = ScramState::ClientFirstSent;
+        format!("n,,{}", self.client_first_bare).into_bytes()
+    }
+
+    fn challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        match self.state {
+            ScramState::ClientFirstSent => self.client_final(challenge),
+            ScramState::ClientFinalSent => {
+                self.verify_server_final(challenge)?;
+                Ok(Vec::new())
+            }
+            _ => Err(AmqpError::sasl("unexpected SCRAM challenge")),
+        }
+    }
+
+    fn outcome(&mut self, additional_data: Option<&[u8]>) -> Result<()> {
+        match (&self.state, additional_data) {
+            (ScramState::Done, _) => Ok(()),
+            (ScramState::ClientFinalSent, Some(data)) => self.verify_server_final(data),
+            _ => Err(AmqpError::sasl("server signature not received")),
+        }
+    }
+
+    fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>> {
+        let server_first = std::str::from_utf8(server_first)?;
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attr in server_first.split(',') {
+            if let Some(v) = attr.strip_prefix("r=") {
+                nonce = Some(v);
+            } else if let Some(v) = attr.strip_prefix("s=") {
+                salt = Some(base64::decode(v).map_err(|_| AmqpError::sasl("invalid SCRAM salt"))?);
+            } else if let Some(v) = attr.strip_prefix("i=") {
+                iterations = Some(v.parse::<u32>()?);
+            }
+        }
+
+        let nonce = match nonce {
+            Some(nonce) if nonce.starts_with(&self.nonce) => nonce,
+            _ => return Err(AmqpError::sasl("invalid SCRAM server nonce")),
+        };
+        let (salt, iterations) = match (salt, iterations) {
+            (Some(salt), Some(iterations)) if iterations > 0 => (salt, iterations),
+            _ => return Err(AmqpError::sasl("invalid SCRAM server-first message")),
+        };
+
+        // Channel binding is not supported, "biws" is the base64 encoding of the gs2 header "n,,"
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+
+        let salted_password = self.hash.salted_password(&self.password, &salt, iterations);
+        let client_key = self.hash.hmac(&salted_password, b"Client Key");
+        let stored_key = self.hash.hash(&client_key);
+        let client_signature = self.hash.hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(k, s)| k ^ s)
+            .collect();
+
+        let server_key = self.hash.hmac(&salted_password, b"Server Key");
+        self.server_signature = self.hash.hmac(&server_key, auth_message.as_bytes());
+        self.state = ScramState::ClientFinalSent;
+
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            base64::encode(&proof)
+        )
+        .into_bytes())
+    }
+
+    fn verify_server_final(&mut self, server_final: &[u8]) -> Result<()> {
+        let server_final = std::str::from_utf8(server_final)?;
+        let verifier = server_final
+            .split(',')
+            .find_map(|attr| attr.strip_prefix("v="))
+            .ok_or_else(|| AmqpError::sasl("SCRAM server signature missing"))?;
+        let signature = base64::decode(verifier)
+            .map_err(|_| AmqpError::sasl("invalid SCRAM server signature"))?;
+        if signature != self.server_signature {
+            return Err(AmqpError::sasl("SCRAM server signature mismatch"));
+        }
+        self.state = ScramState::Done;
+        Ok(())
+    }
 }
 
 impl Sasl {
@@ -93,62 +596,273 @@ impl Sasl {
         hostname: Option<&str>,
         transport: &mut Transport<N>,
     ) -> Result<()> {
-        match &self.role {
-            SaslRole::Client(sasl_client) => {
+        match &mut self.role {
+            SaslRole::Client(provider) => {
                 let frame = transport.read_frame()?;
                 match frame {
                     Frame::SASL(SaslFrame::SaslMechanisms(mechs)) => {
-                        trace!(
-                            "Got mechs {:?}, we want: {:?}!",
-                            mechs,
-                            sasl_client.mechanism
-                        );
-                        let mut found = false;
-                        for supported_mech in mechs.mechanisms.iter() {
-                            if sasl_client.mechanism == *supported_mech {
-                                found = true;
+                        trace!("Got mechs {:?}", mechs);
+                        match provider.select(&mechs.mechanisms) {
+                            Some(mechanism) => {
+                                let initial_response = provider.initial_response(&mechanism)?;
+                                let init = Frame::SASL(SaslFrame::SaslInit(SaslInit {
+                                    mechanism,
+                                    initial_response,
+                                    hostname: hostname.map(|s| s.to_string()),
+                                }));
+                                transport.write_frame(&init)?;
+                            }
+                            None => {
+                                self.state = SaslState::Failed;
+                                return Err(AmqpError::sasl(
+                                    format!(
+                                        "no usable SASL mechanism, server offered {:?}",
+                                        mechs.mechanisms
+                                    )
+                                    .as_str(),
+                                ));
                             }
                         }
-                        if !found {
-                            self.state = SaslState::Failed;
-                        } else {
-                            let mut initial_response = None;
-                            if sasl_client.mechanism == SaslMechanism::Plain {
-                                let mut data = Vec::new();
-                                data.extend_from_slice(
-                                    sasl_client.username.clone().unwrap().as_bytes(),
-                                );
-                                data.push(0);
-                                data.extend_from_slice(
-                                    sasl_client.username.clone().unwrap().as_bytes(),
-                                );
-                                data.push(0);
-                                data.extend_from_slice(
-                                    sasl_client.password.clone().unwrap().as_bytes(),
-                                );
-                                initial_response = Some(data);
+                    }
+                    Frame::SASL(SaslFrame::SaslChallenge(challenge)) => {
+                        trace!("Sasl challenge {:?}", challenge);
+                        match provider.challenge(&challenge.challenge) {
+                            Ok(response) => {
+                                let frame =
+                                    Frame::SASL(SaslFrame::SaslResponse(SaslResponse { response }));
+                                transport.write_frame(&frame)?;
+                            }
+                            Err(e) => {
+                                self.state = SaslState::Failed;
+                                return Err(AmqpError::Sasl {
+                                    code: None,
+                                    description: "SASL challenge failed".to_string(),
+                                    source: Some(Box::new(e)),
+                                });
                             }
-                            let init = Frame::SASL(SaslFrame::SaslInit(SaslInit {
-                                mechanism: sasl_client.mechanism,
-                                initial_response,
-                                hostname: hostname.map(|s| s.to_string()),
-                            }));
-                            transport.write_frame(&init)?;
                         }
                     }
                     Frame::SASL(SaslFrame::SaslOutcome(outcome)) => {
                         trace!("Sasl outcome {:?}", outcome);
-                        if outcome.code == 0 {
-                            self.state = SaslState::Success;
-                        } else {
+                        if outcome.code != SASL_CODE_OK {
                             self.state = SaslState::Failed;
+                            return Err(AmqpError::Sasl {
+                                code: Some(outcome.code),
+                                description: format!(
+                                    "authentication failed with code {}",
+                                    outcome.code
+                                ),
+                                source: None,
+                            });
+                        }
+                        match provider.outcome(outcome.additional_data.as_deref()) {
+                            Ok(_) => self.state = SaslState::Success,
+                            Err(e) => {
+                                self.state = SaslState::Failed;
+                                return Err(AmqpError::Sasl {
+                                    code: Some(outcome.code),
+                                    description: "server verification failed".to_string(),
+                                    source: Some(Box::new(e)),
+                                });
+                            }
                         }
                     }
                     _ => trace!("Got frame {:?}", frame),
                 }
             }
-            SaslRole::Server(_) => {}
+            SaslRole::Server(sasl_server) => {
+                if !sasl_server.mechanisms_sent {
+                    let mechs = Frame::SASL(SaslFrame::SaslMechanisms(SaslMechanisms {
+                        mechanisms: sasl_server.supported_mechanisms.clone(),
+                    }));
+                    transport.write_frame(&mechs)?;
+                    sasl_server.mechanisms_sent = true;
+                    return Ok(());
+                }
+
+                let frame = transport.read_frame()?;
+                let step = match frame {
+                    Frame::SASL(SaslFrame::SaslInit(init)) => {
+                        trace!("Got sasl init for mechanism {:?}", init.mechanism);
+                        if sasl_server.supported_mechanisms.contains(&init.mechanism) {
+                            sasl_server.authenticator.init(
+                                init.mechanism.clone(),
+                                init.initial_response.as_deref(),
+                                init.hostname.as_deref(),
+                            )
+                        } else {
+                            SaslStep::Outcome(SASL_CODE_AUTH)
+                        }
+                    }
+                    Frame::SASL(SaslFrame::SaslResponse(response)) => {
+                        sasl_server.authenticator.response(&response.response)
+                    }
+                    _ => {
+                        trace!("Got frame {:?}", frame);
+                        return Ok(());
+                    }
+                };
+
+                match step {
+                    SaslStep::Challenge(challenge) => {
+                        let frame =
+                            Frame::SASL(SaslFrame::SaslChallenge(SaslChallenge { challenge }));
+                        transport.write_frame(&frame)?;
+                    }
+                    SaslStep::Outcome(code) => {
+                        trace!("Sasl outcome {:?}", code);
+                        let frame = Frame::SASL(SaslFrame::SaslOutcome(SaslOutcome {
+                            code,
+                            additional_data: None,
+                        }));
+                        transport.write_frame(&frame)?;
+                        self.state = if code == SASL_CODE_OK {
+                            SaslState::Success
+                        } else {
+                            SaslState::Failed
+                        };
+                    }
+                }
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn scram_vector(
+        hash: ScramHash,
+        nonce: &str,
+        server_first: &str,
+        client_final: &str,
+        server_final: &str,
+    ) {
+        let mut client = ScramClient::new(hash, "user", "pencil", nonce);
+        assert_eq!(
+            format!("n,,n=user,r={}", nonce).into_bytes(),
+            client.client_first()
+        );
+        let response = client.challenge(server_first.as_bytes()).unwrap();
+        assert_eq!(client_final, std::str::from_utf8(&response).unwrap());
+        assert!(client.outcome(Some(server_final.as_bytes())).is_ok());
+    }
+
+    #[test]
+    fn scram_sha1_rfc5802() {
+        scram_vector(
+            ScramHash::Sha1,
+            "fyko+d2lbbFgONRv9qkxdawL",
+            "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+            "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+        );
+    }
+
+    #[test]
+    fn scram_sha256_rfc7677() {
+        scram_vector(
+            ScramHash::Sha256,
+            "rOprNGfwEbeRWgbNEkqO",
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+        );
+    }
+
+    #[test]
+    fn scram_rejects_bad_server() {
+        let nonce = "fyko+d2lbbFgONRv9qkxdawL";
+        let mut client = ScramClient::new(ScramHash::Sha1, "user", "pencil", nonce);
+        client.client_first();
+        assert!(client
+            .challenge(b"r=othernonce,s=QSXCR+Q6sek8bf92,i=4096")
+            .is_err());
+
+        let mut client = ScramClient::new(ScramHash::Sha1, "user", "pencil", nonce);
+        client.client_first();
+        client
+            .challenge(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096")
+            .unwrap();
+        assert!(client
+            .outcome(Some(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAA="))
+            .is_err());
+        assert!(client.outcome(None).is_err());
+    }
+
+    #[test]
+    fn cram_md5_rfc2195() {
+        assert_eq!(
+            "tim b913a602c7eda7a495b4e6e7334d3890",
+            cram_md5_response(
+                "tim",
+                "tanstaaftanstaaf",
+                b"<1896.697170952@postoffice.reston.mci.net>"
+            )
+        );
+    }
+
+    #[test]
+    fn oauth_initial_response() {
+        let mut client = SaslClient::new(
+            vec![SaslMechanism::XOAuth2],
+            Some("someuser@example.com".to_string()),
+            None,
+        );
+        client.token = Some("ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg".to_string());
+        assert_eq!(
+            b"user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01".to_vec(),
+            client.initial_response(&SaslMechanism::XOAuth2).unwrap().unwrap()
+        );
+
+        let mut client = SaslClient::new(
+            vec![SaslMechanism::OAuthBearer],
+            Some("user@example.com".to_string()),
+            None,
+        );
+        client.token = Some("vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==".to_string());
+        assert_eq!(
+            b"n,a=user@example.com,\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01".to_vec(),
+            client.initial_response(&SaslMechanism::OAuthBearer).unwrap().unwrap()
+        );
+
+        let mut client = SaslClient::new(vec![SaslMechanism::OAuthBearer], None, None);
+        assert!(client
+            .initial_response(&SaslMechanism::OAuthBearer)
+            .is_err());
+    }
+
+    #[test]
+    fn select_mechanism() {
+        let offered = vec![
+            SaslMechanism::Anonymous,
+            SaslMechanism::Plain,
+            SaslMechanism::ScramSha1,
+        ];
+
+        let mut client = SaslClient::new(Vec::new(), None, None);
+        assert_eq!(Some(SaslMechanism::Anonymous), client.select(&offered));
+
+        let mut client = SaslClient::new(Vec::new(), Some("u".to_string()), Some("p".to_string()));
+        assert_eq!(Some(SaslMechanism::ScramSha1), client.select(&offered));
+
+        let mut client = SaslClient::new(
+            vec![SaslMechanism::CramMd5, SaslMechanism::Plain],
+            Some("u".to_string()),
+            Some("p".to_string()),
+        );
+        assert_eq!(Some(SaslMechanism::Plain), client.select(&offered));
+
+        let mut client = SaslClient::new(Vec::new(), None, None);
+        client.token = Some("token".to_string());
+        assert_eq!(None, client.select(&offered));
+    }
+
+    #[test]
+    fn scram_escapes_username() {
+        assert_eq!("a=3Db=2Cc", scram_username("a=b,c"));
+    }
+}
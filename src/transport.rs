@@ -76,11 +76,18 @@ impl ProtocolHeader {
     }
 }
 
-const BUFFER_SIZE: usize = 1024;
+/// Size of the frame header that precedes the frame body.
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+/// The smallest max frame size allowed, and the limit used before it has been negotiated.
+pub const MIN_MAX_FRAME_SIZE: usize = 512;
+
+/// Max frame size used when none is configured.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 65536;
 
 #[derive(Debug)]
 struct Buffer {
-    buffer: [u8; BUFFER_SIZE],
+    buffer: Vec<u8>,
     capacity: usize,
     position: usize,
 }
@@ -88,7 +95,7 @@ struct Buffer {
 impl Buffer {
     fn new(capacity: usize) -> Buffer {
         Buffer {
-            buffer: [0; BUFFER_SIZE],
+            buffer: vec![0; capacity],
             capacity,
             position: 0,
         }
@@ -101,6 +108,10 @@ impl Buffer {
     fn fill(&mut self, reader: &mut dyn Read) -> Result<&[u8]> {
         if self.position < self.capacity {
             let len = reader.read(&mut self.buffer[self.position..self.capacity])?;
+            if len == 0 {
+                // Remote end closed the connection
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
             self.position += len;
             // println!("Filled {} bytes", len);
         }
@@ -152,30 +163,90 @@ pub trait Network: Read + Write + Debug {
     fn close(&mut self) -> Result<()>;
 }
 
+/**
+ * TLS settings for a connection or listener. Certificates and keys are PEM encoded.
+ *
+ * For clients, ca_certs are the roots used to verify the server (the webpki roots are used if
+ * not set), cert/key is an optional client certificate and server_name overrides the name used
+ * for SNI and verification. As certificates are verified against DNS names, server_name must be
+ * set when connecting to an IP address. For listeners, cert/key is the server certificate, and setting
+ * ca_certs requires clients to present a certificate signed by one of them.
+ */
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub ca_certs: Option<Vec<u8>>,
+    pub cert: Option<Vec<u8>>,
+    pub key: Option<Vec<u8>>,
+    pub server_name: Option<String>,
+}
+
+impl TlsOptions {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> TlsOptions {
+        TlsOptions {
+            ca_certs: None,
+            cert: None,
+            key: None,
+            server_name: None,
+        }
+    }
+
+    pub fn ca_certs(mut self, pem: &[u8]) -> Self {
+        self.ca_certs = Some(pem.to_vec());
+        self
+    }
+
+    pub fn certificate(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Self {
+        self.cert = Some(cert_pem.to_vec());
+        self.key = Some(key_pem.to_vec());
+        self
+    }
+
+    pub fn server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+}
+
 #[derive(Debug)]
 pub struct Transport<N: Network> {
     network: N,
     incoming: Buffer,
     outgoing: Buffer,
     max_frame_size: usize,
+    remote_max_frame_size: usize,
     last_sent: Instant,
     last_received: Instant,
 }
 
 impl<N: Network> Transport<N> {
     pub fn new(network: N, max_frame_size: usize) -> Transport<N> {
-        assert!(max_frame_size <= BUFFER_SIZE);
-        assert!(max_frame_size <= BUFFER_SIZE);
         Transport {
             network,
             incoming: Buffer::new(max_frame_size),
             outgoing: Buffer::new(max_frame_size),
             max_frame_size,
+            remote_max_frame_size: MIN_MAX_FRAME_SIZE,
             last_sent: Instant::now(),
             last_received: Instant::now(),
         }
     }
 
+    /// The largest frame that may be written, the smallest of the local and remote max frame size.
+    pub fn max_frame_size(&self) -> usize {
+        std::cmp::min(self.max_frame_size, self.remote_max_frame_size)
+    }
+
+    /// The largest frame accepted by this transport.
+    pub fn local_max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Set the max frame size announced by the remote endpoint.
+    pub fn set_remote_max_frame_size(&mut self, max_frame_size: usize) {
+        self.remote_max_frame_size = std::cmp::max(max_frame_size, MIN_MAX_FRAME_SIZE);
+    }
+
     pub fn network(&mut self) -> &mut N {
         &mut self.network
     }
@@ -209,6 +280,18 @@ impl<N: Network> Transport<N> {
             if buf.len() >= 8 {
                 let header = FrameHeader::decode(&mut buf)?;
                 let frame_size = header.size as usize;
+                if frame_size > self.max_frame_size {
+                    return Err(AmqpError::amqp_error(
+                        condition::connection::FRAMING_ERROR,
+                        Some(
+                            format!(
+                                "frame size {} exceeds max frame size {}",
+                                frame_size, self.max_frame_size
+                            )
+                            .as_str(),
+                        ),
+                    ));
+                }
                 trace!(
                     "Found enough bytes for header {:?}. Buffer is {} bytes!",
                     header,
@@ -247,6 +330,7 @@ impl<N: Network> Transport<N> {
         let data = self.outgoing.written();
         let len = data.len();
         self.network.write_all(data)?;
+        self.network.flush()?;
         self.outgoing.clear();
         Ok(len)
     }
@@ -260,21 +344,223 @@ impl<N: Network> Transport<N> {
     }
 }
 
+#[cfg(feature = "tls")]
+pub mod tls {
+    use rustls::internal::pemfile;
+    use rustls::{
+        AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientSession, NoClientAuth,
+        PrivateKey, RootCertStore, ServerConfig, ServerSession, Session,
+    };
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use super::TlsOptions;
+    use crate::error::*;
+
+    fn certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+        let certs = pemfile::certs(&mut &pem[..])
+            .map_err(|_| AmqpError::generic("error parsing PEM certificates"))?;
+        if certs.is_empty() {
+            return Err(AmqpError::generic("no certificates found in PEM data"));
+        }
+        Ok(certs)
+    }
+
+    fn private_key(pem: &[u8]) -> Result<PrivateKey> {
+        let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..])
+            .map_err(|_| AmqpError::generic("error parsing PEM private key"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut &pem[..])
+                .map_err(|_| AmqpError::generic("error parsing PEM private key"))?;
+        }
+        keys.pop()
+            .ok_or_else(|| AmqpError::generic("no private key found in PEM data"))
+    }
+
+    fn root_store(pem: &[u8]) -> Result<RootCertStore> {
+        let mut store = RootCertStore::empty();
+        let (valid, _) = store
+            .add_pem_file(&mut &pem[..])
+            .map_err(|_| AmqpError::generic("error parsing PEM CA certificates"))?;
+        if valid == 0 {
+            return Err(AmqpError::generic("no valid CA certificates found"));
+        }
+        Ok(store)
+    }
+
+    pub fn client_config(opts: &TlsOptions) -> Result<ClientConfig> {
+        let mut config = ClientConfig::new();
+        match &opts.ca_certs {
+            Some(pem) => config.root_store = root_store(pem)?,
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+        if let (Some(cert), Some(key)) = (&opts.cert, &opts.key) {
+            config
+                .set_single_client_cert(certs(cert)?, private_key(key)?)
+                .map_err(|e| AmqpError::generic(e.to_string().as_str()))?;
+        }
+        Ok(config)
+    }
+
+    pub fn server_config(opts: &TlsOptions) -> Result<ServerConfig> {
+        let verifier = match &opts.ca_certs {
+            Some(pem) => AllowAnyAuthenticatedClient::new(root_store(pem)?),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        match (&opts.cert, &opts.key) {
+            (Some(cert), Some(key)) => config
+                .set_single_cert(certs(cert)?, private_key(key)?)
+                .map_err(|e| AmqpError::generic(e.to_string().as_str()))?,
+            _ => {
+                return Err(AmqpError::generic(
+                    "a certificate and private key is required to accept TLS connections",
+                ))
+            }
+        }
+        Ok(config)
+    }
+
+    /**
+     * A TLS session layered on top of a non-blocking byte stream. Reads and writes operate
+     * on plaintext, while the TLS records are exchanged with the underlying stream.
+     */
+    pub struct TlsSession {
+        session: Box<dyn Session>,
+    }
+
+    impl std::fmt::Debug for TlsSession {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("TlsSession")
+                .field("handshaking", &self.session.is_handshaking())
+                .finish()
+        }
+    }
+
+    impl TlsSession {
+        pub fn client(opts: &TlsOptions, host: &str) -> Result<TlsSession> {
+            let config = Arc::new(client_config(opts)?);
+            let name = opts.server_name.as_deref().unwrap_or(host);
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(name).map_err(|_| {
+                if name.parse::<std::net::IpAddr>().is_ok() {
+                    AmqpError::generic(
+                        format!("server_name must be set to connect to {} with TLS", name).as_str(),
+                    )
+                } else {
+                    AmqpError::generic(format!("invalid server name {}", name).as_str())
+                }
+            })?;
+            Ok(TlsSession {
+                session: Box::new(ClientSession::new(&config, dns_name)),
+            })
+        }
+
+        pub fn server(config: &Arc<ServerConfig>) -> TlsSession {
+            TlsSession {
+                session: Box::new(ServerSession::new(config)),
+            }
+        }
+
+        pub fn read<S: Read + Write>(
+            &mut self,
+            stream: &mut S,
+            buf: &mut [u8],
+        ) -> std::io::Result<usize> {
+            loop {
+                let len = self.session.read(buf)?;
+                if len > 0 {
+                    return Ok(len);
+                }
+                if self.session.read_tls(stream)? == 0 {
+                    return Ok(0);
+                }
+                self.session.process_new_packets().map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                })?;
+                // Handshake messages and alerts may need to be sent in response
+                self.flush(stream)?;
+            }
+        }
+
+        pub fn write<S: Write>(&mut self, stream: &mut S, data: &[u8]) -> std::io::Result<usize> {
+            let len = self.session.write(data)?;
+            self.flush(stream)?;
+            Ok(len)
+        }
+
+        /// Write pending TLS records. Records that cannot be written without blocking are kept
+        /// and written on the next read, write or flush.
+        pub fn flush<S: Write>(&mut self, stream: &mut S) -> std::io::Result<()> {
+            while self.session.wants_write() {
+                match self.session.write_tls(stream) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+
+        pub fn close<S: Write>(&mut self, stream: &mut S) -> std::io::Result<()> {
+            self.session.send_close_notify();
+            self.flush(stream)
+        }
+    }
+}
+
 pub mod mio {
     use mio::event::Source;
     use mio::net::TcpStream;
     use mio::{Interest, Registry, Token};
 
-    use super::Network;
+    use super::{Network, TlsOptions};
     use crate::error::*;
     use std::io::Read;
     use std::io::Write;
     use std::net::Shutdown;
     use std::net::ToSocketAddrs;
 
+    #[cfg(feature = "tls")]
+    use super::tls::TlsSession;
+    #[cfg(feature = "tls")]
+    use std::sync::Arc;
+
+    /**
+     * Server side TLS configuration, prepared once and shared by all connections accepted by a listener.
+     */
+    #[derive(Clone)]
+    pub struct TlsAcceptor {
+        #[cfg(feature = "tls")]
+        config: Arc<rustls::ServerConfig>,
+    }
+
+    impl std::fmt::Debug for TlsAcceptor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("TlsAcceptor").finish()
+        }
+    }
+
+    impl TlsAcceptor {
+        #[cfg(feature = "tls")]
+        pub fn new(opts: &TlsOptions) -> Result<TlsAcceptor> {
+            Ok(TlsAcceptor {
+                config: Arc::new(super::tls::server_config(opts)?),
+            })
+        }
+
+        #[cfg(not(feature = "tls"))]
+        pub fn new(_opts: &TlsOptions) -> Result<TlsAcceptor> {
+            Err(AmqpError::generic("TLS support is not enabled"))
+        }
+    }
+
     #[derive(Debug)]
     pub struct MioNetwork {
         stream: TcpStream,
+        #[cfg(feature = "tls")]
+        tls: Option<TlsSession>,
     }
 
     impl MioNetwork {
@@ -282,12 +568,54 @@ pub mod mio {
             let mut addrs = format!("{}:{}", host, port).to_socket_addrs().unwrap();
             let stream = TcpStream::connect(addrs.next().unwrap())?;
 
-            Ok(MioNetwork { stream })
+            Ok(MioNetwork::from_stream(stream))
+        }
+
+        pub fn from_stream(stream: TcpStream) -> MioNetwork {
+            MioNetwork {
+                stream,
+                #[cfg(feature = "tls")]
+                tls: None,
+            }
+        }
+
+        /// Connect to host and port, wrapping the connection in TLS.
+        #[cfg(feature = "tls")]
+        pub fn connect_tls(host: &str, port: u16, opts: &TlsOptions) -> Result<MioNetwork> {
+            let session = TlsSession::client(opts, host)?;
+            let mut network = MioNetwork::connect(host, port)?;
+            network.tls = Some(session);
+            Ok(network)
+        }
+
+        #[cfg(not(feature = "tls"))]
+        pub fn connect_tls(_host: &str, _port: u16, _opts: &TlsOptions) -> Result<MioNetwork> {
+            Err(AmqpError::generic("TLS support is not enabled"))
+        }
+
+        /// Wrap an accepted stream in TLS.
+        #[cfg(feature = "tls")]
+        pub fn from_stream_tls(stream: TcpStream, acceptor: &TlsAcceptor) -> MioNetwork {
+            MioNetwork {
+                stream,
+                tls: Some(TlsSession::server(&acceptor.config)),
+            }
+        }
+
+        #[cfg(not(feature = "tls"))]
+        pub fn from_stream_tls(stream: TcpStream, _acceptor: &TlsAcceptor) -> MioNetwork {
+            MioNetwork::from_stream(stream)
         }
     }
 
     impl Network for MioNetwork {
         fn close(&mut self) -> Result<()> {
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls) = &mut self.tls {
+                    let _ = tls.close(&mut self.stream);
+                }
+            }
             self.stream.shutdown(Shutdown::Both)?;
             Ok(())
         }
@@ -295,16 +623,34 @@ pub mod mio {
 
     impl Write for MioNetwork {
         fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls) = &mut self.tls {
+                    return tls.write(&mut self.stream, data);
+                }
+            }
             self.stream.write(data)
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls) = &mut self.tls {
+                    tls.flush(&mut self.stream)?;
+                }
+            }
             self.stream.flush()
         }
     }
 
     impl Read for MioNetwork {
         fn read(&mut self, b: &mut [u8]) -> std::io::Result<usize> {
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls) = &mut self.tls {
+                    return tls.read(&mut self.stream, b);
+                }
+            }
             self.stream.read(b)
         }
     }
@@ -382,4 +728,19 @@ mod tests {
         assert!(result.is_err());
         assert_eq!([1, 2, 3, 4, 5, 6], &buf.buffer[..buf.capacity]);
     }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_ip_address_requires_server_name() {
+        use super::tls::TlsSession;
+        use super::TlsOptions;
+
+        match TlsSession::client(&TlsOptions::new(), "127.0.0.1") {
+            Err(e) => assert!(e.to_string().contains("server_name")),
+            Ok(_) => panic!("IP address accepted as server name"),
+        }
+
+        let opts = TlsOptions::new().server_name("localhost");
+        assert!(TlsSession::client(&opts, "127.0.0.1").is_ok());
+    }
 }
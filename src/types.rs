@@ -5,11 +5,13 @@
 
 //! The types module contains the AMQP 1.0 types system encoders and decoders. By using these types you can enforce a certain encoding for your data.
 
-use log::trace;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::io::Write;
 use std::vec::Vec;
 
+use uuid::Uuid;
+
 use crate::error::*;
 
 /**
@@ -20,10 +22,10 @@ pub trait Encoder {
 }
 
 // Various constants used in encoding and decoding.
-pub const U8_MAX: usize = std::u8::MAX as usize;
-pub const I8_MAX: usize = std::i8::MAX as usize;
-pub const LIST8_MAX: usize = (std::u8::MAX as usize) - 1;
-pub const LIST32_MAX: usize = (std::u32::MAX as usize) - 4;
+pub const U8_MAX: usize = u8::MAX as usize;
+pub const I8_MAX: usize = i8::MAX as usize;
+pub const LIST8_MAX: usize = (u8::MAX as usize) - 1;
+pub const LIST32_MAX: usize = (u32::MAX as usize) - 4;
 
 pub const DESC_OPEN: Value = Value::Ulong(0x10);
 pub const DESC_BEGIN: Value = Value::Ulong(0x11);
@@ -50,15 +52,77 @@ pub const DESC_DELIVERY_STATE_REJECTED: Value = Value::Ulong(0x25);
 pub const DESC_DELIVERY_STATE_RELEASED: Value = Value::Ulong(0x26);
 pub const DESC_DELIVERY_STATE_MODIFIED: Value = Value::Ulong(0x27);
 
+pub const DESC_COORDINATOR: Value = Value::Ulong(0x30);
+pub const DESC_DECLARE: Value = Value::Ulong(0x31);
+pub const DESC_DISCHARGE: Value = Value::Ulong(0x32);
+pub const DESC_DECLARED: Value = Value::Ulong(0x33);
+pub const DESC_TRANSACTIONAL_STATE: Value = Value::Ulong(0x34);
+
 pub const DESC_DETACH: Value = Value::Ulong(0x16);
 pub const DESC_END: Value = Value::Ulong(0x17);
 pub const DESC_CLOSE: Value = Value::Ulong(0x18);
 
 pub const DESC_SASL_MECHANISMS: Value = Value::Ulong(0x40);
 pub const DESC_SASL_INIT: Value = Value::Ulong(0x41);
+pub const DESC_SASL_CHALLENGE: Value = Value::Ulong(0x42);
+pub const DESC_SASL_RESPONSE: Value = Value::Ulong(0x43);
 pub const DESC_SASL_OUTCOME: Value = Value::Ulong(0x44);
 pub const DESC_ERROR: Value = Value::Ulong(0x1D);
 
+/**
+ * A 32-bit floating point value. Values are compared by their total ordering, so that they can be
+ * used in maps like the other AMQP types.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct F32(pub f32);
+
+/**
+ * A 64-bit floating point value. Values are compared by their total ordering, so that they can be
+ * used in maps like the other AMQP types.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct F64(pub f64);
+
+impl PartialEq for F32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for F32 {}
+
+impl PartialOrd for F32 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for F32 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialEq for F64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for F64 {}
+
+impl PartialOrd for F64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for F64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
 /**
  * A reference to a type with a given value. This allows efficient zero copy of the provided values and should
  * be used when possible (depends on lifetime constraints where its used).
@@ -76,6 +140,14 @@ pub enum ValueRef<'a> {
     Short(&'a i16),
     Int(&'a i32),
     Long(&'a i64),
+    Float(&'a F32),
+    Double(&'a F64),
+    Decimal32(&'a [u8; 4]),
+    Decimal64(&'a [u8; 8]),
+    Decimal128(&'a [u8; 16]),
+    Char(&'a char),
+    Timestamp(&'a i64),
+    Uuid(&'a Uuid),
     String(&'a str),
     Binary(&'a [u8]),
     Symbol(&'a [u8]),
@@ -104,6 +176,16 @@ pub enum Value {
     Short(i16),
     Int(i32),
     Long(i64),
+    Float(F32),
+    Double(F64),
+    /// IEEE 754 decimal values, kept in their encoded form.
+    Decimal32([u8; 4]),
+    Decimal64([u8; 8]),
+    Decimal128([u8; 16]),
+    Char(char),
+    /// Milliseconds since the unix epoch.
+    Timestamp(i64),
+    Uuid(Uuid),
     String(String),
     Binary(Vec<u8>),
     Symbol(Vec<u8>),
@@ -114,7 +196,7 @@ pub enum Value {
 
 impl Value {
     /**
-     * Convert to a reference type. Not currently implemented for all types.
+     * Convert to a reference type.
      */
     pub fn value_ref(&self) -> ValueRef {
         match self {
@@ -126,7 +208,10 @@ impl Value {
             Value::Bool(ref value) => ValueRef::Bool(value),
             Value::String(ref value) => ValueRef::String(value),
             Value::Symbol(ref value) => ValueRef::Symbol(&value[..]),
+            Value::Binary(ref value) => ValueRef::Binary(&value[..]),
+            Value::Array(ref value) => ValueRef::Array(value),
             Value::List(ref value) => ValueRef::List(value),
+            Value::Map(ref value) => ValueRef::Map(value),
             Value::Ubyte(ref value) => ValueRef::Ubyte(value),
             Value::Ushort(ref value) => ValueRef::Ushort(value),
             Value::Uint(ref value) => ValueRef::Uint(value),
@@ -135,10 +220,14 @@ impl Value {
             Value::Short(ref value) => ValueRef::Short(value),
             Value::Int(ref value) => ValueRef::Int(value),
             Value::Long(ref value) => ValueRef::Long(value),
-            v => {
-                trace!("Cannot convert value ref {:?}", v);
-                panic!(format!("Cannot convert value ref {:?}", v));
-            }
+            Value::Float(ref value) => ValueRef::Float(value),
+            Value::Double(ref value) => ValueRef::Double(value),
+            Value::Decimal32(ref value) => ValueRef::Decimal32(value),
+            Value::Decimal64(ref value) => ValueRef::Decimal64(value),
+            Value::Decimal128(ref value) => ValueRef::Decimal128(value),
+            Value::Char(ref value) => ValueRef::Char(value),
+            Value::Timestamp(ref value) => ValueRef::Timestamp(value),
+            Value::Uuid(ref value) => ValueRef::Uuid(value),
         }
     }
 }
@@ -168,6 +257,14 @@ pub enum TypeCode {
     Intsmall = 0x54,
     Long = 0x81,
     Longsmall = 0x55,
+    Float = 0x72,
+    Double = 0x82,
+    Decimal32 = 0x74,
+    Decimal64 = 0x84,
+    Decimal128 = 0x94,
+    Char = 0x73,
+    Timestamp = 0x83,
+    Uuid = 0x98,
     Bin8 = 0xA0,
     Bin32 = 0xB0,
     Str8 = 0xA1,
@@ -188,6 +285,7 @@ mod tests {
 
     use super::*;
     use crate::decoding::decode_value;
+    use std::time::{Duration, UNIX_EPOCH};
 
     fn assert_type(value: &Value, expected_len: usize, expected_type: TypeCode) {
         let mut output: Vec<u8> = Vec::new();
@@ -219,4 +317,40 @@ mod tests {
             TypeCode::List8,
         );
     }
+
+    #[test]
+    fn check_primitive_types() {
+        assert_type(&Value::Float(F32(1.5)), 5, TypeCode::Float);
+        assert_type(&Value::Double(F64(-2.25)), 9, TypeCode::Double);
+        assert_type(&Value::Decimal32([1, 2, 3, 4]), 5, TypeCode::Decimal32);
+        assert_type(&Value::Decimal64([1; 8]), 9, TypeCode::Decimal64);
+        assert_type(&Value::Decimal128([1; 16]), 17, TypeCode::Decimal128);
+        assert_type(&Value::Char('\u{1F600}'), 5, TypeCode::Char);
+        assert_type(&Value::Timestamp(1_311_704_463_521), 9, TypeCode::Timestamp);
+        assert_type(&Value::Uuid(Uuid::new_v4()), 17, TypeCode::Uuid);
+    }
+
+    #[test]
+    fn system_time_out_of_range() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_311_704_463_521);
+        let mut output: Vec<u8> = Vec::new();
+        time.encode(&mut output).unwrap();
+        let decoded = decode_value(&mut &output[..]).unwrap();
+        assert_eq!(Value::Timestamp(1_311_704_463_521), decoded);
+
+        let time = UNIX_EPOCH
+            .checked_add(Duration::from_secs(1 << 60))
+            .expect("time not supported");
+        assert!(time.encode(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn float_ordering() {
+        assert_eq!(F64(f64::NAN), F64(f64::NAN));
+        assert!(F32(1.0) < F32(2.0));
+
+        let mut m = BTreeMap::new();
+        m.insert(Value::Double(F64(1.0)), Value::Null);
+        assert!(m.contains_key(&Value::Double(F64(1.0))));
+    }
 }
@@ -9,7 +9,7 @@ use dove::message::MessageBody;
 use futures::executor::block_on;
 use reqwest;
 use std::sync::Once;
-use std::{sync::mpsc, thread, time::Duration};
+use std::{thread, time::Duration};
 use testcontainers::{clients, images, Docker};
 
 static INIT: Once = Once::new();
@@ -22,80 +22,82 @@ fn setup() {
 
 #[test]
 fn test_artemis() {
-    panic_after(Duration::from_secs(120), || {
-        setup();
-        let docker = clients::Cli::default();
-        let node = docker.run(
-            images::generic::GenericImage::new("docker.io/vromero/activemq-artemis:2-latest")
-                .with_env_var("ARTEMIS_USERNAME", "test")
-                .with_env_var("ARTEMIS_PASSWORD", "test"),
-        );
-        log::info!("ActiveMQ Artemis Started");
-        std::thread::sleep(Duration::from_millis(20000));
-        let port: u16 = node.get_host_port(5672).unwrap();
-        let opts = ConnectionOptions::new()
-            .sasl_mechanism(SaslMechanism::Plain)
-            .username("test")
-            .password("test");
-        single_client(port, opts.clone());
-        multiple_clients(port, opts);
-    });
+    setup();
+    let docker = clients::Cli::default();
+    let node = docker.run(
+        images::generic::GenericImage::new("docker.io/vromero/activemq-artemis:2-latest")
+            .with_env_var("ARTEMIS_USERNAME", "test")
+            .with_env_var("ARTEMIS_PASSWORD", "test"),
+    );
+    log::info!("ActiveMQ Artemis Started");
+    std::thread::sleep(Duration::from_millis(20000));
+    let port: u16 = node.get_host_port(5672).unwrap();
+    let opts = ConnectionOptions::new()
+        .sasl_mechanism(SaslMechanism::Plain)
+        .username("test")
+        .password("test")
+        .connect_timeout(Duration::from_secs(10))
+        .operation_timeout(Duration::from_secs(30));
+    single_client(port, opts.clone());
+    multiple_clients(port, opts);
 }
 
 #[test]
 fn test_qpid_dispatch() {
-    panic_after(Duration::from_secs(120), || {
-        setup();
-        let docker = clients::Cli::default();
-        let node = docker.run(images::generic::GenericImage::new(
-            "quay.io/interconnectedcloud/qdrouterd:1.12.0",
-        ));
-        log::info!("Router Started");
-        std::thread::sleep(Duration::from_millis(10000));
-        let port: u16 = node.get_host_port(5672).unwrap();
-        let opts = ConnectionOptions::new().sasl_mechanism(SaslMechanism::Anonymous);
-        multiple_clients(port, opts);
-    });
+    setup();
+    let docker = clients::Cli::default();
+    let node = docker.run(images::generic::GenericImage::new(
+        "quay.io/interconnectedcloud/qdrouterd:1.12.0",
+    ));
+    log::info!("Router Started");
+    std::thread::sleep(Duration::from_millis(10000));
+    let port: u16 = node.get_host_port(5672).unwrap();
+    let opts = ConnectionOptions::new()
+        .sasl_mechanism(SaslMechanism::Anonymous)
+        .connect_timeout(Duration::from_secs(10))
+        .operation_timeout(Duration::from_secs(30));
+    multiple_clients(port, opts);
 }
 
 #[test]
 fn test_qpid_broker_j() {
-    panic_after(Duration::from_secs(120), || {
-        setup();
-        let mut config_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        config_dir.push("tests");
-        config_dir.push("qpid-broker-j");
-        log::info!("Loaded config from {:?}", config_dir);
-        let docker = clients::Cli::default();
-        let config_dir_path = config_dir.as_path().to_str().unwrap();
-
-        // Required to allow reading directory in container
-        let chcon_output = std::process::Command::new("chcon")
-            .arg("-t")
-            .arg("svirt_sandbox_file_t")
-            .arg(config_dir_path)
-            .output()
-            .expect("failed to run command");
-        log::info!("CHCON: {:?}", chcon_output);
-
-        let node = docker.run(
-            images::generic::GenericImage::new("docker.io/chrisob/qpid-broker-j-docker:8.0.0")
-                .with_volume(config_dir_path, "/usr/local/etc"),
-        );
-        log::info!("Qpid Broker J Started");
-        std::thread::sleep(Duration::from_millis(20000));
-
-        // Create queues used by tests
-        let client = reqwest::blocking::Client::new();
-        let http_port: u16 = node.get_host_port(8080).unwrap();
-        create_queue(&client, http_port, "myqueue");
-        create_queue(&client, http_port, "queue2");
-
-        let port: u16 = node.get_host_port(5672).unwrap();
-        let opts = ConnectionOptions::new().sasl_mechanism(SaslMechanism::Anonymous);
-        single_client(port, opts.clone());
-        multiple_clients(port, opts);
-    });
+    setup();
+    let mut config_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    config_dir.push("tests");
+    config_dir.push("qpid-broker-j");
+    log::info!("Loaded config from {:?}", config_dir);
+    let docker = clients::Cli::default();
+    let config_dir_path = config_dir.as_path().to_str().unwrap();
+
+    // Required to allow reading directory in container
+    let chcon_output = std::process::Command::new("chcon")
+        .arg("-t")
+        .arg("svirt_sandbox_file_t")
+        .arg(config_dir_path)
+        .output()
+        .expect("failed to run command");
+    log::info!("CHCON: {:?}", chcon_output);
+
+    let node = docker.run(
+        images::generic::GenericImage::new("docker.io/chrisob/qpid-broker-j-docker:8.0.0")
+            .with_volume(config_dir_path, "/usr/local/etc"),
+    );
+    log::info!("Qpid Broker J Started");
+    std::thread::sleep(Duration::from_millis(20000));
+
+    // Create queues used by tests
+    let client = reqwest::blocking::Client::new();
+    let http_port: u16 = node.get_host_port(8080).unwrap();
+    create_queue(&client, http_port, "myqueue");
+    create_queue(&client, http_port, "queue2");
+
+    let port: u16 = node.get_host_port(5672).unwrap();
+    let opts = ConnectionOptions::new()
+        .sasl_mechanism(SaslMechanism::Anonymous)
+        .connect_timeout(Duration::from_secs(10))
+        .operation_timeout(Duration::from_secs(30));
+    single_client(port, opts.clone());
+    multiple_clients(port, opts);
 }
 
 fn single_client(port: u16, opts: ConnectionOptions) {
@@ -294,22 +296,3 @@ fn create_queue(client: &reqwest::blocking::Client, port: u16, queue: &str) {
     log::trace!("Create queue response: {:?}", response);
     assert!(response.status().is_success());
 }
-
-fn panic_after<T, F>(d: Duration, f: F) -> T
-where
-    T: Send + 'static,
-    F: FnOnce() -> T,
-    F: Send + 'static,
-{
-    let (done_tx, done_rx) = mpsc::channel();
-    let handle = thread::spawn(move || {
-        let val = f();
-        done_tx.send(()).expect("Unable to send completion signal");
-        val
-    });
-
-    match done_rx.recv_timeout(d) {
-        Ok(_) => handle.join().expect("Thread panicked"),
-        Err(_) => panic!("Thread took too long"),
-    }
-}